@@ -0,0 +1,416 @@
+// Alert rules that keep telling people while a door is left open
+use std::{
+    collections::HashMap,
+    error::Error,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use chrono::{DateTime, Local, TimeDelta};
+use reqwest::Client;
+use serde::Serialize;
+use tokio::sync::broadcast;
+use crate::{
+    config::{AlertConfig, NotifierConfig, TimeWindow},
+    controller::DoorStatus,
+    door::{DoorHandle, Doors},
+    notifiers::{self, Notification, Notifier},
+    webhooks::Webhooks,
+};
+
+// Alert events waiting for slow `/watch-status` clients
+const EVENT_BUFFER: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(tag = "alert", rename_all = "snake_case")]
+pub enum AlertPhase {
+    Fired,
+    /// Numbered from 1
+    Reminder { reminder: u32 },
+    /// The door closed after this many reminders
+    Cleared { reminders: u32 },
+}
+
+impl AlertPhase {
+    // Notifiers with a `from_reminder` up to this hear about it. Escalates with every
+    // reminder, and clearing goes to everyone who heard about the alert.
+    fn level(self) -> u32 {
+        match self {
+            AlertPhase::Fired => 0,
+            AlertPhase::Reminder { reminder } => reminder,
+            AlertPhase::Cleared { reminders } => reminders,
+        }
+    }
+
+    fn urgency(self) -> u8 {
+        match self {
+            AlertPhase::Fired => 3,
+            AlertPhase::Reminder { reminder } => (3 + reminder).min(5) as u8,
+            AlertPhase::Cleared { .. } => 2,
+        }
+    }
+}
+
+/// Sent as an `alert` event on `/watch-status`, and to notifiers and webhooks
+#[derive(Debug, Clone, Serialize)]
+pub struct AlertEvent {
+    pub rule: String,
+    pub door: String,
+    #[serde(flatten)]
+    pub phase: AlertPhase,
+    /// RFC 3339 time the door was last seen closed, or the server started
+    pub not_closed_since: String,
+    pub time: String,
+    pub message: String,
+}
+
+#[derive(Debug)]
+struct AlertRule {
+    name: String,
+    after: TimeDelta,
+    windows: Vec<TimeWindow>,
+    reminders: Vec<TimeDelta>,
+    notifiers: Vec<Arc<Notifier>>,
+}
+
+impl AlertRule {
+    fn in_window(&self, time: DateTime<Local>) -> bool {
+        self.windows.is_empty() || self.windows.iter().any(|window| window.contains(time.time()))
+    }
+
+    // Gap before the reminder after `sent` reminders
+    fn next_reminder(&self, sent: u32) -> Option<TimeDelta> {
+        self.reminders.get(sent as usize).or(self.reminders.last()).copied()
+    }
+
+    // The notifiers to tell about a phase of the alert
+    fn recipients(&self, phase: AlertPhase) -> impl Iterator<Item = &Arc<Notifier>> {
+        self.notifiers.iter().filter(move |notifier| notifier.from_reminder <= phase.level())
+    }
+}
+
+/// Lets `/watch-status` follow the alerts. Cloning it shares the same alerts.
+#[derive(Debug, Clone)]
+pub struct Alerts {
+    events_tx: broadcast::Sender<AlertEvent>,
+    /// Latest event of every alert that hasn't cleared, by door and rule
+    active: Arc<Mutex<HashMap<(String, String), AlertEvent>>>,
+    notifiers: Arc<Vec<Arc<Notifier>>>,
+    client: Client,
+    webhooks: Webhooks,
+}
+
+impl Alerts {
+    /// Check the rules and start watching the doors they apply to
+    pub fn spawn(
+        rules: &[AlertConfig],
+        notifiers: &[NotifierConfig],
+        doors: &Doors,
+        webhooks: Webhooks,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut all_notifiers: Vec<Arc<Notifier>> = Vec::new();
+        for config in notifiers {
+            if all_notifiers.iter().any(|notifier| notifier.name == config.name) {
+                return Err(format!("Notifier name {} is used more than once", config.name).into());
+            }
+            all_notifiers.push(Arc::new(Notifier::new(config)?));
+        }
+
+        let alerts = Self {
+            events_tx: broadcast::channel(EVENT_BUFFER).0,
+            active: Arc::default(),
+            notifiers: Arc::new(all_notifiers),
+            client: Client::builder().timeout(notifiers::TIMEOUT).build()?,
+            webhooks,
+        };
+
+        for (index, config) in rules.iter().enumerate() {
+            if rules[..index].iter().any(|other| other.name == config.name) {
+                return Err(format!("Alert name {} is used more than once", config.name).into());
+            }
+            let notifiers = if config.notify.is_empty() {
+                alerts.notifiers.to_vec()
+            } else {
+                config
+                    .notify
+                    .iter()
+                    .map(|name| {
+                        alerts
+                            .notifier(name)
+                            .ok_or_else(|| format!("Alert {} uses unknown notifier {}", config.name, name))
+                    })
+                    .collect::<Result<_, _>>()?
+            };
+            let rule = Arc::new(AlertRule {
+                name: config.name.clone(),
+                after: TimeDelta::minutes(config.after_min.unwrap_or(0) as i64),
+                windows: config.windows.clone(),
+                reminders: config.reminder_min.iter().map(|min| TimeDelta::minutes(*min as i64)).collect(),
+                notifiers,
+            });
+            let rule_doors: Vec<_> = match &config.door {
+                Some(id) => vec![doors
+                    .get(id)
+                    .ok_or_else(|| format!("Alert {} is for unknown door {}", config.name, id))?],
+                None => doors.iter().collect(),
+            };
+            for door in rule_doors {
+                tokio::spawn(watch_door(alerts.clone(), rule.clone(), door.clone()));
+            }
+        }
+        Ok(alerts)
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<AlertEvent> {
+        self.events_tx.subscribe()
+    }
+
+    /// Alerts on a door that haven't cleared yet
+    pub fn active(&self, door: &str) -> Vec<AlertEvent> {
+        let active = self.active.lock().unwrap();
+        active.values().filter(|event| event.door == door).cloned().collect()
+    }
+
+    pub fn notifier(&self, name: &str) -> Option<Arc<Notifier>> {
+        self.notifiers.iter().find(|notifier| notifier.name == name).cloned()
+    }
+
+    /// Send a test notification, to check a notifier works
+    pub async fn test(&self, notifier: &Notifier) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let notification = Notification {
+            title: "PiOpener test".to_string(),
+            message: format!("Test notification from PiOpener through {}", notifier.name),
+            urgency: 3,
+            alert: None,
+        };
+        notifier.send(&self.client, &notification).await
+    }
+
+    fn publish(&self, rule: &AlertRule, door: &DoorHandle, phase: AlertPhase, since: DateTime<Local>, now: DateTime<Local>) {
+        let minutes = (now - since).num_minutes();
+        let message = match phase {
+            AlertPhase::Fired => format!("Door {} has not been closed for {} min", door.id, minutes),
+            AlertPhase::Reminder { .. } => format!("Door {} is still not closed after {} min", door.id, minutes),
+            AlertPhase::Cleared { .. } => format!("Door {} is closed again after {} min", door.id, minutes),
+        };
+        let event = AlertEvent {
+            rule: rule.name.clone(),
+            door: door.id.clone(),
+            phase,
+            not_closed_since: since.to_rfc3339(),
+            time: now.to_rfc3339(),
+            message,
+        };
+
+        {
+            let mut active = self.active.lock().unwrap();
+            let key = (door.id.clone(), rule.name.clone());
+            match phase {
+                AlertPhase::Cleared { .. } => active.remove(&key),
+                _ => active.insert(key, event.clone()),
+            };
+        }
+        // Nobody may be watching
+        let _ = self.events_tx.send(event.clone());
+        self.webhooks.alert(door, &event);
+
+        let notification = Notification {
+            title: format!("PiOpener: {}", rule.name),
+            message: event.message.clone(),
+            urgency: phase.urgency(),
+            alert: Some(event),
+        };
+        for notifier in rule.recipients(phase) {
+            let (notifier, client, notification) = (notifier.clone(), self.client.clone(), notification.clone());
+            tokio::spawn(async move {
+                if let Err(err) = notifier.send(&client, &notification).await {
+                    eprintln!("Failed to send an alert through {}: {}", notifier.name, err);
+                }
+            });
+        }
+    }
+}
+
+// Where one rule stands for one door
+#[derive(Debug, Default)]
+struct AlertState {
+    not_closed_since: Option<DateTime<Local>>,
+    // Reminders sent so far and when the next one is due, while the alert is on
+    firing: Option<(u32, Option<DateTime<Local>>)>,
+}
+
+impl AlertState {
+    // Move on to the door status at `now`. Returns what to publish, if anything,
+    // with the time the door was last seen closed.
+    fn update(&mut self, rule: &AlertRule, status: DoorStatus, now: DateTime<Local>) -> Option<(AlertPhase, DateTime<Local>)> {
+        if status == DoorStatus::Closed {
+            let since = self.not_closed_since.take();
+            let (reminders, _) = self.firing.take()?;
+            return since.map(|since| (AlertPhase::Cleared { reminders }, since));
+        }
+        let since = *self.not_closed_since.get_or_insert(now);
+
+        match &mut self.firing {
+            None if now - since >= rule.after && rule.in_window(now) => {
+                self.firing = Some((0, rule.next_reminder(0).map(|gap| now + gap)));
+                Some((AlertPhase::Fired, since))
+            }
+            Some((reminders, next)) if next.is_some_and(|next| now >= next) => {
+                *reminders += 1;
+                *next = rule.next_reminder(*reminders).map(|gap| now + gap);
+                Some((AlertPhase::Reminder { reminder: *reminders }, since))
+            }
+            _ => None,
+        }
+    }
+}
+
+// Follow one door for one rule
+async fn watch_door(alerts: Alerts, rule: Arc<AlertRule>, door: DoorHandle) {
+    let mut state_rx = door.state.subscribe();
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    let mut state = AlertState::default();
+
+    loop {
+        tokio::select! {
+            changed = state_rx.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+            }
+            _ = interval.tick() => {}
+        }
+        let status = state_rx.borrow_and_update().status;
+        let now = Local::now();
+        if let Some((phase, since)) = state.update(&rule, status, now) {
+            alerts.publish(&rule, &door, phase, since, now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveTime, TimeZone};
+    use crate::config::NotifierBackend;
+    use super::*;
+
+    fn at(hour: u32, min: u32) -> DateTime<Local> {
+        Local.with_ymd_and_hms(2025, 6, 2, hour, min, 0).unwrap()
+    }
+
+    fn minutes(min: i64) -> TimeDelta {
+        TimeDelta::minutes(min)
+    }
+
+    fn rule(after_min: i64, reminder_min: &[i64], windows: Vec<TimeWindow>) -> AlertRule {
+        AlertRule {
+            name: "left open".to_string(),
+            after: minutes(after_min),
+            windows,
+            reminders: reminder_min.iter().copied().map(minutes).collect(),
+            notifiers: Vec::new(),
+        }
+    }
+
+    fn night() -> TimeWindow {
+        TimeWindow {
+            start: NaiveTime::from_hms_opt(21, 0, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(7, 0, 0).unwrap(),
+        }
+    }
+
+    #[test]
+    fn repeats_the_last_reminder_gap() {
+        let rule = rule(10, &[5, 15], Vec::new());
+        assert_eq!(rule.next_reminder(0), Some(minutes(5)));
+        assert_eq!(rule.next_reminder(1), Some(minutes(15)));
+        assert_eq!(rule.next_reminder(7), Some(minutes(15)));
+        assert_eq!(self::rule(10, &[], Vec::new()).next_reminder(0), None);
+    }
+
+    #[test]
+    fn only_alerts_within_its_windows() {
+        assert!(rule(0, &[], Vec::new()).in_window(at(12, 0)));
+
+        let rule = rule(0, &[], vec![night()]);
+        assert!(rule.in_window(at(23, 0)));
+        assert!(rule.in_window(at(6, 59)));
+        assert!(!rule.in_window(at(7, 0)));
+        assert!(!rule.in_window(at(12, 0)));
+    }
+
+    #[test]
+    fn fires_reminds_and_clears() {
+        let rule = rule(10, &[5, 15], Vec::new());
+        let mut state = AlertState::default();
+        let open = |state: &mut AlertState, time| state.update(&rule, DoorStatus::Open, time).map(|(phase, _)| phase);
+
+        assert_eq!(state.update(&rule, DoorStatus::Closed, at(8, 0)), None);
+        assert_eq!(open(&mut state, at(8, 0)), None);
+        assert_eq!(open(&mut state, at(8, 9)), None);
+        assert_eq!(state.update(&rule, DoorStatus::Ajar, at(8, 10)), Some((AlertPhase::Fired, at(8, 0))));
+        assert_eq!(open(&mut state, at(8, 14)), None);
+        assert_eq!(open(&mut state, at(8, 15)), Some(AlertPhase::Reminder { reminder: 1 }));
+        assert_eq!(open(&mut state, at(8, 29)), None);
+        assert_eq!(open(&mut state, at(8, 30)), Some(AlertPhase::Reminder { reminder: 2 }));
+        assert_eq!(open(&mut state, at(8, 45)), Some(AlertPhase::Reminder { reminder: 3 }));
+        assert_eq!(
+            state.update(&rule, DoorStatus::Closed, at(8, 50)),
+            Some((AlertPhase::Cleared { reminders: 3 }, at(8, 0)))
+        );
+        assert_eq!(state.update(&rule, DoorStatus::Closed, at(8, 51)), None);
+
+        // Opening again starts over, and closing before the alert fires says nothing
+        assert_eq!(open(&mut state, at(9, 0)), None);
+        assert_eq!(state.update(&rule, DoorStatus::Closed, at(9, 5)), None);
+        assert_eq!(open(&mut state, at(9, 10)), None);
+        assert_eq!(open(&mut state, at(9, 20)), Some(AlertPhase::Fired));
+    }
+
+    #[test]
+    fn waits_for_the_window_to_fire() {
+        let rule = rule(10, &[], vec![night()]);
+        let mut state = AlertState::default();
+        assert_eq!(state.update(&rule, DoorStatus::Open, at(12, 0)), None);
+        assert_eq!(state.update(&rule, DoorStatus::Open, at(20, 59)), None);
+        assert_eq!(state.update(&rule, DoorStatus::Open, at(21, 0)), Some((AlertPhase::Fired, at(12, 0))));
+        // Without reminders nothing follows until the door closes
+        assert_eq!(state.update(&rule, DoorStatus::Open, at(23, 0)), None);
+        assert_eq!(
+            state.update(&rule, DoorStatus::Closed, at(23, 30)),
+            Some((AlertPhase::Cleared { reminders: 0 }, at(12, 0)))
+        );
+    }
+
+    #[test]
+    fn escalates_with_every_reminder() {
+        let notifier = |from_reminder| {
+            Notifier::new(&NotifierConfig {
+                name: format!("from {}", from_reminder),
+                from_reminder: Some(from_reminder),
+                backend: NotifierBackend::Webhook {
+                    url: "http://localhost/".to_string(),
+                    secret: None,
+                },
+            })
+            .unwrap()
+        };
+        let rule = AlertRule {
+            notifiers: vec![Arc::new(notifier(0)), Arc::new(notifier(2))],
+            ..rule(10, &[5], Vec::new())
+        };
+        let notified = |phase| -> Vec<u32> { rule.recipients(phase).map(|notifier| notifier.from_reminder).collect() };
+
+        assert_eq!(notified(AlertPhase::Fired), [0]);
+        assert_eq!(notified(AlertPhase::Reminder { reminder: 1 }), [0]);
+        assert_eq!(notified(AlertPhase::Reminder { reminder: 2 }), [0, 2]);
+        assert_eq!(notified(AlertPhase::Reminder { reminder: 3 }), [0, 2]);
+        // Clearing only goes to whoever heard about the alert
+        assert_eq!(notified(AlertPhase::Cleared { reminders: 1 }), [0]);
+        assert_eq!(notified(AlertPhase::Cleared { reminders: 2 }), [0, 2]);
+
+        assert_eq!(AlertPhase::Fired.urgency(), 3);
+        assert_eq!(AlertPhase::Reminder { reminder: 1 }.urgency(), 4);
+        assert_eq!(AlertPhase::Reminder { reminder: 5 }.urgency(), 5);
+        assert_eq!(AlertPhase::Cleared { reminders: 5 }.urgency(), 2);
+    }
+}
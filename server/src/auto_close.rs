@@ -0,0 +1,282 @@
+use std::time::Duration;
+use chrono::{DateTime, Local, TimeDelta, TimeZone};
+use tokio::sync::{mpsc, watch};
+use crate::{
+    commands::{CommandSender, CommandSource},
+    config::{AutoCloseConfig, TimeWindow},
+    controller::{DoorState, DoorStatus, GpioCommand},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct AutoCloseStatus {
+    /// When the door is going to be closed, if it is
+    pub deadline: Option<DateTime<Local>>,
+    /// The door is being held open until then
+    pub hold_until: Option<DateTime<Local>>,
+    /// Auto-close was cancelled until the door closes again
+    pub cancelled: bool,
+}
+
+#[derive(Debug)]
+enum AutoCloseControl {
+    HoldOpen(Duration),
+    Cancel,
+}
+
+/// Lets the API talk to the auto-close task
+#[derive(Debug, Clone)]
+pub struct AutoCloseHandle {
+    status_rx: watch::Receiver<AutoCloseStatus>,
+    control_tx: mpsc::UnboundedSender<AutoCloseControl>,
+}
+
+impl AutoCloseHandle {
+    pub fn status(&self) -> AutoCloseStatus {
+        *self.status_rx.borrow()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<AutoCloseStatus> {
+        self.status_rx.clone()
+    }
+
+    /// Don't close automatically before `duration` from now has passed
+    pub fn hold_open(&self, duration: Duration) {
+        let _ = self.control_tx.send(AutoCloseControl::HoldOpen(duration));
+    }
+
+    /// Don't close automatically until the door has been closed some other way
+    pub fn cancel(&self) {
+        let _ = self.control_tx.send(AutoCloseControl::Cancel);
+    }
+}
+
+/// Start the auto-close task. It watches the door state and sends a close
+/// command once the door has been open for the configured time.
+pub fn spawn(config: &AutoCloseConfig, mut state_rx: watch::Receiver<DoorState>, commands: CommandSender) -> AutoCloseHandle {
+    let (status_tx, status_rx) = watch::channel(AutoCloseStatus::default());
+    let (control_tx, mut control_rx) = mpsc::unbounded_channel();
+
+    let mut closer = AutoCloser {
+        after: TimeDelta::minutes(config.after_min as i64),
+        windows: config.windows.clone(),
+        open_since: None,
+        hold_until: None,
+        cancelled: false,
+        fired: false,
+    };
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        let mut state = *state_rx.borrow_and_update();
+        closer.update(state.status, Local::now());
+
+        loop {
+            tokio::select! {
+                changed = state_rx.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                    state = *state_rx.borrow_and_update();
+                    closer.update(state.status, Local::now());
+                }
+                Some(control) = control_rx.recv() => match control {
+                    AutoCloseControl::HoldOpen(duration) => {
+                        closer.hold_until = TimeDelta::from_std(duration).ok().map(|duration| Local::now() + duration);
+                    }
+                    AutoCloseControl::Cancel => closer.cancelled = true,
+                },
+                _ = interval.tick() => {}
+            }
+
+            let now = Local::now();
+            let closable = matches!(state.status, DoorStatus::Open | DoorStatus::Ajar);
+            if closable && closer.deadline().is_some_and(|deadline| now >= deadline) {
+                if commands.send(GpioCommand::Close, CommandSource::AutoClose).is_err() {
+                    break;
+                }
+                // Once is enough. If the close fails, the close retry policy deals with it.
+                closer.fired = true;
+            }
+
+            let status = closer.status();
+            status_tx.send_if_modified(|current| {
+                let modified = *current != status;
+                *current = status;
+                modified
+            });
+        }
+    });
+
+    AutoCloseHandle { status_rx, control_tx }
+}
+
+#[derive(Debug)]
+struct AutoCloser {
+    after: TimeDelta,
+    windows: Vec<TimeWindow>,
+    open_since: Option<DateTime<Local>>,
+    hold_until: Option<DateTime<Local>>,
+    cancelled: bool,
+    // We already sent a close for this open period
+    fired: bool,
+}
+
+impl AutoCloser {
+    fn update(&mut self, status: DoorStatus, now: DateTime<Local>) {
+        match status {
+            DoorStatus::Closed => {
+                self.open_since = None;
+                self.hold_until = None;
+                self.cancelled = false;
+                self.fired = false;
+            }
+            DoorStatus::Open | DoorStatus::Ajar => {
+                self.open_since.get_or_insert(now);
+            }
+            // Moving, unknown or faulted doors keep whatever timer they had
+            _ => {}
+        }
+    }
+
+    fn deadline(&self) -> Option<DateTime<Local>> {
+        if self.cancelled || self.fired {
+            return None;
+        }
+        let open_deadline = self.open_since? + self.after;
+        let deadline = match self.hold_until {
+            Some(hold_until) => open_deadline.max(hold_until),
+            None => open_deadline,
+        };
+        self.next_allowed(deadline)
+    }
+
+    // The first time at or after `time` that falls within one of the windows
+    fn next_allowed(&self, time: DateTime<Local>) -> Option<DateTime<Local>> {
+        if self.windows.is_empty() || self.windows.iter().any(|window| window.contains(time.time())) {
+            return Some(time);
+        }
+
+        self.windows
+            .iter()
+            .filter_map(|window| {
+                let today = time.date_naive().and_time(window.start);
+                let start = if today > time.naive_local() {
+                    today
+                } else {
+                    today + TimeDelta::days(1)
+                };
+                Local.from_local_datetime(&start).earliest()
+            })
+            .min()
+    }
+
+    fn status(&self) -> AutoCloseStatus {
+        AutoCloseStatus {
+            deadline: self.deadline(),
+            hold_until: self.hold_until,
+            cancelled: self.cancelled,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveTime;
+    use super::*;
+
+    fn at(day: u32, hour: u32, min: u32) -> DateTime<Local> {
+        Local.with_ymd_and_hms(2025, 6, day, hour, min, 0).unwrap()
+    }
+
+    fn window(start: u32, end: u32) -> TimeWindow {
+        TimeWindow {
+            start: NaiveTime::from_hms_opt(start, 0, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(end, 0, 0).unwrap(),
+        }
+    }
+
+    fn auto_closer(after_min: i64, windows: Vec<TimeWindow>) -> AutoCloser {
+        AutoCloser {
+            after: TimeDelta::minutes(after_min),
+            windows,
+            open_since: None,
+            hold_until: None,
+            cancelled: false,
+            fired: false,
+        }
+    }
+
+    #[test]
+    fn closes_after_the_door_has_been_open_for_a_while() {
+        let mut closer = auto_closer(10, Vec::new());
+        closer.update(DoorStatus::Closed, at(2, 8, 0));
+        assert_eq!(closer.deadline(), None);
+
+        closer.update(DoorStatus::Open, at(2, 8, 0));
+        assert_eq!(closer.deadline(), Some(at(2, 8, 10)));
+        // Stopping part way or moving doesn't restart the timer
+        closer.update(DoorStatus::MovingDown, at(2, 8, 5));
+        closer.update(DoorStatus::Ajar, at(2, 8, 6));
+        assert_eq!(closer.deadline(), Some(at(2, 8, 10)));
+    }
+
+    #[test]
+    fn holding_open_only_extends_the_deadline() {
+        let mut closer = auto_closer(10, Vec::new());
+        closer.update(DoorStatus::Open, at(2, 8, 0));
+        closer.hold_until = Some(at(2, 9, 0));
+        assert_eq!(closer.deadline(), Some(at(2, 9, 0)));
+        closer.hold_until = Some(at(2, 8, 5));
+        assert_eq!(closer.deadline(), Some(at(2, 8, 10)));
+
+        // Closing the door drops the hold
+        closer.hold_until = Some(at(2, 9, 0));
+        closer.update(DoorStatus::Closed, at(2, 8, 30));
+        assert_eq!(closer.status(), AutoCloseStatus::default());
+        closer.update(DoorStatus::Open, at(2, 8, 40));
+        assert_eq!(closer.deadline(), Some(at(2, 8, 50)));
+    }
+
+    #[test]
+    fn cancelling_or_firing_lasts_until_the_door_closes() {
+        let mut closer = auto_closer(10, Vec::new());
+        closer.update(DoorStatus::Open, at(2, 8, 0));
+        closer.cancelled = true;
+        assert_eq!(closer.deadline(), None);
+        assert!(closer.status().cancelled);
+        closer.update(DoorStatus::Closed, at(2, 8, 30));
+        assert!(!closer.status().cancelled);
+
+        closer.update(DoorStatus::Open, at(2, 9, 0));
+        closer.fired = true;
+        assert_eq!(closer.deadline(), None);
+        // Still open, so it won't try again
+        closer.update(DoorStatus::Open, at(2, 10, 0));
+        assert_eq!(closer.deadline(), None);
+        closer.update(DoorStatus::Closed, at(2, 10, 5));
+        closer.update(DoorStatus::Open, at(2, 10, 10));
+        assert_eq!(closer.deadline(), Some(at(2, 10, 20)));
+    }
+
+    #[test]
+    fn waits_for_the_next_window() {
+        // Only at night, across midnight
+        let mut closer = auto_closer(10, vec![window(21, 7)]);
+        closer.update(DoorStatus::Open, at(2, 12, 0));
+        assert_eq!(closer.deadline(), Some(at(2, 21, 0)));
+
+        let mut closer = auto_closer(10, vec![window(21, 7)]);
+        closer.update(DoorStatus::Open, at(2, 23, 55));
+        assert_eq!(closer.deadline(), Some(at(3, 0, 5)));
+
+        // Past today's only window, so tomorrow's
+        let mut closer = auto_closer(10, vec![window(8, 9)]);
+        closer.update(DoorStatus::Open, at(2, 9, 30));
+        assert_eq!(closer.deadline(), Some(at(3, 8, 0)));
+
+        // The earliest of several windows
+        let mut closer = auto_closer(10, vec![window(18, 20), window(13, 14)]);
+        closer.update(DoorStatus::Open, at(2, 10, 0));
+        assert_eq!(closer.deadline(), Some(at(2, 13, 0)));
+    }
+}
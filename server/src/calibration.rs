@@ -0,0 +1,65 @@
+use std::{fs, io, path::Path, time::Duration};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::state_file::write_json;
+
+// Weight of the newest sample in the rolling averages
+const SMOOTHING: f64 = 0.2;
+
+/// Learned limit-to-limit travel times for each direction
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize, ToSchema)]
+pub struct TravelCalibration {
+    pub up: TravelStats,
+    pub down: TravelStats,
+}
+
+/// Exponentially weighted mean and variance of full travel times, in seconds
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize, ToSchema)]
+pub struct TravelStats {
+    pub mean_sec: f64,
+    pub variance: f64,
+    pub samples: u32,
+    /// The most recent travel time as measured, before smoothing
+    #[serde(default)]
+    pub last_sec: Option<f64>,
+}
+
+impl TravelStats {
+    pub fn add_sample(&mut self, travel_time: Duration) {
+        let sample = travel_time.as_secs_f64();
+        if self.samples == 0 {
+            self.mean_sec = sample;
+            self.variance = 0.0;
+        } else {
+            let diff = sample - self.mean_sec;
+            self.mean_sec += SMOOTHING * diff;
+            self.variance = (1.0 - SMOOTHING) * (self.variance + SMOOTHING * diff * diff);
+        }
+        self.samples = self.samples.saturating_add(1);
+        self.last_sec = Some(sample);
+    }
+
+    /// The learned travel time, or `fallback` until we have measured one
+    pub fn travel_time(&self, fallback: Duration) -> Duration {
+        if self.samples == 0 {
+            fallback
+        } else {
+            Duration::from_secs_f64(self.mean_sec)
+        }
+    }
+}
+
+impl TravelCalibration {
+    /// Load the calibration from `path`. A missing file just means nothing was learned yet.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        match fs::read_to_string(path) {
+            Ok(contents) => Ok(serde_json::from_str(&contents)?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        write_json(path, self)
+    }
+}
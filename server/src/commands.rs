@@ -0,0 +1,356 @@
+use std::{collections::VecDeque, sync::{Arc, Mutex}, time::Duration};
+use serde::Serialize;
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+use crate::{
+    controller::{Command, CommandId, CommandPhase, CommandProgress, GpioCommand},
+    gpio::Wakeup,
+    history::HistoryRecorder,
+};
+
+// How many commands to remember for status queries
+const HISTORY_LEN: usize = 64;
+// How far a listener to `events` can fall behind before it misses progress updates
+const EVENTS_LEN: usize = 64;
+
+/// Where a command came from, for the history
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandSource {
+    Api,
+    Websocket,
+    Mqtt,
+    #[cfg(feature = "homekit")]
+    Homekit,
+    Schedule,
+    AutoClose,
+}
+
+/// Hands out command IDs and keeps the latest progress of recent commands so
+/// callers can wait on or look up a command after submitting it.
+#[derive(Debug, Clone)]
+pub struct CommandTracker {
+    inner: Arc<Mutex<TrackerInner>>,
+    history: Option<HistoryRecorder>,
+    events: broadcast::Sender<CommandProgress>,
+}
+
+#[derive(Debug, Default)]
+struct TrackerInner {
+    next_id: CommandId,
+    records: VecDeque<watch::Sender<CommandProgress>>,
+}
+
+impl CommandTracker {
+    pub fn new(history: Option<HistoryRecorder>) -> Self {
+        Self {
+            inner: Arc::default(),
+            history,
+            events: broadcast::channel(EVENTS_LEN).0,
+        }
+    }
+
+    /// Register a new pending command and subscribe to its progress
+    pub fn register(&self, kind: GpioCommand, source: CommandSource) -> (Command, watch::Receiver<CommandProgress>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_id += 1;
+        let command = Command {
+            id: inner.next_id,
+            kind,
+        };
+
+        let progress = CommandProgress {
+            id: command.id,
+            command: kind,
+            phase: CommandPhase::Pending,
+        };
+        let (tx, rx) = watch::channel(progress);
+        if inner.records.len() >= HISTORY_LEN {
+            inner.records.pop_front();
+        }
+        inner.records.push_back(tx);
+        if let Some(history) = &self.history {
+            history.command(command.id, kind, source);
+        }
+        // Nobody listening is fine
+        let _ = self.events.send(progress);
+
+        (command, rx)
+    }
+
+    pub fn update(&self, progress: CommandProgress) {
+        // Still recorded if the command has dropped out of the records
+        if let Some(history) = &self.history
+            && progress.phase.is_finished()
+        {
+            history.finished(progress.id, progress.phase);
+        }
+        let inner = self.inner.lock().unwrap();
+        if let Some(tx) = inner.records.iter().find(|tx| tx.borrow().id == progress.id) {
+            tx.send_replace(progress);
+        }
+        let _ = self.events.send(progress);
+    }
+
+    /// Every phase change of every command, from registering or refusing it until it finishes
+    pub fn events(&self) -> broadcast::Receiver<CommandProgress> {
+        self.events.subscribe()
+    }
+
+    pub fn subscribe(&self, id: CommandId) -> Option<watch::Receiver<CommandProgress>> {
+        let inner = self.inner.lock().unwrap();
+        inner.records.iter().find(|tx| tx.borrow().id == id).map(|tx| tx.subscribe())
+    }
+
+    pub fn get(&self, id: CommandId) -> Option<CommandProgress> {
+        let inner = self.inner.lock().unwrap();
+        inner.records.iter().map(|tx| *tx.borrow()).find(|progress| progress.id == id)
+    }
+
+    /// Note a command that was turned away before it reached the GPIO loop
+    pub fn refused(&self, kind: GpioCommand, source: CommandSource) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_id += 1;
+        let progress = CommandProgress {
+            id: inner.next_id,
+            command: kind,
+            phase: CommandPhase::Refused,
+        };
+        if let Some(history) = &self.history {
+            history.refused(progress.id, kind, source);
+        }
+        let _ = self.events.send(progress);
+    }
+}
+
+/// What the server sends to the GPIO loop
+#[derive(Debug)]
+pub enum LoopMessage {
+    Command(Command),
+    /// Call off a close that is still in its warning
+    CancelWarning,
+    /// Release the outputs and stop. The loop answers once the outputs are inactive.
+    Shutdown(oneshot::Sender<()>),
+}
+
+/// Registers commands with the tracker and hands them to the GPIO loop. Every
+/// part of the server that wants to move the door goes through this.
+#[derive(Debug, Clone)]
+pub struct CommandSender {
+    tracker: CommandTracker,
+    tx: mpsc::UnboundedSender<LoopMessage>,
+    wakeup: Wakeup,
+}
+
+/// The GPIO loop is gone, so nothing will ever run the command
+#[derive(Debug)]
+pub struct GpioLoopStopped;
+
+impl CommandSender {
+    pub fn new(tracker: CommandTracker, tx: mpsc::UnboundedSender<LoopMessage>, wakeup: Wakeup) -> Self {
+        Self { tracker, tx, wakeup }
+    }
+
+    pub fn tracker(&self) -> &CommandTracker {
+        &self.tracker
+    }
+
+    /// Submit a command and subscribe to its progress
+    pub fn send(&self, kind: GpioCommand, source: CommandSource) -> Result<watch::Receiver<CommandProgress>, GpioLoopStopped> {
+        let (command, progress_rx) = self.tracker.register(kind, source);
+        self.tx.send(LoopMessage::Command(command)).map_err(|_| GpioLoopStopped)?;
+        self.wakeup.notify();
+        Ok(progress_rx)
+    }
+
+    /// Stop the GPIO loop with its outputs inactive, waiting up to `timeout` for it
+    pub async fn shutdown(&self, timeout: Duration) -> Result<(), GpioLoopStopped> {
+        let (done_tx, done_rx) = oneshot::channel();
+        self.tx.send(LoopMessage::Shutdown(done_tx)).map_err(|_| GpioLoopStopped)?;
+        self.wakeup.notify();
+        match tokio::time::timeout(timeout, done_rx).await {
+            Ok(Ok(())) => Ok(()),
+            _ => Err(GpioLoopStopped),
+        }
+    }
+
+    pub fn cancel_warning(&self) -> Result<(), GpioLoopStopped> {
+        self.tx.send(LoopMessage::CancelWarning).map_err(|_| GpioLoopStopped)?;
+        self.wakeup.notify();
+        Ok(())
+    }
+
+    /// Wait for a submitted command to finish and return how it ended
+    pub async fn wait(&self, mut progress_rx: watch::Receiver<CommandProgress>) -> CommandProgress {
+        loop {
+            // If the record gets dropped from the history, report the last phase we saw
+            let _ = progress_rx.wait_for(|progress| progress.phase.is_finished()).await;
+
+            // A merged command finishes together with the one it was merged into
+            let CommandPhase::Merged(into) = progress_rx.borrow().phase else {
+                break;
+            };
+            match self.tracker.subscribe(into) {
+                Some(rx) => progress_rx = rx,
+                None => break,
+            }
+        }
+
+        *progress_rx.borrow()
+    }
+}
+
+/// Commands received from the API, waiting for the coupler to be free.
+///
+/// Commands are coalesced as they arrive:
+/// - an `Open`, `Close`, `Stop` or `Position` identical to the last pending command is merged into it
+/// - any of those supersedes every other pending command, since it says
+///   where the door should end up regardless of what was asked before
+/// - a `Toggle` is relative to whatever ran before it, so it is always queued
+#[derive(Debug, Default)]
+pub struct CommandQueue {
+    pending: VecDeque<Command>,
+}
+
+impl CommandQueue {
+    /// Queue a command. Returns the progress of the command itself or of any
+    /// pending commands if they were merged or superseded.
+    pub fn push(&mut self, command: Command) -> Vec<CommandProgress> {
+        if command.kind == GpioCommand::Toggle {
+            self.pending.push_back(command);
+            return Vec::new();
+        }
+
+        if let Some(existing) = self.pending.back().filter(|pending| pending.kind == command.kind) {
+            return vec![CommandProgress {
+                id: command.id,
+                command: command.kind,
+                phase: CommandPhase::Merged(existing.id),
+            }];
+        }
+
+        let superseded = self
+            .pending
+            .drain(..)
+            .map(|pending| CommandProgress {
+                id: pending.id,
+                command: pending.kind,
+                phase: CommandPhase::Superseded,
+            })
+            .collect();
+        self.pending.push_back(command);
+        superseded
+    }
+
+    pub fn pop(&mut self) -> Option<Command> {
+        self.pending.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(id: CommandId, kind: GpioCommand) -> Command {
+        Command { id, kind }
+    }
+
+    fn progress(id: CommandId, kind: GpioCommand, phase: CommandPhase) -> CommandProgress {
+        CommandProgress { id, command: kind, phase }
+    }
+
+    fn pending(queue: &mut CommandQueue) -> Vec<(CommandId, GpioCommand)> {
+        std::iter::from_fn(|| queue.pop()).map(|command| (command.id, command.kind)).collect()
+    }
+
+    #[test]
+    fn merges_an_identical_command() {
+        let mut queue = CommandQueue::default();
+        assert!(queue.push(command(1, GpioCommand::Open)).is_empty());
+        assert_eq!(
+            queue.push(command(2, GpioCommand::Open)),
+            [progress(2, GpioCommand::Open, CommandPhase::Merged(1))]
+        );
+        assert_eq!(
+            queue.push(command(3, GpioCommand::Position(0.5))),
+            [progress(1, GpioCommand::Open, CommandPhase::Superseded)]
+        );
+        assert_eq!(
+            queue.push(command(4, GpioCommand::Position(0.5))),
+            [progress(4, GpioCommand::Position(0.5), CommandPhase::Merged(3))]
+        );
+        assert_eq!(pending(&mut queue), [(3, GpioCommand::Position(0.5))]);
+    }
+
+    #[test]
+    fn different_command_supersedes_everything_pending() {
+        let mut queue = CommandQueue::default();
+        queue.push(command(1, GpioCommand::Toggle));
+        queue.push(command(2, GpioCommand::Toggle));
+        assert_eq!(
+            queue.push(command(3, GpioCommand::Close)),
+            [
+                progress(1, GpioCommand::Toggle, CommandPhase::Superseded),
+                progress(2, GpioCommand::Toggle, CommandPhase::Superseded),
+            ]
+        );
+        assert_eq!(
+            queue.push(command(4, GpioCommand::Open)),
+            [progress(3, GpioCommand::Close, CommandPhase::Superseded)]
+        );
+        assert_eq!(
+            queue.push(command(5, GpioCommand::Stop)),
+            [progress(4, GpioCommand::Open, CommandPhase::Superseded)]
+        );
+        assert_eq!(pending(&mut queue), [(5, GpioCommand::Stop)]);
+    }
+
+    #[test]
+    fn always_queues_toggles() {
+        let mut queue = CommandQueue::default();
+        queue.push(command(1, GpioCommand::Open));
+        assert!(queue.push(command(2, GpioCommand::Toggle)).is_empty());
+        assert!(queue.push(command(3, GpioCommand::Toggle)).is_empty());
+        assert_eq!(
+            pending(&mut queue),
+            [(1, GpioCommand::Open), (2, GpioCommand::Toggle), (3, GpioCommand::Toggle)]
+        );
+
+        // A command after a toggle isn't merged into one from before it
+        queue.push(command(4, GpioCommand::Open));
+        queue.push(command(5, GpioCommand::Toggle));
+        assert_eq!(
+            queue.push(command(6, GpioCommand::Open)),
+            [
+                progress(4, GpioCommand::Open, CommandPhase::Superseded),
+                progress(5, GpioCommand::Toggle, CommandPhase::Superseded),
+            ]
+        );
+        assert_eq!(pending(&mut queue), [(6, GpioCommand::Open)]);
+    }
+
+    #[test]
+    fn tracker_reports_every_phase_change() {
+        let tracker = CommandTracker::new(None);
+        let mut events = tracker.events();
+        let (command, _) = tracker.register(GpioCommand::Open, CommandSource::Api);
+        tracker.update(CommandProgress {
+            id: command.id,
+            command: GpioCommand::Open,
+            phase: CommandPhase::Superseded,
+        });
+        // Turned away before reaching the GPIO loop, but still given an ID
+        tracker.refused(GpioCommand::Close, CommandSource::Mqtt);
+
+        let phases: Vec<_> = std::iter::from_fn(|| events.try_recv().ok())
+            .map(|progress| (progress.id, progress.command, progress.phase))
+            .collect();
+        assert_eq!(
+            phases,
+            [
+                (1, GpioCommand::Open, CommandPhase::Pending),
+                (1, GpioCommand::Open, CommandPhase::Superseded),
+                (2, GpioCommand::Close, CommandPhase::Refused),
+            ]
+        );
+    }
+}
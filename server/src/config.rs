@@ -1,12 +1,52 @@
-use serde::Deserialize;
+use chrono::NaiveTime;
+use serde::{Deserialize, Deserializer, Serialize};
+use utoipa::ToSchema;
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct AppConfig {
     pub garage_door: GarageDoorConfig,
+    // More doors besides the one in garage_door
+    #[serde(default)]
+    pub doors: Vec<ExtraDoorConfig>,
+    // Applies to every door
+    pub auto_close: Option<AutoCloseConfig>,
+    #[serde(default)]
+    pub schedules: Vec<ScheduleConfig>,
+    pub mqtt: Option<MqttConfig>,
+    // Nothing is recorded if unset
+    pub history: Option<HistoryConfig>,
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+    #[serde(default)]
+    pub alerts: Vec<AlertConfig>,
+    // Where alerts are sent
+    #[serde(default)]
+    pub notifiers: Vec<NotifierConfig>,
+    // Only used when built with the homekit feature
+    pub homekit: Option<HomekitConfig>,
 }
 
+// Server settings and the default door, which the routes without a door ID act on
 #[derive(Debug, Deserialize, Clone)]
 pub struct GarageDoorConfig {
+    // ID of the default door, "default" if unset
+    pub id: Option<String>,
+    pub server_address: String,
+    pub api_key: String,
+    #[serde(flatten)]
+    pub door: DoorConfig,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ExtraDoorConfig {
+    pub id: String,
+    #[serde(flatten)]
+    pub door: DoorConfig,
+}
+
+// Pins and timings of a single door
+#[derive(Debug, Deserialize, Clone)]
+pub struct DoorConfig {
     pub close_limit_pin: u8,
     pub open_limit_pin: u8,
     pub coupler_pin: u8,
@@ -17,8 +57,256 @@ pub struct GarageDoorConfig {
     pub coupler_active_intervals: u64,
     pub coupler_rest_intervals: u64,
     pub limit_cooldown_ms: u64,
-    pub server_address: String,
-    pub api_key: String,
+    pub close_retry: Option<CloseRetryConfig>,
+    // Where to keep the learned travel times. They are only kept in memory if unset.
+    pub calibration_file: Option<String>,
+    // Where to keep the door state across restarts. This includes the learned
+    // travel times, which are then taken from here instead of calibration_file.
+    pub state_file: Option<String>,
+    pub close_limit_filter: Option<InputFilterConfig>,
+    pub open_limit_filter: Option<InputFilterConfig>,
+    // Wake up on limit switch edges instead of polling all the time, where the
+    // GPIO backend supports it. Defaults to true.
+    pub edge_interrupts: Option<bool>,
+    // How often to still poll while the door is idle when edge interrupts are
+    // in use, in case an edge gets missed. Defaults to 1000.
+    pub idle_poll_interval_ms: Option<u64>,
+    pub warning: Option<WarningConfig>,
+}
+
+// Flash a light or sound a buzzer on this pin for a while before every close
+#[derive(Debug, Deserialize, Clone)]
+pub struct WarningConfig {
+    pub pin: u8,
+    pub active_low: bool,
+    pub lead_time_sec: u64,
+    // Length of each flash and of the gap between them. The output stays on if unset.
+    pub pulse_ms: Option<u64>,
+}
+
+// Ignore limit switch changes that don't last. Both conditions must hold if both are set.
+#[derive(Debug, Deserialize, Clone)]
+pub struct InputFilterConfig {
+    // Consecutive polls that must read the new level
+    pub samples: Option<u32>,
+    // How long the new level must hold
+    pub stable_ms: Option<u64>,
+}
+
+// Retry a close that reversed or stalled before reaching the closed limit switch
+#[derive(Debug, Deserialize, Clone)]
+pub struct CloseRetryConfig {
+    pub attempts: u32,
+    pub delay_sec: u64,
+}
+
+// Close the door once it has been left open for a while
+#[derive(Debug, Deserialize, Clone)]
+pub struct AutoCloseConfig {
+    pub after_min: u64,
+    // Only close automatically within these times of day. Any time if empty.
+    #[serde(default)]
+    pub windows: Vec<TimeWindow>,
+}
+
+// MQTT broker to publish door state to and take commands from
+#[derive(Debug, Deserialize, Clone)]
+pub struct MqttConfig {
+    pub host: String,
+    // Defaults to 1883
+    pub port: Option<u16>,
+    // Also used to tell our entities apart in Home Assistant. Defaults to "piopener".
+    pub client_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // Topics go under <topic_prefix>/<door ID>/. Defaults to the client ID.
+    pub topic_prefix: Option<String>,
+    // Where Home Assistant looks for discovery messages. Defaults to "homeassistant".
+    pub discovery_prefix: Option<String>,
+    // Publish Home Assistant discovery messages. Defaults to true.
+    pub discovery: Option<bool>,
+}
+
+// Keep door state changes and commands in an SQLite database
+#[derive(Debug, Deserialize, Clone)]
+pub struct HistoryConfig {
+    // Defaults to "history.db"
+    pub path: Option<String>,
+    // Drop events older than this. Defaults to 90.
+    pub retention_days: Option<u32>,
+}
+
+// Somewhere to POST door events to as JSON
+#[derive(Debug, Deserialize, Clone)]
+pub struct WebhookConfig {
+    pub url: String,
+    // Sign requests with HMAC-SHA256 in the X-PiOpener-Signature header
+    pub secret: Option<String>,
+    // Only send these events. All of them if empty.
+    #[serde(default)]
+    pub events: Vec<WebhookEvent>,
+    // Tries per event before giving up. Defaults to 5.
+    pub max_attempts: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    /// The status or setpoint changed, other than into a fault
+    State,
+    Fault,
+    /// A command was received or finished, including commands refused or replaced before they ran
+    Command,
+    /// A left-open alert fired, reminded or cleared
+    LeftOpen,
+}
+
+// Notify people while a door is not closed
+#[derive(Debug, Deserialize, Clone)]
+pub struct AlertConfig {
+    pub name: String,
+    // Alert once the door has not been closed for this long. Defaults to 0.
+    pub after_min: Option<u64>,
+    // Only start alerting within these times of day. Any time if empty.
+    #[serde(default)]
+    pub windows: Vec<TimeWindow>,
+    // Minutes between reminders while the door stays open, the last gap repeating.
+    // No reminders if empty.
+    #[serde(default)]
+    pub reminder_min: Vec<u64>,
+    // Names of the notifiers to send to. All of them if empty.
+    #[serde(default)]
+    pub notify: Vec<String>,
+    // Every door if unset
+    pub door: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct NotifierConfig {
+    pub name: String,
+    // Only notify from this reminder on, for escalating to more people. Defaults to 0,
+    // the alert itself.
+    pub from_reminder: Option<u32>,
+    #[serde(flatten)]
+    pub backend: NotifierBackend,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NotifierBackend {
+    // POST the alert as JSON, signed like the webhooks if there is a secret
+    Webhook {
+        url: String,
+        secret: Option<String>,
+    },
+    // POST the message to an ntfy topic URL
+    Ntfy {
+        url: String,
+        // Sent as a bearer token
+        token: Option<String>,
+    },
+    Smtp(SmtpConfig),
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SmtpConfig {
+    pub host: String,
+    // Defaults to 465 with tls, 587 with starttls and 25 without
+    pub port: Option<u16>,
+    // Defaults to starttls
+    pub tls: Option<SmtpTls>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpTls {
+    None,
+    Starttls,
+    Tls,
+}
+
+// Apple Home accessory. Several doors are shown behind a bridge.
+#[derive(Debug, Deserialize, Clone)]
+#[cfg_attr(not(feature = "homekit"), allow(dead_code))]
+pub struct HomekitConfig {
+    // Entered in the Home app when pairing, like "123-45-678"
+    pub setup_code: String,
+    // Name shown while pairing. Defaults to "PiOpener".
+    pub name: Option<String>,
+    // Defaults to 51826
+    pub port: Option<u16>,
+    // Keeps the accessory identity and paired controllers. Defaults to "homekit.json".
+    pub state_file: Option<String>,
+}
+
+/// A door action that runs on a cron schedule
+#[derive(Debug, Deserialize, Clone, ToSchema)]
+pub struct ScheduleConfig {
+    pub name: String,
+    /// "min hour day month weekday", optionally with a leading seconds field
+    pub cron: String,
+    pub action: ScheduleAction,
+    /// Only run if the door is in this state when the schedule fires
+    pub only_if: Option<ScheduleCondition>,
+    /// The default door if unset
+    pub door: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ScheduleAction {
+    Open,
+    Close,
+    Toggle,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ScheduleCondition {
+    Open,
+    Closed,
+}
+
+// A daily time range like 21:00 to 07:00. It wraps past midnight if it ends before it starts.
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct TimeWindow {
+    #[serde(deserialize_with = "time_of_day")]
+    pub start: NaiveTime,
+    #[serde(deserialize_with = "time_of_day")]
+    pub end: NaiveTime,
+}
+
+impl TimeWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+// Times of day are written as "HH:MM"
+fn time_of_day<'de, D: Deserializer<'de>>(deserializer: D) -> Result<NaiveTime, D::Error> {
+    let time = String::deserialize(deserializer)?;
+    NaiveTime::parse_from_str(&time, "%H:%M").map_err(serde::de::Error::custom)
+}
+
+impl AppConfig {
+    pub fn default_door_id(&self) -> &str {
+        self.garage_door.id.as_deref().unwrap_or("default")
+    }
+
+    /// Every door with its ID, the default door first
+    pub fn doors(&self) -> Vec<(&str, &DoorConfig)> {
+        std::iter::once((self.default_door_id(), &self.garage_door.door))
+            .chain(self.doors.iter().map(|door| (door.id.as_str(), &door.door)))
+            .collect()
+    }
 }
 
 pub fn load_config() -> Result<AppConfig, config::ConfigError> {
@@ -26,4 +314,33 @@ pub fn load_config() -> Result<AppConfig, config::ConfigError> {
         .add_source(config::File::with_name("config"))
         .build()?
         .try_deserialize::<AppConfig>()
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(hour: u32, min: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, min, 0).unwrap()
+    }
+
+    #[test]
+    fn time_window_includes_its_start_but_not_its_end() {
+        let window = TimeWindow { start: time(8, 0), end: time(17, 30) };
+        assert!(!window.contains(time(7, 59)));
+        assert!(window.contains(time(8, 0)));
+        assert!(window.contains(time(12, 0)));
+        assert!(window.contains(time(17, 29)));
+        assert!(!window.contains(time(17, 30)));
+    }
+
+    #[test]
+    fn time_window_wraps_past_midnight() {
+        let window = TimeWindow { start: time(21, 0), end: time(7, 0) };
+        assert!(!window.contains(time(20, 59)));
+        assert!(window.contains(time(21, 0)));
+        assert!(window.contains(time(0, 0)));
+        assert!(window.contains(time(6, 59)));
+        assert!(!window.contains(time(7, 0)));
+        assert!(!window.contains(time(12, 0)));
+    }
+}
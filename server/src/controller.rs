@@ -0,0 +1,1608 @@
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use crate::{
+    calibration::TravelCalibration,
+    state_file::{SavedState, SavedStatus},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DoorState {
+    pub status: DoorStatus,
+    pub setpoint: DoorSetpoint,
+    /// Estimated opening from 0 (closed) to 1 (open), or `None` if we can't trust the estimate
+    pub position: Option<f64>,
+    /// The most recent command and how far along it is
+    pub command: Option<CommandProgress>,
+    /// Automatic close retries after a close didn't make it, if any are going on
+    pub close_retry: Option<CloseRetry>,
+    /// A close waiting out the warning before the door starts moving
+    pub warning: Option<Warning>,
+    pub calibration: TravelCalibration,
+}
+
+impl DoorState {
+    /// Whether the controller would refuse `command` in this state. If we don't know
+    /// where the door is or which way it will move, open and close could pick the wrong
+    /// number of clicks. A single toggle click is still fine unless the switches themselves
+    /// are broken, and stopping a door we don't know to be moving does nothing. Going to a
+    /// position needs a position estimate to work from.
+    pub fn refuses(&self, command: GpioCommand) -> bool {
+        match self.status {
+            DoorStatus::Fault { reason } if reason.is_hardware() => true,
+            DoorStatus::Unknown => !matches!(command, GpioCommand::Toggle | GpioCommand::Stop),
+            _ => matches!(command, GpioCommand::Position(_)) && self.position.is_none(),
+        }
+    }
+
+    /// Nothing is moving or about to, so the controller has no timers running
+    pub fn is_settled(&self) -> bool {
+        !matches!(self.status, DoorStatus::MovingUp | DoorStatus::MovingDown)
+            && self.command.is_none_or(|command| command.phase.is_finished())
+            && self.close_retry.is_none_or(|retry| retry.phase == RetryPhase::GaveUp)
+            && self.warning.is_none()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Warning {
+    /// The close command being warned about, `None` for a close retry
+    pub command: Option<CommandId>,
+    /// When the door starts closing unless the close is cancelled first
+    pub until: Instant,
+}
+
+// State tracking and GPIO command enums
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum DoorStatus {
+    Closed,
+    Open,
+    Ajar,
+    MovingUp,
+    MovingDown,
+    /// We have no idea where the door is, e.g. right after startup with the door between limits
+    Unknown,
+    /// Something went wrong and the door needs attention
+    Fault {
+        #[serde(rename = "fault")]
+        reason: FaultReason,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FaultReason {
+    /// The door ended up at the opposite limit switch, e.g. after the safety eye reversed it
+    Reversed,
+    /// The door never reached a limit switch, e.g. it stalled or a cable broke
+    Stalled,
+    /// Both limit switches are pressed at once, which can't happen with working wiring
+    LimitConflict,
+    /// A limit switch pin could not be read
+    PinReadError,
+}
+
+impl FaultReason {
+    /// Whether the fault comes from the switches themselves rather than from the door's travel.
+    /// While one of these is active we can't tell where the door is.
+    pub fn is_hardware(&self) -> bool {
+        matches!(self, FaultReason::LimitConflict | FaultReason::PinReadError)
+    }
+}
+
+// Flattened, this is a `setpoint` field plus `target_position` for a position
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "setpoint", content = "target_position", rename_all = "snake_case")]
+pub enum DoorSetpoint {
+    Closed,
+    Open,
+    Ajar,
+    /// Partly open, as a fraction from 0 (closed) to 1 (open)
+    Position(f64),
+}
+
+// Flattened like DoorSetpoint, with the target in `position`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "command", content = "position", rename_all = "snake_case")]
+pub enum GpioCommand {
+    Toggle,
+    Open,
+    Close,
+    /// Stop the door where it is if it is moving
+    Stop,
+    /// Move to a fraction between 0 (closed) and 1 (open)
+    Position(f64),
+}
+
+pub type CommandId = u64;
+
+/// A command together with the ID its caller uses to follow it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Command {
+    pub id: CommandId,
+    pub kind: GpioCommand,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+#[serde(tag = "phase", content = "merged_into", rename_all = "snake_case")]
+pub enum CommandPhase {
+    /// Accepted by the server but not yet picked up by the controller
+    Pending,
+    /// The coupler is clicking
+    Sequencing,
+    /// Warning people around the door before it starts closing
+    Warning,
+    /// Clicks are done, waiting for the door to reach the target limit switch
+    Travelling,
+    /// The door didn't close, waiting to try again
+    Retrying,
+    Completed,
+    /// The door did not reach the target limit switch in time, see the fault status
+    TimedOut,
+    /// A newer command replaced this one before it finished
+    Superseded,
+    /// The door was in a state where the command can't be carried out safely
+    Refused,
+    /// An identical command was already pending, follow that one instead
+    #[schema(value_type = u64)]
+    Merged(CommandId),
+    /// Cancelled while still in its warning
+    Cancelled,
+}
+
+impl CommandPhase {
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            CommandPhase::Completed
+                | CommandPhase::TimedOut
+                | CommandPhase::Superseded
+                | CommandPhase::Refused
+                | CommandPhase::Merged(_)
+                | CommandPhase::Cancelled
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+pub struct CommandProgress {
+    #[schema(value_type = u64)]
+    pub id: CommandId,
+    #[serde(flatten)]
+    pub command: GpioCommand,
+    #[serde(flatten)]
+    pub phase: CommandPhase,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+pub struct CloseRetry {
+    /// Retries started so far
+    pub attempt: u32,
+    /// Retries allowed in total
+    pub attempts: u32,
+    pub phase: RetryPhase,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryPhase {
+    /// Waiting out the delay before the next attempt
+    Waiting,
+    /// A retry is underway
+    Closing,
+    /// Out of attempts, somebody needs to look at the door
+    GaveUp,
+}
+
+/// Limit switch readings for a single poll. `true` means the switch is pressed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LimitSwitches {
+    pub closed: bool,
+    pub open: bool,
+}
+
+/// What the coupler should do, in order. The coupler sequencer turns these into pin levels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CouplerAction {
+    /// Press and release the opener button once
+    Click,
+    /// Hold the button released long enough for the opener to register a new click
+    Rest,
+}
+
+/// Result of a single controller step
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    pub state: DoorState,
+    pub coupler: Vec<CouplerAction>,
+    /// Every command whose phase changed during this step, including superseded ones
+    pub commands: Vec<CommandProgress>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ControllerConfig {
+    pub expected_shut_time: Duration,
+    pub limit_cooldown: Duration,
+    /// How long a commanded travel may take to reach its limit switch before we call it a fault
+    pub travel_timeout: Duration,
+    pub close_retry: Option<CloseRetryPolicy>,
+    /// How long the opener takes to react to a click, used to stop early enough for a position
+    pub coupler_latency: Duration,
+    /// Warn for this long before every close
+    pub warning_lead: Option<Duration>,
+}
+
+/// How to retry a close that reversed or stalled
+#[derive(Debug, Clone, Copy)]
+pub struct CloseRetryPolicy {
+    pub attempts: u32,
+    pub delay: Duration,
+}
+
+// What to do once a warning has run out
+#[derive(Debug)]
+enum PendingClose {
+    Command(Command),
+    Retry,
+}
+
+// A travel that has to reach a limit switch in time
+#[derive(Debug, Clone, Copy)]
+struct TravelWatch {
+    deadline: Instant,
+    // Started from the wall button or a remote rather than by one of our commands.
+    // Any limit switch ends it, since we don't know where the door was meant to go.
+    manual: bool,
+}
+
+#[derive(Debug)]
+struct ActiveCommand {
+    progress: CommandProgress,
+    target: DoorSetpoint,
+}
+
+/// Door state machine. It never touches pins or reads the clock itself, so the
+/// caller feeds it limit switch levels, commands and the current time.
+#[derive(Debug)]
+pub struct DoorController {
+    config: ControllerConfig,
+    state: DoorState,
+    last_direction: f64,
+    last_time: Instant,
+    last_full_close: Instant,
+    last_full_open: Instant,
+    last_switches: LimitSwitches,
+    active_command: Option<ActiveCommand>,
+    travel_watch: Option<TravelWatch>,
+    // When to start the next close retry
+    retry_at: Option<Instant>,
+    // Direction and start of a limit-to-limit travel we are timing
+    travel_timer: Option<(f64, Instant)>,
+    // A close waiting out its warning, and when the warning ends
+    warning: Option<(PendingClose, Instant)>,
+}
+
+impl DoorController {
+    /// `switches` is `None` if the limit switches could not be read
+    pub fn new(config: ControllerConfig, switches: Option<LimitSwitches>, now: Instant) -> Self {
+        let (status, setpoint, position) = match switches.map(|switches| (switches.closed, switches.open)) {
+            Some((true, false)) => (DoorStatus::Closed, DoorSetpoint::Closed, Some(0.0)),
+            Some((false, true)) => (DoorStatus::Open, DoorSetpoint::Open, Some(1.0)),
+            Some((false, false)) => (DoorStatus::Unknown, DoorSetpoint::Ajar, None),
+            Some((true, true)) => (
+                DoorStatus::Fault {
+                    reason: FaultReason::LimitConflict,
+                },
+                DoorSetpoint::Ajar,
+                None,
+            ),
+            None => (
+                DoorStatus::Fault {
+                    reason: FaultReason::PinReadError,
+                },
+                DoorSetpoint::Ajar,
+                None,
+            ),
+        };
+
+        Self {
+            config,
+            state: DoorState {
+                status,
+                setpoint,
+                position,
+                command: None,
+                close_retry: None,
+                warning: None,
+                calibration: TravelCalibration::default(),
+            },
+            last_direction: 0.0,
+            last_time: now,
+            last_full_close: now,
+            last_full_open: now,
+            last_switches: switches.unwrap_or(LimitSwitches {
+                closed: false,
+                open: false,
+            }),
+            active_command: None,
+            travel_watch: None,
+            retry_at: None,
+            travel_timer: None,
+            warning: None,
+        }
+    }
+
+    /// Start out with travel times learned earlier
+    pub fn with_calibration(mut self, calibration: TravelCalibration) -> Self {
+        self.state.calibration = calibration;
+        self
+    }
+
+    /// Pick up where a previous run left off, as far as the limit switches agree with it
+    pub fn restore(mut self, saved: SavedState) -> Self {
+        self.state.calibration = saved.calibration;
+        match (self.state.status, saved.status) {
+            (DoorStatus::Closed, _) => {
+                self.last_direction = -1.0;
+                if saved.setpoint == DoorSetpoint::Closed {
+                    self.state.setpoint = saved.setpoint;
+                }
+            }
+            (DoorStatus::Open, _) => {
+                self.last_direction = 1.0;
+                if saved.setpoint == DoorSetpoint::Open {
+                    self.state.setpoint = saved.setpoint;
+                }
+            }
+            // Stopped between the limits last time and neither switch is pressed now,
+            // so the door is most likely right where we left it
+            (DoorStatus::Unknown, SavedStatus::Ajar) if saved.position.is_some() => {
+                self.state.status = DoorStatus::Ajar;
+                self.state.setpoint = saved.setpoint;
+                self.state.position = saved.position;
+                self.last_direction = saved.last_direction;
+            }
+            // The door moved while we were down, or the switches are faulty
+            _ => {}
+        }
+        self
+    }
+
+    /// What to save so restore() can pick up from here
+    pub fn saved_state(&self) -> SavedState {
+        let status = match self.state.status {
+            DoorStatus::Closed => SavedStatus::Closed,
+            DoorStatus::Open => SavedStatus::Open,
+            DoorStatus::Ajar => SavedStatus::Ajar,
+            DoorStatus::MovingUp | DoorStatus::MovingDown => SavedStatus::Moving,
+            DoorStatus::Unknown | DoorStatus::Fault { .. } => SavedStatus::Unknown,
+        };
+        SavedState {
+            status,
+            setpoint: self.state.setpoint,
+            // The estimate changes every poll while moving, don't write it out that often
+            position: if status == SavedStatus::Moving { None } else { self.state.position },
+            last_direction: self.last_direction,
+            calibration: self.state.calibration,
+        }
+    }
+
+    pub fn state(&self) -> DoorState {
+        self.state
+    }
+
+    /// Call off a close that is still in its warning. Returns the progress of
+    /// the cancelled command, if it was one.
+    pub fn cancel_warning(&mut self) -> Option<CommandProgress> {
+        let (close, _) = self.warning.take()?;
+        self.state.warning = None;
+        if let PendingClose::Retry = close {
+            self.state.close_retry = None;
+        }
+        let active = self.active_command.as_mut().filter(|active| !active.progress.phase.is_finished())?;
+        active.progress.phase = CommandPhase::Cancelled;
+        self.state.command = Some(active.progress);
+        Some(active.progress)
+    }
+
+    /// Advance the state machine to `now` with the given switch readings (`None`
+    /// if they could not be read) and optional command. `coupler_busy` tells
+    /// whether previously queued clicks are still being played out. Returns the
+    /// new state, any coupler actions to queue and the progress of any commands
+    /// that moved along.
+    pub fn step(&mut self, switches: Option<LimitSwitches>, command: Option<Command>, coupler_busy: bool, now: Instant) -> Step {
+        let mut coupler = Vec::new();
+        let mut commands = Vec::new();
+        let progress_before = self.active_command.as_ref().map(|active| active.progress);
+        // The door only starts to leave its limit switch once the clicks are done
+        if coupler_busy {
+            match self.state.status {
+                DoorStatus::MovingUp => self.last_full_open = now,
+                DoorStatus::MovingDown => self.last_full_close = now,
+                _ => {}
+            }
+        }
+        let mut new_state = match switches {
+            Some(switches) => self.read_switches(switches, now),
+            None => DoorState {
+                status: DoorStatus::Fault {
+                    reason: FaultReason::PinReadError,
+                },
+                position: None,
+                ..self.state
+            },
+        };
+
+        let command = match command {
+            Some(cmd) if new_state.refuses(cmd.kind) => {
+                commands.push(CommandProgress {
+                    id: cmd.id,
+                    command: cmd.kind,
+                    phase: CommandPhase::Refused,
+                });
+                None
+            }
+            command => command,
+        };
+
+        // Deal with a close waiting out its warning
+        let mut warned = false;
+        let mut retry_now = false;
+        let command = match (command, &self.warning) {
+            // Asking again doesn't restart the warning
+            (Some(cmd), Some((PendingClose::Command(pending), _))) if cmd.kind == pending.kind => {
+                commands.push(CommandProgress {
+                    id: cmd.id,
+                    command: cmd.kind,
+                    phase: CommandPhase::Merged(pending.id),
+                });
+                None
+            }
+            // Anything else takes over from the warned close
+            (Some(cmd), Some(_)) => {
+                self.warning = None;
+                Some(cmd)
+            }
+            (None, Some((_, until))) if now >= *until => {
+                warned = true;
+                match self.warning.take() {
+                    Some((PendingClose::Command(cmd), _)) => Some(cmd),
+                    _ => {
+                        retry_now = true;
+                        None
+                    }
+                }
+            }
+            (command, _) => command,
+        };
+
+        // Closes get a warning first, unless they just had one
+        let command = match (command, self.config.warning_lead) {
+            (Some(cmd), Some(lead)) if !warned && self.closes(cmd.kind, new_state) => {
+                self.supersede_active(&mut commands);
+                self.active_command = Some(ActiveCommand {
+                    progress: CommandProgress {
+                        id: cmd.id,
+                        command: cmd.kind,
+                        phase: CommandPhase::Warning,
+                    },
+                    target: new_state.setpoint,
+                });
+                self.warning = Some((PendingClose::Command(cmd), now + lead));
+                new_state.close_retry = None;
+                self.retry_at = None;
+                None
+            }
+            (command, _) => command,
+        };
+
+        if let Some(cmd) = command {
+            if let DoorStatus::Fault { .. } = new_state.status {
+                // A new command clears the fault. Pick the clicks based on what the switches say.
+                new_state.status = self.status_from_switches();
+            }
+
+            new_state = match cmd.kind {
+                GpioCommand::Toggle => self.toggle(new_state, now, &mut coupler),
+                GpioCommand::Open => self.open(new_state, now, &mut coupler),
+                GpioCommand::Close => self.close(new_state, now, &mut coupler),
+                GpioCommand::Stop => self.stop(new_state, &mut coupler),
+                GpioCommand::Position(target) => self.go_to(target, new_state, now, &mut coupler),
+            };
+
+            if !warned {
+                self.supersede_active(&mut commands);
+            }
+            self.active_command = Some(ActiveCommand {
+                progress: CommandProgress {
+                    id: cmd.id,
+                    command: cmd.kind,
+                    phase: CommandPhase::Sequencing,
+                },
+                target: new_state.setpoint,
+            });
+            self.travel_watch = match new_state.setpoint {
+                DoorSetpoint::Open | DoorSetpoint::Closed | DoorSetpoint::Position(_) => Some(TravelWatch {
+                    deadline: now + self.config.travel_timeout,
+                    manual: false,
+                }),
+                DoorSetpoint::Ajar => None,
+            };
+            // Whoever sent the command is in charge now
+            new_state.close_retry = None;
+            self.retry_at = None;
+        } else if retry_now {
+            new_state = self.retry_close(new_state, now, &mut coupler);
+        } else if self.retry_at.is_some_and(|at| now >= at) {
+            match self.config.warning_lead {
+                Some(lead) => {
+                    self.retry_at = None;
+                    self.warning = Some((PendingClose::Retry, now + lead));
+                }
+                None => new_state = self.retry_close(new_state, now, &mut coupler),
+            }
+        }
+
+        // The door has to be underway before we can tell when to stop it
+        if !coupler_busy && coupler.is_empty() {
+            self.stop_at_position(&mut new_state, &mut coupler);
+        }
+
+        if !coupler.is_empty() {
+            // The door gets stopped or reversed, so this won't be a clean limit-to-limit run
+            self.travel_timer = None;
+        }
+        if let Some(switches) = switches {
+            self.time_travel(&mut new_state, switches, now);
+        }
+
+        self.watch_travel(&mut new_state, switches, now);
+
+        // The clicks for a new command were only just queued, so don't look at coupler_busy yet
+        let coupler_busy = coupler_busy || !coupler.is_empty();
+        self.track_command(new_state, coupler_busy);
+        let progress = self.active_command.as_ref().map(|active| active.progress);
+        if command.is_some() || progress != progress_before {
+            commands.extend(progress);
+        }
+
+        new_state.warning = self.warning.as_ref().map(|(close, until)| Warning {
+            command: match close {
+                PendingClose::Command(cmd) => Some(cmd.id),
+                PendingClose::Retry => None,
+            },
+            until: *until,
+        });
+
+        new_state.command = self.active_command.as_ref().map(|active| active.progress);
+        self.state = new_state;
+        self.last_time = now;
+        if let Some(switches) = switches {
+            self.last_switches = switches;
+        }
+
+        Step {
+            state: new_state,
+            coupler,
+            commands,
+        }
+    }
+
+    // Learn how long a full travel takes in each direction. Timing runs from
+    // one limit switch releasing to the other one being pressed.
+    fn time_travel(&mut self, state: &mut DoorState, switches: LimitSwitches, now: Instant) {
+        let last = self.last_switches;
+        if last.closed && !switches.closed {
+            self.travel_timer = Some((1.0, now));
+        } else if last.open && !switches.open {
+            self.travel_timer = Some((-1.0, now));
+        } else if (!last.open && switches.open) || (!last.closed && switches.closed) {
+            let arrived_direction = if switches.open { 1.0 } else { -1.0 };
+            if let Some((direction, started)) = self.travel_timer.take()
+                && direction == arrived_direction
+            {
+                let travel_time = now.duration_since(started);
+                // Anything slower than the watchdog allows is not a normal run
+                if travel_time <= self.config.travel_timeout {
+                    let stats = if direction > 0.0 { &mut state.calibration.up } else { &mut state.calibration.down };
+                    stats.add_sample(travel_time);
+                }
+            }
+        }
+    }
+
+    // Travel watchdog. If the door doesn't reach its limit switch in time, it
+    // reversed or got stuck and the position estimate is useless.
+    fn watch_travel(&mut self, state: &mut DoorState, switches: Option<LimitSwitches>, now: Instant) {
+        // The door left a limit switch without a command from us
+        let departed = matches!(self.state.status, DoorStatus::Closed | DoorStatus::Open)
+            && matches!(state.status, DoorStatus::MovingUp | DoorStatus::MovingDown);
+        if self.travel_watch.is_none() && departed {
+            self.travel_watch = Some(TravelWatch {
+                deadline: now + self.config.travel_timeout,
+                manual: true,
+            });
+        }
+        let Some(watch) = self.travel_watch else {
+            return;
+        };
+
+        if let DoorStatus::Fault { .. } = state.status {
+            // Already faulted, most likely unreadable switches. Don't cover that up.
+        } else if watch.manual && matches!(state.status, DoorStatus::Closed | DoorStatus::Open) {
+            self.travel_watch = None;
+        } else if reached(state.setpoint, state.status) {
+            self.travel_watch = None;
+            if state.setpoint == DoorSetpoint::Closed {
+                state.close_retry = None;
+            }
+        } else if now > watch.deadline && watch.manual {
+            state.status = DoorStatus::Fault {
+                reason: FaultReason::Stalled,
+            };
+            state.position = None;
+            self.travel_watch = None;
+        } else if now > watch.deadline {
+            let reversed = switches.is_some_and(|switches| match state.setpoint {
+                DoorSetpoint::Closed => switches.open && !switches.closed,
+                DoorSetpoint::Open => switches.closed && !switches.open,
+                DoorSetpoint::Ajar | DoorSetpoint::Position(_) => false,
+            });
+            state.status = DoorStatus::Fault {
+                reason: if reversed { FaultReason::Reversed } else { FaultReason::Stalled },
+            };
+            state.position = None;
+            self.travel_watch = None;
+
+            if state.setpoint == DoorSetpoint::Closed {
+                self.schedule_close_retry(state, now);
+            }
+        }
+    }
+
+    // After a failed close, try again later if the policy allows it
+    fn schedule_close_retry(&mut self, state: &mut DoorState, now: Instant) {
+        let Some(policy) = self.config.close_retry else {
+            return;
+        };
+
+        let attempt = state.close_retry.map_or(0, |retry| retry.attempt);
+        let phase = if attempt < policy.attempts {
+            self.retry_at = Some(now + policy.delay);
+            RetryPhase::Waiting
+        } else {
+            RetryPhase::GaveUp
+        };
+        state.close_retry = Some(CloseRetry {
+            attempt,
+            attempts: policy.attempts,
+            phase,
+        });
+    }
+
+    // Send the door down again using the same clicks a close command would
+    fn retry_close(&mut self, state: DoorState, now: Instant, coupler: &mut Vec<CouplerAction>) -> DoorState {
+        self.retry_at = None;
+        let state = DoorState {
+            status: self.status_from_switches(),
+            ..state
+        };
+        let mut state = self.close(state, now, coupler);
+        self.travel_watch = Some(TravelWatch {
+            deadline: now + self.config.travel_timeout,
+            manual: false,
+        });
+
+        if let Some(retry) = &mut state.close_retry {
+            retry.attempt += 1;
+            retry.phase = RetryPhase::Closing;
+        }
+        if let Some(active) = &mut self.active_command {
+            active.progress.phase = CommandPhase::Sequencing;
+        }
+        state
+    }
+
+    fn supersede_active(&mut self, commands: &mut Vec<CommandProgress>) {
+        if let Some(mut previous) = self.active_command.take().filter(|active| !active.progress.phase.is_finished()) {
+            previous.progress.phase = CommandPhase::Superseded;
+            commands.push(previous.progress);
+        }
+    }
+
+    // Whether carrying out the command sends the door down
+    fn closes(&self, command: GpioCommand, state: DoorState) -> bool {
+        let status = match state.status {
+            // The command clears the fault, see step()
+            DoorStatus::Fault { .. } => self.status_from_switches(),
+            status => status,
+        };
+        match command {
+            GpioCommand::Open | GpioCommand::Stop => false,
+            GpioCommand::Close => !matches!(status, DoorStatus::Closed | DoorStatus::MovingDown),
+            GpioCommand::Toggle => match status {
+                DoorStatus::Open => true,
+                DoorStatus::Ajar => self.last_direction > 0.0,
+                _ => false,
+            },
+            GpioCommand::Position(target) => {
+                status != DoorStatus::MovingDown
+                    && state.position.is_some_and(|position| target < position - POSITION_TOLERANCE)
+            }
+        }
+    }
+
+    // Move the active command along
+    fn track_command(&mut self, state: DoorState, coupler_busy: bool) {
+        let Some(active) = self.active_command.as_mut() else {
+            return;
+        };
+
+        if active.progress.phase == CommandPhase::Sequencing && !coupler_busy {
+            active.progress.phase = CommandPhase::Travelling;
+        }
+
+        if active.progress.phase == CommandPhase::Travelling {
+            if let DoorStatus::Fault { reason } = state.status {
+                if reason.is_hardware() {
+                    // Keep waiting, the switches may come back
+                } else if state.close_retry.is_some_and(|retry| retry.phase == RetryPhase::Waiting) {
+                    active.progress.phase = CommandPhase::Retrying;
+                } else {
+                    active.progress.phase = CommandPhase::TimedOut;
+                }
+            } else if active.target == DoorSetpoint::Ajar || reached(active.target, state.status) {
+                // Stopping the door is done as soon as the click is
+                active.progress.phase = CommandPhase::Completed;
+            }
+        }
+    }
+
+    fn status_from_switches(&self) -> DoorStatus {
+        match (self.last_switches.closed, self.last_switches.open) {
+            (true, false) => DoorStatus::Closed,
+            (false, true) => DoorStatus::Open,
+            _ => DoorStatus::Ajar,
+        }
+    }
+
+    // Update door state with timing consideration
+    fn read_switches(&mut self, switches: LimitSwitches, now: Instant) -> DoorState {
+        let last_state = self.state;
+        // A travel fault sticks around until a limit switch changes or a new command comes in
+        if let DoorStatus::Fault { reason } = last_state.status
+            && !reason.is_hardware()
+            && switches == self.last_switches
+        {
+            return last_state;
+        }
+
+        match (switches.closed, switches.open) {
+            (true, true) => DoorState {
+                status: DoorStatus::Fault {
+                    reason: FaultReason::LimitConflict,
+                },
+                position: None,
+                ..last_state
+            },
+            (true, false) => {
+                if now.duration_since(self.last_full_open) < self.config.limit_cooldown {
+                    last_state
+                } else {
+                    self.last_direction = -1.0;
+                    DoorState {
+                        status: DoorStatus::Closed,
+                        position: Some(0.0),
+                        ..last_state
+                    }
+                }
+            }
+            (false, true) => {
+                if now.duration_since(self.last_full_close) < self.config.limit_cooldown {
+                    last_state
+                } else {
+                    self.last_direction = 1.0;
+                    DoorState {
+                        status: DoorStatus::Open,
+                        position: Some(1.0),
+                        ..last_state
+                    }
+                }
+            }
+            (false, false) => match last_state.status {
+                DoorStatus::Closed => DoorState {
+                    status: DoorStatus::MovingUp,
+                    position: self.integrate_position(now),
+                    ..last_state
+                },
+                DoorStatus::Open => DoorState {
+                    status: DoorStatus::MovingDown,
+                    position: self.integrate_position(now),
+                    ..last_state
+                },
+                DoorStatus::MovingUp | DoorStatus::MovingDown => DoorState {
+                    position: self.integrate_position(now),
+                    ..last_state
+                },
+                DoorStatus::Fault { reason } if reason.is_hardware() => DoorState {
+                    // The switches are back but the door is between them, so we lost track of it
+                    status: DoorStatus::Unknown,
+                    position: None,
+                    ..last_state
+                },
+                DoorStatus::Ajar | DoorStatus::Unknown | DoorStatus::Fault { .. } => last_state,
+            },
+        }
+    }
+
+    fn integrate_position(&self, now: Instant) -> Option<f64> {
+        let stats = if self.last_direction > 0.0 { self.state.calibration.up } else { self.state.calibration.down };
+        let travel_time = stats.travel_time(self.config.expected_shut_time);
+        let travelled = now.duration_since(self.last_time).as_secs_f64() / travel_time.as_secs_f64();
+        self.state
+            .position
+            .map(|position| (position + self.last_direction * travelled).clamp(0.0, 1.0))
+    }
+
+    fn toggle(&mut self, state: DoorState, now: Instant, coupler: &mut Vec<CouplerAction>) -> DoorState {
+        // If toggled we will always activate coupler exactly once
+        coupler.push(CouplerAction::Click);
+        // We will invert setpoint. If stopped, we will use the direction opposite the last direction.
+        match state.status {
+            DoorStatus::Closed => {
+                self.last_full_open = now;
+                self.last_direction = 1.0;
+                moving(DoorStatus::MovingUp, DoorSetpoint::Open, state)
+            }
+            DoorStatus::Open => {
+                self.last_full_close = now;
+                self.last_direction = -1.0;
+                moving(DoorStatus::MovingDown, DoorSetpoint::Closed, state)
+            }
+            DoorStatus::MovingUp | DoorStatus::MovingDown => moving(DoorStatus::Ajar, DoorSetpoint::Ajar, state),
+            // No idea which way it will go, wait for a limit switch to tell us
+            DoorStatus::Unknown => DoorState {
+                setpoint: DoorSetpoint::Ajar,
+                ..state
+            },
+            DoorStatus::Ajar | DoorStatus::Fault { .. } => {
+                if self.last_direction > 0.0 {
+                    self.last_direction = -1.0;
+                    moving(DoorStatus::MovingDown, DoorSetpoint::Closed, state)
+                } else {
+                    self.last_direction = 1.0;
+                    moving(DoorStatus::MovingUp, DoorSetpoint::Open, state)
+                }
+            }
+        }
+    }
+
+    fn stop(&mut self, state: DoorState, coupler: &mut Vec<CouplerAction>) -> DoorState {
+        match state.status {
+            DoorStatus::MovingUp | DoorStatus::MovingDown => {
+                coupler.push(CouplerAction::Click);
+                moving(DoorStatus::Ajar, DoorSetpoint::Ajar, state)
+            }
+            // Already standing still, just stop heading anywhere else
+            DoorStatus::Closed => DoorState {
+                setpoint: DoorSetpoint::Closed,
+                ..state
+            },
+            DoorStatus::Open => DoorState {
+                setpoint: DoorSetpoint::Open,
+                ..state
+            },
+            _ => DoorState {
+                setpoint: DoorSetpoint::Ajar,
+                ..state
+            },
+        }
+    }
+
+    fn go_to(&mut self, target: f64, state: DoorState, now: Instant, coupler: &mut Vec<CouplerAction>) -> DoorState {
+        if target <= 0.0 {
+            return self.close(state, now, coupler);
+        }
+        if target >= 1.0 {
+            return self.open(state, now, coupler);
+        }
+
+        let setpoint = DoorSetpoint::Position(target);
+        // Position is always known here, see DoorState::refuses
+        let position = state.position.unwrap_or(target);
+        if (target - position).abs() < POSITION_TOLERANCE {
+            // Close enough already, just make sure the door stays there
+            return match state.status {
+                DoorStatus::MovingUp | DoorStatus::MovingDown => {
+                    coupler.push(CouplerAction::Click);
+                    moving(DoorStatus::Ajar, setpoint, state)
+                }
+                _ => DoorState { setpoint, ..state },
+            };
+        }
+
+        // Get the door going the right way, stop_at_position() takes it from there
+        let new_state = if target > position {
+            self.open(state, now, coupler)
+        } else {
+            self.close(state, now, coupler)
+        };
+        DoorState { setpoint, ..new_state }
+    }
+
+    // Send the stop click once the door is close enough to its target position
+    fn stop_at_position(&mut self, state: &mut DoorState, coupler: &mut Vec<CouplerAction>) {
+        let (DoorSetpoint::Position(target), Some(position)) = (state.setpoint, state.position) else {
+            return;
+        };
+
+        // How far the door keeps going while the opener reacts to the click
+        let stats = if self.last_direction > 0.0 { state.calibration.up } else { state.calibration.down };
+        let travel_time = stats.travel_time(self.config.expected_shut_time);
+        let lead = self.config.coupler_latency.as_secs_f64() / travel_time.as_secs_f64();
+
+        let arrived = match state.status {
+            DoorStatus::MovingUp => position + lead >= target,
+            DoorStatus::MovingDown => position - lead <= target,
+            _ => false,
+        };
+        if arrived {
+            coupler.push(CouplerAction::Click);
+            state.status = DoorStatus::Ajar;
+        }
+    }
+
+    fn open(&mut self, state: DoorState, now: Instant, coupler: &mut Vec<CouplerAction>) -> DoorState {
+        // If command is open or close then we need to decide if we need 0, 1, 2, or 3 clicks
+        let new_state = match state.status {
+            DoorStatus::MovingDown => {
+                coupler.extend(REVERSE);
+                moving(DoorStatus::MovingUp, DoorSetpoint::Open, state)
+            }
+            DoorStatus::Ajar | DoorStatus::Unknown | DoorStatus::Fault { .. } => {
+                // If it went up last time, now it will go down, so we need three clicks. Otherwise we just need 1
+                if self.last_direction > 0.0 {
+                    coupler.extend(RESUME_REVERSED);
+                } else {
+                    coupler.push(CouplerAction::Click);
+                }
+                moving(DoorStatus::MovingUp, DoorSetpoint::Open, state)
+            }
+            DoorStatus::Closed => {
+                self.last_full_open = now;
+                coupler.push(CouplerAction::Click);
+                moving(DoorStatus::MovingUp, DoorSetpoint::Open, state)
+            }
+            DoorStatus::MovingUp | DoorStatus::Open => DoorState {
+                setpoint: DoorSetpoint::Open,
+                ..state
+            },
+        };
+        // In all cases, we are now moving up (hopefully)
+        self.last_direction = 1.0;
+        new_state
+    }
+
+    fn close(&mut self, state: DoorState, now: Instant, coupler: &mut Vec<CouplerAction>) -> DoorState {
+        // If command is close then we need to decide if we need 0, 1, 2, or 3 clicks
+        let new_state = match state.status {
+            DoorStatus::MovingUp => {
+                coupler.extend(REVERSE);
+                moving(DoorStatus::MovingDown, DoorSetpoint::Closed, state)
+            }
+            DoorStatus::Ajar | DoorStatus::Unknown | DoorStatus::Fault { .. } => {
+                // If it went down last time, now it will go up, so we need three clicks. Otherwise we just need 1
+                if self.last_direction < 0.0 {
+                    coupler.extend(RESUME_REVERSED);
+                } else {
+                    coupler.push(CouplerAction::Click);
+                }
+                moving(DoorStatus::MovingDown, DoorSetpoint::Closed, state)
+            }
+            DoorStatus::Open => {
+                self.last_full_close = now;
+                coupler.push(CouplerAction::Click);
+                moving(DoorStatus::MovingDown, DoorSetpoint::Closed, state)
+            }
+            DoorStatus::MovingDown | DoorStatus::Closed => DoorState {
+                setpoint: DoorSetpoint::Closed,
+                ..state
+            },
+        };
+        // In all cases we should be moving down
+        self.last_direction = -1.0;
+        new_state
+    }
+}
+
+/// Stop the door, then start it again in the opposite direction
+const REVERSE: [CouplerAction; 3] = [CouplerAction::Click, CouplerAction::Rest, CouplerAction::Click];
+
+/// From a stopped door: start it (the wrong way), stop it, then start it again the right way
+const RESUME_REVERSED: [CouplerAction; 4] = [
+    CouplerAction::Click,
+    CouplerAction::Click,
+    CouplerAction::Rest,
+    CouplerAction::Click,
+];
+
+/// How far from a target position still counts as being there
+const POSITION_TOLERANCE: f64 = 0.02;
+
+fn reached(setpoint: DoorSetpoint, status: DoorStatus) -> bool {
+    matches!(
+        (setpoint, status),
+        (DoorSetpoint::Closed, DoorStatus::Closed)
+            | (DoorSetpoint::Open, DoorStatus::Open)
+            // Stopped, or ran into a limit switch because the estimate was off
+            | (DoorSetpoint::Position(_), DoorStatus::Ajar | DoorStatus::Closed | DoorStatus::Open)
+    )
+}
+
+fn moving(status: DoorStatus, setpoint: DoorSetpoint, state: DoorState) -> DoorState {
+    DoorState {
+        status,
+        setpoint,
+        ..state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLOSED: LimitSwitches = LimitSwitches { closed: true, open: false };
+    const OPEN: LimitSwitches = LimitSwitches { closed: false, open: true };
+    const BETWEEN: LimitSwitches = LimitSwitches { closed: false, open: false };
+    const BOTH: LimitSwitches = LimitSwitches { closed: true, open: true };
+
+    fn config() -> ControllerConfig {
+        ControllerConfig {
+            expected_shut_time: Duration::from_secs(10),
+            limit_cooldown: Duration::from_millis(250),
+            travel_timeout: Duration::from_secs(13),
+            close_retry: None,
+            coupler_latency: Duration::from_millis(100),
+            warning_lead: None,
+        }
+    }
+
+    // A controller with a clock the test moves forward
+    struct Door {
+        controller: DoorController,
+        now: Instant,
+        next_id: CommandId,
+    }
+
+    impl Door {
+        fn new(config: ControllerConfig, switches: LimitSwitches) -> Self {
+            let now = Instant::now();
+            Self {
+                controller: DoorController::new(config, Some(switches), now),
+                now,
+                next_id: 1,
+            }
+        }
+
+        // Start over as if the server restarted with `saved` on disk
+        fn restart(&self, switches: LimitSwitches, saved: SavedState) -> Self {
+            Self {
+                controller: DoorController::new(config(), Some(switches), self.now).restore(saved),
+                now: self.now,
+                next_id: self.next_id,
+            }
+        }
+
+        fn poll(&mut self, after: Duration, switches: LimitSwitches) -> Step {
+            self.now += after;
+            self.controller.step(Some(switches), None, false, self.now)
+        }
+
+        fn command(&mut self, after: Duration, switches: LimitSwitches, kind: GpioCommand) -> Step {
+            self.now += after;
+            let command = Command { id: self.next_id, kind };
+            self.next_id += 1;
+            self.controller.step(Some(switches), Some(command), false, self.now)
+        }
+    }
+
+    fn secs(secs: f64) -> Duration {
+        Duration::from_secs_f64(secs)
+    }
+
+    #[test]
+    fn starts_from_the_limit_switches() {
+        let door = Door::new(config(), CLOSED);
+        assert_eq!(door.controller.state().status, DoorStatus::Closed);
+        assert_eq!(door.controller.state().setpoint, DoorSetpoint::Closed);
+        assert_eq!(door.controller.state().position, Some(0.0));
+
+        let door = Door::new(config(), OPEN);
+        assert_eq!(door.controller.state().status, DoorStatus::Open);
+        assert_eq!(door.controller.state().setpoint, DoorSetpoint::Open);
+        assert_eq!(door.controller.state().position, Some(1.0));
+
+        let door = Door::new(config(), BETWEEN);
+        assert_eq!(door.controller.state().status, DoorStatus::Unknown);
+        assert_eq!(door.controller.state().position, None);
+
+        let door = Door::new(config(), BOTH);
+        assert_eq!(
+            door.controller.state().status,
+            DoorStatus::Fault { reason: FaultReason::LimitConflict }
+        );
+    }
+
+    #[test]
+    fn every_status_and_command() {
+        fn closed() -> Door {
+            Door::new(config(), CLOSED)
+        }
+        fn open() -> Door {
+            Door::new(config(), OPEN)
+        }
+        fn moving_up() -> Door {
+            let mut door = closed();
+            door.command(secs(1.0), CLOSED, GpioCommand::Open);
+            door.poll(secs(1.0), BETWEEN);
+            door
+        }
+        fn moving_down() -> Door {
+            let mut door = open();
+            door.command(secs(1.0), OPEN, GpioCommand::Close);
+            door.poll(secs(1.0), BETWEEN);
+            door
+        }
+        fn stopped_going_up() -> Door {
+            let mut door = moving_up();
+            door.command(secs(1.0), BETWEEN, GpioCommand::Stop);
+            door
+        }
+        fn stopped_going_down() -> Door {
+            let mut door = moving_down();
+            door.command(secs(1.0), BETWEEN, GpioCommand::Stop);
+            door
+        }
+        fn unknown() -> Door {
+            Door::new(config(), BETWEEN)
+        }
+
+        use CouplerAction::Click;
+        use DoorSetpoint as Set;
+        use DoorStatus::*;
+        use GpioCommand::{Close as CloseCmd, Open as OpenCmd, Stop, Toggle};
+        // How the door got there, what it is sent, and the clicks and state that should follow
+        type Case = (fn() -> Door, LimitSwitches, GpioCommand, &'static [CouplerAction], DoorStatus, DoorSetpoint);
+        let cases: [Case; 28] = [
+            (closed, CLOSED, Toggle, &[Click], MovingUp, Set::Open),
+            (closed, CLOSED, OpenCmd, &[Click], MovingUp, Set::Open),
+            (closed, CLOSED, CloseCmd, &[], Closed, Set::Closed),
+            (closed, CLOSED, Stop, &[], Closed, Set::Closed),
+            (open, OPEN, Toggle, &[Click], MovingDown, Set::Closed),
+            (open, OPEN, OpenCmd, &[], Open, Set::Open),
+            (open, OPEN, CloseCmd, &[Click], MovingDown, Set::Closed),
+            (open, OPEN, Stop, &[], Open, Set::Open),
+            (moving_up, BETWEEN, Toggle, &[Click], Ajar, Set::Ajar),
+            (moving_up, BETWEEN, OpenCmd, &[], MovingUp, Set::Open),
+            (moving_up, BETWEEN, CloseCmd, &REVERSE, MovingDown, Set::Closed),
+            (moving_up, BETWEEN, Stop, &[Click], Ajar, Set::Ajar),
+            (moving_down, BETWEEN, Toggle, &[Click], Ajar, Set::Ajar),
+            (moving_down, BETWEEN, OpenCmd, &REVERSE, MovingUp, Set::Open),
+            (moving_down, BETWEEN, CloseCmd, &[], MovingDown, Set::Closed),
+            (moving_down, BETWEEN, Stop, &[Click], Ajar, Set::Ajar),
+            (stopped_going_up, BETWEEN, Toggle, &[Click], MovingDown, Set::Closed),
+            (stopped_going_up, BETWEEN, OpenCmd, &RESUME_REVERSED, MovingUp, Set::Open),
+            (stopped_going_up, BETWEEN, CloseCmd, &[Click], MovingDown, Set::Closed),
+            (stopped_going_up, BETWEEN, Stop, &[], Ajar, Set::Ajar),
+            (stopped_going_down, BETWEEN, Toggle, &[Click], MovingUp, Set::Open),
+            (stopped_going_down, BETWEEN, OpenCmd, &[Click], MovingUp, Set::Open),
+            (stopped_going_down, BETWEEN, CloseCmd, &RESUME_REVERSED, MovingDown, Set::Closed),
+            (stopped_going_down, BETWEEN, Stop, &[], Ajar, Set::Ajar),
+            // Nobody knows which way a click sends it
+            (unknown, BETWEEN, Toggle, &[Click], Unknown, Set::Ajar),
+            (unknown, BETWEEN, OpenCmd, &[], Unknown, Set::Ajar),
+            (unknown, BETWEEN, CloseCmd, &[], Unknown, Set::Ajar),
+            (unknown, BETWEEN, Stop, &[], Unknown, Set::Ajar),
+        ];
+        for (index, (setup, switches, command, coupler, status, setpoint)) in cases.into_iter().enumerate() {
+            let mut door = setup();
+            let step = door.command(secs(1.0), switches, command);
+            assert_eq!(step.coupler, coupler, "case {}", index);
+            assert_eq!((step.state.status, step.state.setpoint), (status, setpoint), "case {}", index);
+        }
+    }
+
+    #[test]
+    fn opens_from_closed_with_one_click() {
+        let mut door = Door::new(config(), CLOSED);
+        let step = door.command(secs(1.0), CLOSED, GpioCommand::Open);
+        assert_eq!(step.coupler, [CouplerAction::Click]);
+        assert_eq!(step.state.status, DoorStatus::MovingUp);
+        assert_eq!(step.state.setpoint, DoorSetpoint::Open);
+        assert_eq!(step.state.command.map(|command| command.phase), Some(CommandPhase::Sequencing));
+
+        // Open and Close do nothing when the door is already there
+        let mut door = Door::new(config(), OPEN);
+        let step = door.command(secs(1.0), OPEN, GpioCommand::Open);
+        assert!(step.coupler.is_empty());
+        assert_eq!(step.state.status, DoorStatus::Open);
+    }
+
+    #[test]
+    fn reverses_a_moving_door() {
+        let mut door = Door::new(config(), CLOSED);
+        door.command(secs(1.0), CLOSED, GpioCommand::Open);
+        assert_eq!(door.poll(secs(1.0), BETWEEN).state.status, DoorStatus::MovingUp);
+
+        let step = door.command(secs(1.0), BETWEEN, GpioCommand::Close);
+        assert_eq!(step.coupler, REVERSE);
+        assert_eq!(step.coupler, [CouplerAction::Click, CouplerAction::Rest, CouplerAction::Click]);
+        assert_eq!(step.state.status, DoorStatus::MovingDown);
+        assert_eq!(step.state.setpoint, DoorSetpoint::Closed);
+
+        let step = door.command(secs(1.0), BETWEEN, GpioCommand::Open);
+        assert_eq!(step.coupler, REVERSE);
+        assert_eq!(step.state.status, DoorStatus::MovingUp);
+    }
+
+    #[test]
+    fn resumes_a_stopped_door_the_other_way() {
+        // Stopped on the way up, so the next click would send it down
+        let mut door = Door::new(config(), CLOSED);
+        door.command(secs(1.0), CLOSED, GpioCommand::Open);
+        door.poll(secs(1.0), BETWEEN);
+        let step = door.command(secs(1.0), BETWEEN, GpioCommand::Stop);
+        assert_eq!(step.coupler, [CouplerAction::Click]);
+        assert_eq!(step.state.status, DoorStatus::Ajar);
+
+        let step = door.command(secs(1.0), BETWEEN, GpioCommand::Open);
+        assert_eq!(step.coupler, RESUME_REVERSED);
+        assert_eq!(
+            step.coupler,
+            [CouplerAction::Click, CouplerAction::Click, CouplerAction::Rest, CouplerAction::Click]
+        );
+        assert_eq!(step.state.status, DoorStatus::MovingUp);
+        assert_eq!(step.state.setpoint, DoorSetpoint::Open);
+    }
+
+    #[test]
+    fn closes_from_ajar() {
+        // Stopped on the way up, one click sends it down
+        let mut door = Door::new(config(), CLOSED);
+        door.command(secs(1.0), CLOSED, GpioCommand::Open);
+        door.poll(secs(1.0), BETWEEN);
+        door.command(secs(1.0), BETWEEN, GpioCommand::Stop);
+        let step = door.command(secs(1.0), BETWEEN, GpioCommand::Close);
+        assert_eq!(step.coupler, [CouplerAction::Click]);
+        assert_eq!(step.state.status, DoorStatus::MovingDown);
+        assert_eq!(step.state.setpoint, DoorSetpoint::Closed);
+
+        // Stopped on the way down, the next click would send it up
+        let mut door = Door::new(config(), OPEN);
+        door.command(secs(1.0), OPEN, GpioCommand::Close);
+        door.poll(secs(1.0), BETWEEN);
+        door.command(secs(1.0), BETWEEN, GpioCommand::Stop);
+        let step = door.command(secs(1.0), BETWEEN, GpioCommand::Close);
+        assert_eq!(step.coupler, RESUME_REVERSED);
+        assert_eq!(step.state.status, DoorStatus::MovingDown);
+
+        let step = door.poll(secs(1.0), CLOSED);
+        assert_eq!(step.state.status, DoorStatus::Closed);
+        assert_eq!(step.state.command.map(|command| command.phase), Some(CommandPhase::Completed));
+    }
+
+    #[test]
+    fn follows_the_limit_switches() {
+        let mut door = Door::new(config(), CLOSED);
+        let step = door.poll(secs(1.0), BETWEEN);
+        assert_eq!(step.state.status, DoorStatus::MovingUp);
+        let step = door.poll(secs(1.0), OPEN);
+        assert_eq!(step.state.status, DoorStatus::Open);
+        assert_eq!(step.state.position, Some(1.0));
+        let step = door.poll(secs(1.0), BETWEEN);
+        assert_eq!(step.state.status, DoorStatus::MovingDown);
+        let step = door.poll(secs(1.0), CLOSED);
+        assert_eq!(step.state.status, DoorStatus::Closed);
+        assert_eq!(step.state.position, Some(0.0));
+    }
+
+    #[test]
+    fn ignores_the_old_limit_during_the_cooldown() {
+        let mut door = Door::new(config(), CLOSED);
+        door.command(secs(1.0), CLOSED, GpioCommand::Open);
+        // The closed switch is still pressed right after the click
+        let step = door.poll(secs(0.1), CLOSED);
+        assert_eq!(step.state.status, DoorStatus::MovingUp);
+        let step = door.poll(secs(1.0), CLOSED);
+        assert_eq!(step.state.status, DoorStatus::Closed);
+    }
+
+    #[test]
+    fn tracks_a_command_to_the_limit_switch() {
+        let mut door = Door::new(config(), CLOSED);
+        door.command(secs(1.0), CLOSED, GpioCommand::Open);
+        // Still clicking
+        door.now += secs(0.1);
+        let step = door.controller.step(Some(CLOSED), None, true, door.now);
+        assert!(step.commands.is_empty());
+
+        let step = door.poll(secs(0.5), BETWEEN);
+        assert_eq!(step.commands, [CommandProgress { id: 1, command: GpioCommand::Open, phase: CommandPhase::Travelling }]);
+        let step = door.poll(secs(5.0), BETWEEN);
+        assert!(step.commands.is_empty());
+        let step = door.poll(secs(5.0), OPEN);
+        assert_eq!(step.commands[0].phase, CommandPhase::Completed);
+    }
+
+    #[test]
+    fn newer_command_supersedes_the_active_one() {
+        let mut door = Door::new(config(), CLOSED);
+        door.command(secs(1.0), CLOSED, GpioCommand::Open);
+        door.poll(secs(1.0), BETWEEN);
+        let step = door.command(secs(1.0), BETWEEN, GpioCommand::Close);
+        assert_eq!(
+            step.commands[0],
+            CommandProgress { id: 1, command: GpioCommand::Open, phase: CommandPhase::Superseded }
+        );
+        assert_eq!(step.state.command.map(|command| command.id), Some(2));
+    }
+
+    #[test]
+    fn stalled_travel_is_a_fault() {
+        let mut door = Door::new(config(), CLOSED);
+        door.command(secs(1.0), CLOSED, GpioCommand::Open);
+        let step = door.poll(secs(1.0), BETWEEN);
+        assert_eq!(step.state.status, DoorStatus::MovingUp);
+        let step = door.poll(secs(10.0), BETWEEN);
+        assert_eq!(step.state.status, DoorStatus::MovingUp);
+
+        let step = door.poll(secs(5.0), BETWEEN);
+        assert_eq!(step.state.status, DoorStatus::Fault { reason: FaultReason::Stalled });
+        assert_eq!(step.state.position, None);
+        assert_eq!(step.state.command.map(|command| command.phase), Some(CommandPhase::TimedOut));
+
+        // The fault sticks until a limit switch changes
+        let step = door.poll(secs(1.0), BETWEEN);
+        assert_eq!(step.state.status, DoorStatus::Fault { reason: FaultReason::Stalled });
+        let step = door.poll(secs(1.0), CLOSED);
+        assert_eq!(step.state.status, DoorStatus::Closed);
+    }
+
+    #[test]
+    fn stalled_travel_from_the_wall_button_is_a_fault() {
+        let mut door = Door::new(config(), CLOSED);
+        let step = door.poll(secs(1.0), BETWEEN);
+        assert_eq!(step.state.status, DoorStatus::MovingUp);
+        let step = door.poll(secs(13.0), BETWEEN);
+        assert_eq!(step.state.status, DoorStatus::MovingUp);
+        let step = door.poll(secs(1.0), BETWEEN);
+        assert_eq!(step.state.status, DoorStatus::Fault { reason: FaultReason::Stalled });
+        assert_eq!(step.state.close_retry, None);
+
+        // Ending up back where it started is fine, we don't know where it was meant to go
+        let mut door = Door::new(config(), OPEN);
+        door.poll(secs(1.0), BETWEEN);
+        door.poll(secs(3.0), OPEN);
+        let step = door.poll(secs(20.0), OPEN);
+        assert_eq!(step.state.status, DoorStatus::Open);
+    }
+
+    #[test]
+    fn close_ending_up_open_is_reversed() {
+        let mut door = Door::new(config(), OPEN);
+        door.command(secs(1.0), OPEN, GpioCommand::Close);
+        door.poll(secs(1.0), BETWEEN);
+        // The safety eye sent it back up
+        let step = door.poll(secs(3.0), OPEN);
+        assert_eq!(step.state.status, DoorStatus::Open);
+
+        let step = door.poll(secs(10.0), OPEN);
+        assert_eq!(step.state.status, DoorStatus::Fault { reason: FaultReason::Reversed });
+    }
+
+    #[test]
+    fn retries_a_failed_close() {
+        let mut door = Door::new(
+            ControllerConfig {
+                close_retry: Some(CloseRetryPolicy { attempts: 1, delay: Duration::from_secs(30) }),
+                ..config()
+            },
+            OPEN,
+        );
+        door.command(secs(1.0), OPEN, GpioCommand::Close);
+        door.poll(secs(1.0), BETWEEN);
+        door.poll(secs(3.0), OPEN);
+        let step = door.poll(secs(10.0), OPEN);
+        assert_eq!(step.state.status, DoorStatus::Fault { reason: FaultReason::Reversed });
+        assert_eq!(
+            step.state.close_retry,
+            Some(CloseRetry { attempt: 0, attempts: 1, phase: RetryPhase::Waiting })
+        );
+        assert_eq!(step.state.command.map(|command| command.phase), Some(CommandPhase::Retrying));
+
+        let step = door.poll(secs(29.0), OPEN);
+        assert!(step.coupler.is_empty());
+        let step = door.poll(secs(1.0), OPEN);
+        assert_eq!(step.coupler, [CouplerAction::Click]);
+        assert_eq!(step.state.status, DoorStatus::MovingDown);
+        assert_eq!(
+            step.state.close_retry,
+            Some(CloseRetry { attempt: 1, attempts: 1, phase: RetryPhase::Closing })
+        );
+
+        // Out of attempts after this one
+        door.poll(secs(1.0), BETWEEN);
+        let step = door.poll(secs(13.0), BETWEEN);
+        assert_eq!(step.state.status, DoorStatus::Fault { reason: FaultReason::Stalled });
+        assert_eq!(step.state.close_retry.map(|retry| retry.phase), Some(RetryPhase::GaveUp));
+        assert_eq!(step.state.command.map(|command| command.phase), Some(CommandPhase::TimedOut));
+    }
+
+    #[test]
+    fn both_limits_pressed_is_a_fault() {
+        let mut door = Door::new(config(), CLOSED);
+        let step = door.poll(secs(1.0), BOTH);
+        assert_eq!(step.state.status, DoorStatus::Fault { reason: FaultReason::LimitConflict });
+        assert_eq!(step.state.position, None);
+
+        let step = door.command(secs(1.0), BOTH, GpioCommand::Open);
+        assert!(step.coupler.is_empty());
+        assert_eq!(step.commands[0].phase, CommandPhase::Refused);
+
+        let step = door.controller.step(None, None, false, door.now + secs(1.0));
+        assert_eq!(step.state.status, DoorStatus::Fault { reason: FaultReason::PinReadError });
+    }
+
+    #[test]
+    fn unknown_door_only_takes_toggle_and_stop() {
+        let mut door = Door::new(config(), BETWEEN);
+        let step = door.command(secs(1.0), BETWEEN, GpioCommand::Close);
+        assert!(step.coupler.is_empty());
+        assert_eq!(step.commands[0].phase, CommandPhase::Refused);
+
+        let step = door.command(secs(1.0), BETWEEN, GpioCommand::Toggle);
+        assert_eq!(step.coupler, [CouplerAction::Click]);
+        assert_eq!(step.state.status, DoorStatus::Unknown);
+
+        // The first limit switch tells us where the door is again
+        let step = door.poll(secs(5.0), OPEN);
+        assert_eq!(step.state.status, DoorStatus::Open);
+    }
+
+    #[test]
+    fn learns_travel_times() {
+        let mut door = Door::new(config(), CLOSED);
+        door.command(secs(1.0), CLOSED, GpioCommand::Open);
+        door.poll(secs(1.0), BETWEEN);
+        let step = door.poll(secs(12.0), OPEN);
+        assert_eq!(step.state.calibration.up.samples, 1);
+        assert_eq!(step.state.calibration.up.mean_sec, 12.0);
+        assert_eq!(step.state.calibration.down.samples, 0);
+    }
+
+    #[test]
+    fn stops_at_a_position() {
+        let mut door = Door::new(config(), CLOSED);
+        let step = door.command(secs(1.0), CLOSED, GpioCommand::Position(0.5));
+        assert_eq!(step.coupler, [CouplerAction::Click]);
+        assert_eq!(step.state.setpoint, DoorSetpoint::Position(0.5));
+
+        let mut step = door.poll(secs(0.1), BETWEEN);
+        while step.coupler.is_empty() {
+            assert_eq!(step.state.status, DoorStatus::MovingUp);
+            step = door.poll(secs(0.1), BETWEEN);
+        }
+        assert_eq!(step.coupler, [CouplerAction::Click]);
+        assert_eq!(step.state.status, DoorStatus::Ajar);
+        let position = step.state.position.unwrap();
+        // Stopped a click's latency early, so it coasts the rest of the way
+        assert!((0.48..=0.5).contains(&position), "stopped at {}", position);
+        assert_eq!(step.state.command.map(|command| command.phase), Some(CommandPhase::Completed));
+
+        // Going back down from there resumes the other way
+        let step = door.command(secs(1.0), BETWEEN, GpioCommand::Position(0.2));
+        assert_eq!(step.coupler, [CouplerAction::Click]);
+        assert_eq!(step.state.status, DoorStatus::MovingDown);
+    }
+
+    #[test]
+    fn warns_before_closing() {
+        let mut door = Door::new(
+            ControllerConfig {
+                warning_lead: Some(Duration::from_secs(5)),
+                ..config()
+            },
+            OPEN,
+        );
+        let step = door.command(secs(1.0), OPEN, GpioCommand::Close);
+        assert!(step.coupler.is_empty());
+        assert!(step.state.warning.is_some());
+        assert_eq!(step.state.command.map(|command| command.phase), Some(CommandPhase::Warning));
+
+        let step = door.poll(secs(4.0), OPEN);
+        assert!(step.coupler.is_empty());
+        let step = door.poll(secs(1.0), OPEN);
+        assert_eq!(step.coupler, [CouplerAction::Click]);
+        assert_eq!(step.state.status, DoorStatus::MovingDown);
+        assert!(step.state.warning.is_none());
+
+        // Cancelling during the warning leaves the door alone
+        let mut door = Door::new(
+            ControllerConfig {
+                warning_lead: Some(Duration::from_secs(5)),
+                ..config()
+            },
+            OPEN,
+        );
+        door.command(secs(1.0), OPEN, GpioCommand::Close);
+        let progress = door.controller.cancel_warning();
+        assert_eq!(progress.map(|progress| progress.phase), Some(CommandPhase::Cancelled));
+        let step = door.poll(secs(10.0), OPEN);
+        assert!(step.coupler.is_empty());
+        assert_eq!(step.state.status, DoorStatus::Open);
+    }
+
+    // Stopped a few seconds into opening
+    fn stopped_part_way_up() -> Door {
+        let mut door = Door::new(config(), CLOSED);
+        door.command(secs(1.0), CLOSED, GpioCommand::Open);
+        door.poll(secs(1.0), BETWEEN);
+        door.poll(secs(3.0), BETWEEN);
+        door.command(secs(1.0), BETWEEN, GpioCommand::Stop);
+        door
+    }
+
+    #[test]
+    fn restores_a_door_left_between_the_limits() {
+        let door = stopped_part_way_up();
+        let saved = door.controller.saved_state();
+        assert_eq!(saved.status, SavedStatus::Ajar);
+        assert!(saved.position.is_some());
+
+        let mut restarted = door.restart(BETWEEN, saved);
+        let state = restarted.controller.state();
+        assert_eq!(state.status, DoorStatus::Ajar);
+        assert_eq!(state.setpoint, DoorSetpoint::Ajar);
+        assert_eq!(state.position, saved.position);
+        // It still knows which way the door went last, so a toggle sends it back down
+        let step = restarted.command(secs(1.0), BETWEEN, GpioCommand::Toggle);
+        assert_eq!(step.state.status, DoorStatus::MovingDown);
+    }
+
+    #[test]
+    fn limit_switches_win_over_the_saved_state() {
+        let door = stopped_part_way_up();
+        let saved = door.controller.saved_state();
+
+        let state = door.restart(CLOSED, saved).controller.state();
+        assert_eq!((state.status, state.setpoint, state.position), (DoorStatus::Closed, DoorSetpoint::Closed, Some(0.0)));
+        let state = door.restart(OPEN, saved).controller.state();
+        assert_eq!((state.status, state.setpoint, state.position), (DoorStatus::Open, DoorSetpoint::Open, Some(1.0)));
+        // The calibration is kept either way
+        assert_eq!(state.calibration, saved.calibration);
+
+        // Closed last time but neither switch is pressed now, so the door moved while we were down
+        let closed = Door::new(config(), CLOSED).controller.saved_state();
+        let state = door.restart(BETWEEN, closed).controller.state();
+        assert_eq!((state.status, state.position), (DoorStatus::Unknown, None));
+    }
+
+    #[test]
+    fn only_trusts_a_saved_position_of_a_door_standing_still() {
+        let mut door = Door::new(config(), CLOSED);
+        door.command(secs(1.0), CLOSED, GpioCommand::Open);
+        door.poll(secs(1.0), BETWEEN);
+        let saved = door.controller.saved_state();
+        assert_eq!((saved.status, saved.position), (SavedStatus::Moving, None));
+        let state = door.restart(BETWEEN, saved).controller.state();
+        assert_eq!(state.status, DoorStatus::Unknown);
+
+        let saved = SavedState {
+            position: None,
+            ..stopped_part_way_up().controller.saved_state()
+        };
+        let state = door.restart(BETWEEN, saved).controller.state();
+        assert_eq!(state.status, DoorStatus::Unknown);
+    }
+}
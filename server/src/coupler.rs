@@ -0,0 +1,166 @@
+use std::collections::VecDeque;
+use embedded_hal::digital::PinState;
+use crate::controller::CouplerAction;
+
+/// Turns coupler actions into one pin level per poll interval
+#[derive(Debug)]
+pub struct CouplerSequencer {
+    queue: VecDeque<PinState>,
+    active_state: PinState,
+    inactive_state: PinState,
+    active_intervals: u64,
+    rest_intervals: u64,
+}
+
+impl CouplerSequencer {
+    pub fn new(active_low: bool, active_intervals: u64, rest_intervals: u64) -> Self {
+        let (active_state, inactive_state) = if active_low {
+            (PinState::Low, PinState::High)
+        } else {
+            (PinState::High, PinState::Low)
+        };
+
+        Self {
+            queue: VecDeque::with_capacity(10),
+            active_state,
+            inactive_state,
+            active_intervals,
+            rest_intervals,
+        }
+    }
+
+    pub fn queue(&mut self, actions: &[CouplerAction]) {
+        for action in actions {
+            match action {
+                CouplerAction::Click => {
+                    for _ in 0..self.active_intervals {
+                        self.queue.push_back(self.active_state);
+                    }
+                    for _ in 0..self.active_intervals {
+                        self.queue.push_back(self.inactive_state);
+                    }
+                }
+                CouplerAction::Rest => {
+                    for _ in 0..self.rest_intervals {
+                        self.queue.push_back(self.inactive_state);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn is_busy(&self) -> bool {
+        !self.queue.is_empty()
+    }
+
+    /// Drop whatever is queued, e.g. to abort a click on shutdown
+    pub fn clear(&mut self) {
+        self.queue.clear();
+    }
+
+    pub fn inactive_state(&self) -> PinState {
+        self.inactive_state
+    }
+
+    /// Pin level for the current poll interval, if anything is queued
+    pub fn tick(&mut self) -> Option<PinState> {
+        self.queue.pop_front()
+    }
+}
+
+/// Flashes the warning output while a close is being warned about
+#[derive(Debug)]
+pub struct WarningSignal {
+    active_state: PinState,
+    inactive_state: PinState,
+    // Poll intervals per flash, on and off each. Steady if 0.
+    pulse_intervals: u64,
+    elapsed: u64,
+}
+
+impl WarningSignal {
+    pub fn new(active_low: bool, pulse_intervals: u64) -> Self {
+        let (active_state, inactive_state) = if active_low {
+            (PinState::Low, PinState::High)
+        } else {
+            (PinState::High, PinState::Low)
+        };
+
+        Self {
+            active_state,
+            inactive_state,
+            pulse_intervals,
+            elapsed: 0,
+        }
+    }
+
+    /// Pin level for the current poll interval
+    pub fn tick(&mut self, warning: bool) -> PinState {
+        if !warning {
+            self.elapsed = 0;
+            return self.inactive_state;
+        }
+
+        let on = self.pulse_intervals == 0 || (self.elapsed / self.pulse_intervals).is_multiple_of(2);
+        self.elapsed += 1;
+        if on { self.active_state } else { self.inactive_state }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_hal::digital::PinState::{High, Low};
+    use super::*;
+
+    fn drain(sequencer: &mut CouplerSequencer) -> Vec<PinState> {
+        std::iter::from_fn(|| sequencer.tick()).collect()
+    }
+
+    #[test]
+    fn click_then_rest_for_both_polarities() {
+        for (active_low, on, off) in [(false, High, Low), (true, Low, High)] {
+            let mut sequencer = CouplerSequencer::new(active_low, 2, 3);
+            assert_eq!(sequencer.inactive_state(), off);
+            assert!(!sequencer.is_busy());
+
+            sequencer.queue(&[CouplerAction::Click, CouplerAction::Rest, CouplerAction::Click]);
+            assert!(sequencer.is_busy());
+            assert_eq!(drain(&mut sequencer), [on, on, off, off, off, off, off, on, on, off, off]);
+            assert!(!sequencer.is_busy());
+            assert_eq!(sequencer.tick(), None);
+        }
+    }
+
+    #[test]
+    fn clear_drops_a_queued_click() {
+        let mut sequencer = CouplerSequencer::new(false, 2, 3);
+        sequencer.queue(&[CouplerAction::Click]);
+        assert_eq!(sequencer.tick(), Some(High));
+        sequencer.clear();
+        assert!(!sequencer.is_busy());
+        assert_eq!(sequencer.tick(), None);
+    }
+
+    #[test]
+    fn warning_flashes_for_both_polarities() {
+        for (active_low, on, off) in [(false, High, Low), (true, Low, High)] {
+            let mut signal = WarningSignal::new(active_low, 2);
+            assert_eq!(signal.tick(false), off);
+            let levels: Vec<_> = (0..6).map(|_| signal.tick(true)).collect();
+            assert_eq!(levels, [on, on, off, off, on, on]);
+
+            // Stopping the warning resets the flash, so the next one starts on
+            assert_eq!(signal.tick(false), off);
+            assert_eq!(signal.tick(true), on);
+        }
+    }
+
+    #[test]
+    fn warning_is_steady_without_a_pulse() {
+        for (active_low, on, off) in [(false, High, Low), (true, Low, High)] {
+            let mut signal = WarningSignal::new(active_low, 0);
+            assert!((0..5).all(|_| signal.tick(true) == on));
+            assert_eq!(signal.tick(false), off);
+        }
+    }
+}
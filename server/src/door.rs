@@ -0,0 +1,187 @@
+use std::{error::Error, path::PathBuf, sync::Arc, time::Duration};
+use tokio::sync::{mpsc, watch};
+use crate::{
+    auto_close::{self, AutoCloseHandle},
+    calibration::TravelCalibration,
+    commands::{CommandSender, CommandTracker},
+    config::{AutoCloseConfig, DoorConfig, InputFilterConfig},
+    controller::{CloseRetryPolicy, ControllerConfig, DoorSetpoint, DoorState, DoorStatus},
+    coupler::{CouplerSequencer, WarningSignal},
+    driver::GpioDriver,
+    gpio::{self, Wakeup},
+    history::HistoryRecorder,
+    input_filter::{InputDiagnostics, InputFilter},
+    metrics::DoorMetrics,
+    state_file::SavedState,
+};
+
+/// Everything the rest of the server needs to talk to one door
+#[derive(Debug, Clone)]
+pub struct DoorHandle {
+    pub id: String,
+    pub state: watch::Sender<DoorState>,
+    pub commands: CommandSender,
+    pub inputs: watch::Receiver<InputDiagnostics>,
+    pub auto_close: Option<AutoCloseHandle>,
+}
+
+/// All doors, the default door first
+#[derive(Debug, Clone)]
+pub struct Doors {
+    doors: Arc<Vec<DoorHandle>>,
+}
+
+impl Doors {
+    pub fn new(doors: Vec<DoorHandle>) -> Self {
+        assert!(!doors.is_empty(), "There is always a default door");
+        Self { doors: Arc::new(doors) }
+    }
+
+    pub fn get(&self, id: &str) -> Option<&DoorHandle> {
+        self.doors.iter().find(|door| door.id == id)
+    }
+
+    pub fn default_door(&self) -> &DoorHandle {
+        &self.doors[0]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &DoorHandle> {
+        self.doors.iter()
+    }
+}
+
+/// Set up the pins for a door and start its GPIO loop and auto-close task
+pub fn start(
+    id: &str,
+    config: &DoorConfig,
+    auto_close: Option<&AutoCloseConfig>,
+    metrics: DoorMetrics,
+    history: Option<HistoryRecorder>,
+) -> Result<DoorHandle, Box<dyn Error>> {
+    // Convert config durations
+    let poll_interval = Duration::from_millis(config.poll_interval_ms);
+    let expected_shut_time = Duration::from_secs(config.expected_shut_time_sec);
+    let shut_time_buffer = Duration::from_secs(config.shut_time_buffer_sec);
+    let limit_cooldown = Duration::from_millis(config.limit_cooldown_ms);
+
+    // Initialize GPIO components with config values
+    let wakeup = Wakeup::default();
+    let pins = gpio::create_pins(config, config.edge_interrupts.unwrap_or(true).then(|| wakeup.clone()))?;
+
+    // Create communication channels
+    let (command_tx, command_rx) = mpsc::unbounded_channel();
+    let (state_tx, _) = watch::channel(DoorState{
+        status: DoorStatus::Unknown,
+        setpoint: DoorSetpoint::Ajar,
+        position: None,
+        command: None,
+        close_retry: None,
+        warning: None,
+        calibration: TravelCalibration::default(),
+    });
+    let (inputs_tx, inputs_rx) = watch::channel(InputDiagnostics::default());
+    let commands = CommandSender::new(CommandTracker::new(history.clone()), command_tx, wakeup.clone());
+
+    let calibration_file = config.calibration_file.as_ref().map(PathBuf::from);
+    let state_file = config.state_file.as_ref().map(PathBuf::from);
+    let saved_state = match &state_file {
+        Some(path) => SavedState::load(path)?,
+        None => None,
+    };
+    let calibration = match (&saved_state, &calibration_file) {
+        (Some(saved_state), _) => saved_state.calibration,
+        (None, Some(path)) => TravelCalibration::load(path)?,
+        (None, None) => TravelCalibration::default(),
+    };
+
+    let idle_poll_interval = pins
+        .interrupts
+        .then(|| Duration::from_millis(config.idle_poll_interval_ms.unwrap_or(1000)));
+
+    GpioDriver {
+        pins,
+        controller_config: ControllerConfig {
+            expected_shut_time,
+            limit_cooldown,
+            travel_timeout: expected_shut_time + shut_time_buffer,
+            close_retry: config.close_retry.as_ref().map(|retry| CloseRetryPolicy {
+                attempts: retry.attempts,
+                delay: Duration::from_secs(retry.delay_sec),
+            }),
+            // The opener reacts once the button has been held for the whole click
+            coupler_latency: poll_interval * config.coupler_active_intervals as u32,
+            warning_lead: config.warning.as_ref().map(|warning| Duration::from_secs(warning.lead_time_sec)),
+        },
+        calibration,
+        calibration_file,
+        saved_state,
+        state_file,
+        sequencer: CouplerSequencer::new(
+            config.coupler_active_low,
+            config.coupler_active_intervals,
+            config.coupler_rest_intervals,
+        ),
+        warning_signal: match &config.warning {
+            Some(warning) => WarningSignal::new(
+                warning.active_low,
+                warning.pulse_ms.map_or(0, |pulse_ms| (pulse_ms / config.poll_interval_ms).max(1)),
+            ),
+            None => WarningSignal::new(false, 0),
+        },
+        state_tx: state_tx.clone(),
+        command_rx,
+        commands: commands.tracker().clone(),
+        poll_interval,
+        idle_poll_interval,
+        wakeup,
+        close_limit_filter: input_filter(config.close_limit_filter.as_ref()),
+        open_limit_filter: input_filter(config.open_limit_filter.as_ref()),
+        inputs_tx,
+        metrics,
+        history,
+    }
+    .spawn();
+
+    Ok(DoorHandle {
+        id: id.to_string(),
+        auto_close: auto_close.map(|auto_close| auto_close::spawn(auto_close, state_tx.subscribe(), commands.clone())),
+        state: state_tx,
+        commands,
+        inputs: inputs_rx,
+    })
+}
+
+#[cfg(test)]
+impl DoorHandle {
+    /// A closed door without a GPIO loop, and the receiving end of the commands sent to it
+    pub fn detached(id: &str) -> (Self, mpsc::UnboundedReceiver<crate::commands::LoopMessage>) {
+        let (command_tx, command_rx) = mpsc::unbounded_channel();
+        let (state_tx, _) = watch::channel(DoorState {
+            status: DoorStatus::Closed,
+            setpoint: DoorSetpoint::Closed,
+            position: Some(0.0),
+            command: None,
+            close_retry: None,
+            warning: None,
+            calibration: TravelCalibration::default(),
+        });
+        let door = Self {
+            id: id.to_string(),
+            state: state_tx,
+            commands: CommandSender::new(CommandTracker::new(None), command_tx, Wakeup::default()),
+            inputs: watch::channel(InputDiagnostics::default()).1,
+            auto_close: None,
+        };
+        (door, command_rx)
+    }
+}
+
+fn input_filter(config: Option<&InputFilterConfig>) -> InputFilter {
+    match config {
+        Some(config) => InputFilter::new(
+            config.samples.unwrap_or(1),
+            Duration::from_millis(config.stable_ms.unwrap_or(0)),
+        ),
+        None => InputFilter::passthrough(),
+    }
+}
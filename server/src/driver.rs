@@ -0,0 +1,250 @@
+use std::{
+    path::PathBuf,
+    thread,
+    time::{Duration, Instant},
+};
+use embedded_hal::digital::{InputPin, OutputPin};
+use tokio::sync::{mpsc, watch};
+use crate::{
+    calibration::TravelCalibration,
+    commands::{CommandQueue, CommandTracker, LoopMessage},
+    controller::{ControllerConfig, CouplerAction, DoorController, DoorState, DoorStatus, LimitSwitches},
+    coupler::{CouplerSequencer, WarningSignal},
+    gpio::{DoorPins, Wakeup},
+    history::HistoryRecorder,
+    metrics::DoorMetrics,
+    state_file::SavedState,
+    input_filter::{InputDiagnostics, InputFilter, InputLevels},
+};
+
+/// GPIO monitoring and control thread. The door logic lives in DoorController,
+/// this just feeds it pin readings and commands and drives the coupler.
+pub struct GpioDriver<I1, I2, O> {
+    pub pins: DoorPins<I1, I2, O>,
+    pub controller_config: ControllerConfig,
+    pub calibration: TravelCalibration,
+    /// Where to save the calibration whenever it changes
+    pub calibration_file: Option<PathBuf>,
+    /// State left behind by the previous run, and where to keep it up to date
+    pub saved_state: Option<SavedState>,
+    pub state_file: Option<PathBuf>,
+    pub sequencer: CouplerSequencer,
+    pub warning_signal: WarningSignal,
+    pub state_tx: watch::Sender<DoorState>,
+    pub command_rx: mpsc::UnboundedReceiver<LoopMessage>,
+    pub commands: CommandTracker,
+    pub poll_interval: Duration,
+    /// Poll this slowly while the door is idle, if the inputs have edge interrupts
+    pub idle_poll_interval: Option<Duration>,
+    pub wakeup: Wakeup,
+    pub close_limit_filter: InputFilter,
+    pub open_limit_filter: InputFilter,
+    pub inputs_tx: watch::Sender<InputDiagnostics>,
+    pub metrics: DoorMetrics,
+    /// Where to record status and setpoint changes
+    pub history: Option<HistoryRecorder>,
+}
+
+impl<I1, I2, O> GpioDriver<I1, I2, O>
+where
+    I1: InputPin + Send + 'static,
+    I2: InputPin + Send + 'static,
+    O: OutputPin + Send + 'static,
+{
+    pub fn spawn(self) {
+        thread::spawn(move || self.run());
+    }
+
+    fn run(self) {
+        let GpioDriver {
+            pins,
+            controller_config,
+            calibration,
+            calibration_file,
+            saved_state,
+            state_file,
+            mut sequencer,
+            mut warning_signal,
+            state_tx,
+            mut command_rx,
+            commands,
+            poll_interval,
+            idle_poll_interval,
+            wakeup,
+            close_limit_filter,
+            open_limit_filter,
+            inputs_tx,
+            metrics,
+            history,
+        } = self;
+        let DoorPins { close_limit, open_limit, mut coupler, mut warning, .. } = pins;
+        // Nothing is pressed until there is a command
+        let _ = coupler.set_state(sequencer.inactive_state());
+        if let Some(warning) = &mut warning {
+            let _ = warning.set_state(warning_signal.tick(false));
+        }
+        let mut inputs = LimitInputs {
+            close_limit,
+            open_limit,
+            close_limit_filter,
+            open_limit_filter,
+            inputs_tx,
+            metrics: metrics.clone(),
+        };
+
+        let mut controller = DoorController::new(controller_config, inputs.read(), Instant::now())
+            .with_calibration(calibration);
+        if let Some(saved_state) = saved_state {
+            controller = controller.restore(saved_state);
+        }
+        let mut last_state = controller.state();
+        let mut last_saved = controller.saved_state();
+        state_tx.send_replace(last_state);
+        if let Some(history) = &history {
+            history.state(&last_state);
+        }
+
+        let mut queue = CommandQueue::default();
+
+        loop {
+            let switches = inputs.read();
+            while let Ok(message) = command_rx.try_recv() {
+                match message {
+                    LoopMessage::Command(command) => {
+                        for progress in queue.push(command) {
+                            commands.update(progress);
+                        }
+                    }
+                    LoopMessage::CancelWarning => {
+                        if let Some(progress) = controller.cancel_warning() {
+                            commands.update(progress);
+                        }
+                    }
+                    LoopMessage::Shutdown(done_tx) => {
+                        // Cut a click short rather than leave the relay pressed.
+                        // At worst the opener misses the click.
+                        sequencer.clear();
+                        let _ = coupler.set_state(sequencer.inactive_state());
+                        if let Some(warning) = &mut warning {
+                            let _ = warning.set_state(warning_signal.tick(false));
+                        }
+                        let _ = done_tx.send(());
+                        return;
+                    }
+                }
+            }
+            // Only start the next command once the clicks for the previous one are done
+            let command = if sequencer.is_busy() { None } else { queue.pop() };
+            let step = controller.step(switches, command, sequencer.is_busy(), Instant::now());
+
+            sequencer.queue(&step.coupler);
+            let clicks = step.coupler.iter().filter(|action| **action == CouplerAction::Click).count();
+            if clicks > 0 {
+                // Retries and stopping at a position click for the command that is already active
+                metrics.coupler_clicks(step.state.command.map(|command| command.command), clicks as u64);
+            }
+            for progress in &step.commands {
+                commands.update(*progress);
+            }
+
+            // Toggle coupler if requested
+            if let Some(pin_state) = sequencer.tick() {
+                let _ = coupler.set_state(pin_state);
+            }
+            if let Some(warning) = &mut warning {
+                let _ = warning.set_state(warning_signal.tick(step.state.warning.is_some()));
+            }
+
+            if step.state.calibration != last_state.calibration
+                && let Some(path) = &calibration_file
+                && let Err(err) = step.state.calibration.save(path)
+            {
+                eprintln!("Failed to save calibration to {}: {}", path.display(), err);
+            }
+
+            let saved = controller.saved_state();
+            if saved != last_saved {
+                if let Some(path) = &state_file
+                    && let Err(err) = saved.save(path)
+                {
+                    eprintln!("Failed to save state to {}: {}", path.display(), err);
+                }
+                last_saved = saved;
+            }
+
+            if step.state != last_state {
+                match (last_state.status, step.state.status) {
+                    (DoorStatus::MovingUp, DoorStatus::Open) => metrics.cycle("open"),
+                    (DoorStatus::MovingDown, DoorStatus::Closed) => metrics.cycle("close"),
+                    _ => {}
+                }
+                // Position updates while moving would drown out everything else
+                if let Some(history) = &history
+                    && (step.state.status != last_state.status || step.state.setpoint != last_state.setpoint)
+                {
+                    history.state(&step.state);
+                }
+                state_tx.send_replace(step.state);
+                last_state = step.state;
+            }
+
+            // Keep polling at full speed while anything is going on. Otherwise
+            // an edge or a command wakes us up.
+            let idle = !sequencer.is_busy()
+                && last_state.is_settled()
+                && !inputs.is_settling();
+            match idle_poll_interval {
+                Some(idle_poll_interval) if idle => wakeup.wait_timeout(idle_poll_interval),
+                _ => wakeup.wait_timeout(poll_interval),
+            }
+        }
+    }
+}
+
+// The limit switch pins and their glitch filters
+struct LimitInputs<I1, I2> {
+    close_limit: I1,
+    open_limit: I2,
+    close_limit_filter: InputFilter,
+    open_limit_filter: InputFilter,
+    inputs_tx: watch::Sender<InputDiagnostics>,
+    metrics: DoorMetrics,
+}
+
+impl<I1: InputPin, I2: InputPin> LimitInputs<I1, I2> {
+    // The controller only ever sees filtered levels
+    fn read(&mut self) -> Option<LimitSwitches> {
+        let now = Instant::now();
+        let closed = self.close_limit.is_low().ok();
+        let open = self.open_limit.is_low().ok();
+        if closed.is_none() {
+            self.metrics.pin_read_error("close_limit");
+        }
+        if open.is_none() {
+            self.metrics.pin_read_error("open_limit");
+        }
+        let switches = match (closed, open) {
+            (Some(closed), Some(open)) => Some(LimitSwitches {
+                closed: self.close_limit_filter.update(closed, now),
+                open: self.open_limit_filter.update(open, now),
+            }),
+            _ => None,
+        };
+
+        let inputs = InputDiagnostics {
+            close_limit: InputLevels::new(closed, &self.close_limit_filter),
+            open_limit: InputLevels::new(open, &self.open_limit_filter),
+        };
+        self.inputs_tx.send_if_modified(|current| {
+            let modified = *current != inputs;
+            *current = inputs;
+            modified
+        });
+
+        switches
+    }
+
+    fn is_settling(&self) -> bool {
+        self.close_limit_filter.is_settling() || self.open_limit_filter.is_settling()
+    }
+}
@@ -0,0 +1,103 @@
+use axum::{
+    extract::rejection::{JsonRejection, PathRejection, QueryRejection},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use utoipa::ToSchema;
+use crate::commands::GpioLoopStopped;
+
+/// Machine readable reason an API request failed
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    MissingAuthorization,
+    InvalidApiKey,
+    /// The body, query or path could not be parsed or is out of range
+    InvalidRequest,
+    UnknownRoute,
+    MethodNotAllowed,
+    UnknownDoor,
+    UnknownCommand,
+    UnknownSchedule,
+    /// The door is in a state where the command can't be carried out safely
+    CommandRefused,
+    /// There is no close in its warning to cancel
+    NoWarning,
+    AutoCloseDisabled,
+    HistoryDisabled,
+    UnknownNotifier,
+    GpioLoopStopped,
+    /// The history database could not be read
+    HistoryUnavailable,
+    /// A test notification didn't go through
+    NotifierFailed,
+}
+
+impl ErrorCode {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ErrorCode::MissingAuthorization | ErrorCode::InvalidApiKey => StatusCode::UNAUTHORIZED,
+            ErrorCode::InvalidRequest => StatusCode::BAD_REQUEST,
+            ErrorCode::UnknownRoute
+            | ErrorCode::UnknownDoor
+            | ErrorCode::UnknownCommand
+            | ErrorCode::UnknownSchedule
+            | ErrorCode::AutoCloseDisabled
+            | ErrorCode::HistoryDisabled
+            | ErrorCode::UnknownNotifier => StatusCode::NOT_FOUND,
+            ErrorCode::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ErrorCode::CommandRefused | ErrorCode::NoWarning => StatusCode::CONFLICT,
+            ErrorCode::GpioLoopStopped | ErrorCode::HistoryUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::NotifierFailed => StatusCode::BAD_GATEWAY,
+        }
+    }
+}
+
+/// Error body every API route answers with: `{"code": ..., "message": ...}`
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.code.status(), Json(self)).into_response()
+    }
+}
+
+// Let extractors wrapped in `WithRejection` fail with the same body as everything else
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        ApiError::new(ErrorCode::InvalidRequest, rejection.body_text())
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        ApiError::new(ErrorCode::InvalidRequest, rejection.body_text())
+    }
+}
+
+impl From<PathRejection> for ApiError {
+    fn from(rejection: PathRejection) -> Self {
+        ApiError::new(ErrorCode::InvalidRequest, rejection.body_text())
+    }
+}
+
+impl From<GpioLoopStopped> for ApiError {
+    fn from(_: GpioLoopStopped) -> Self {
+        ApiError::new(ErrorCode::GpioLoopStopped, "GPIO loop is not running")
+    }
+}
@@ -2,6 +2,8 @@ use embedded_hal::digital::{InputPin, OutputPin, ErrorType, Error};
 use std::sync::{Arc, atomic::{AtomicBool, Ordering}, Mutex};
 use std::thread;
 use std::time::Duration;
+use crate::config::DoorConfig;
+use super::{DoorPins, Wakeup};
 
 #[derive(Clone)]
 pub struct MockInputPin {
@@ -50,9 +52,9 @@ impl InputPin for MockInputPin {
 }
 
 impl MockOutputPin {
-    pub fn new() -> Self {
+    pub fn new(initial_state: bool) -> Self {
         Self {
-            state: Arc::new(AtomicBool::new(false)),
+            state: Arc::new(AtomicBool::new(initial_state)),
         }
     }
 
@@ -119,11 +121,10 @@ impl DoorSimulation {
             
             let new_vel = if vel == 0.0 {
                 // Start moving with the calculated speed
-                if pos <= 0.0 { self.door_speed } // Moving up from closed
-                else if pos >= 1.0 { -self.door_speed } // Moving down from open
-                else if last_dir > 0.0 { -self.door_speed } // Last moved up, now move down
-                else if last_dir < 0.0 { self.door_speed } // Last moved down, now move up
-                else { self.door_speed } // Default direction if no history
+                let moving_down = if pos <= 0.0 { false } // Moving up from closed
+                else if pos >= 1.0 { true } // Moving down from open
+                else { last_dir > 0.0 }; // Reverse the last direction, moving up if no history
+                if moving_down { -self.door_speed } else { self.door_speed }
             } else {
                 0.0 // Stop moving
             };
@@ -162,14 +163,25 @@ impl DoorSimulation {
 }
 
 #[cfg(not(feature = "raspberry_pi"))]
-pub fn create_pins(_close_pin: u8, _open_pin: u8, _coupler_pin: u8, poll_interval: Duration, expected_shut_time: Duration) -> Result<(MockInputPin, MockInputPin, MockOutputPin), Box<dyn std::error::Error>> {
+pub fn create_pins(config: &DoorConfig, _interrupts: Option<Wakeup>) -> Result<DoorPins<MockInputPin, MockInputPin, MockOutputPin>, Box<dyn std::error::Error>> {
+    let poll_interval = Duration::from_millis(config.poll_interval_ms);
+    let expected_shut_time = Duration::from_secs(config.expected_shut_time_sec);
+    println!(
+        "Simulating the door on limit pins {} and {}, coupler pin {}, warning pin {:?}",
+        config.close_limit_pin,
+        config.open_limit_pin,
+        config.coupler_pin,
+        config.warning.as_ref().map(|warning| warning.pin),
+    );
     let simulation = DoorSimulation::new(expected_shut_time);
     // Initialize pins with correct states for a closed door:
     // - Close limit switch is pressed (LOW) when door is closed
     // - Open limit switch is not pressed (HIGH) when door is closed
     let close_pin = MockInputPin::new(false);  // LOW = pressed = door is closed
     let open_pin = MockInputPin::new(true);    // HIGH = not pressed
-    let coupler = MockOutputPin::new();
+    // Outputs start out at their inactive level, like on the Pi
+    let coupler = MockOutputPin::new(config.coupler_active_low);
+    let coupler_active_low = config.coupler_active_low;
 
     // Create thread-safe references
     let sim = simulation.clone();
@@ -180,8 +192,9 @@ pub fn create_pins(_close_pin: u8, _open_pin: u8, _coupler_pin: u8, poll_interva
     // Spawn simulation thread
     thread::spawn(move || {
         loop {
-            let coupler_state = coupler_ref.is_set_high();
-            sim.update(coupler_state);
+            // The button is pressed while the coupler is at its configured active level
+            let coupler_pressed = coupler_ref.is_set_high() != coupler_active_low;
+            sim.update(coupler_pressed);
             
             let pos = sim.get_position();
 
@@ -196,5 +209,12 @@ pub fn create_pins(_close_pin: u8, _open_pin: u8, _coupler_pin: u8, poll_interva
         }
     });
 
-    Ok((close_pin, open_pin, coupler))
+    Ok(DoorPins {
+        close_limit: close_pin,
+        open_limit: open_pin,
+        coupler,
+        warning: config.warning.as_ref().map(|warning| MockOutputPin::new(warning.active_low)),
+        // The simulation only changes the pins once per poll anyway
+        interrupts: false,
+    })
 }
@@ -6,4 +6,38 @@ pub use raspi_gpio::create_pins;
 #[cfg(not(feature = "raspberry_pi"))]
 pub mod mock_gpio;
 #[cfg(not(feature = "raspberry_pi"))]
-pub use mock_gpio::create_pins;
\ No newline at end of file
+pub use mock_gpio::create_pins;
+
+use std::{sync::{Arc, Condvar, Mutex}, time::Duration};
+
+// The pins the door controller is wired to
+pub struct DoorPins<I1, I2, O> {
+    pub close_limit: I1,
+    pub open_limit: I2,
+    pub coupler: O,
+    pub warning: Option<O>,
+    // The limit switch inputs wake the GPIO loop on every edge
+    pub interrupts: bool,
+}
+
+/// Lets the GPIO loop sleep until an input edge or a command comes in
+#[derive(Debug, Clone, Default)]
+pub struct Wakeup {
+    inner: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl Wakeup {
+    pub fn notify(&self) {
+        let (woken, condvar) = &*self.inner;
+        *woken.lock().unwrap() = true;
+        condvar.notify_one();
+    }
+
+    /// Sleep until notified or `timeout` has passed
+    pub fn wait_timeout(&self, timeout: Duration) {
+        let (woken, condvar) = &*self.inner;
+        let guard = woken.lock().unwrap();
+        let (mut guard, _) = condvar.wait_timeout_while(guard, timeout, |woken| !*woken).unwrap();
+        *guard = false;
+    }
+}
@@ -1,11 +1,45 @@
-use rppal::gpio::Gpio as RpGpio;
-use embedded_hal::digital::{InputPin, OutputPin};
-use std::time::Duration;
+use rppal::gpio::{Gpio as RpGpio, InputPin as RpInputPin, OutputPin as RpOutputPin, Trigger};
+use crate::config::DoorConfig;
+use super::{DoorPins, Wakeup};
 
-pub fn create_pins(close_pin: u8, open_pin: u8, coupler_pin: u8, _poll_interval: Duration, _expected_shut_time: Duration) -> Result<(impl InputPin, impl InputPin, impl OutputPin), Box<dyn std::error::Error>> {
+pub fn create_pins(config: &DoorConfig, interrupts: Option<Wakeup>) -> Result<DoorPins<RpInputPin, RpInputPin, RpOutputPin>, Box<dyn std::error::Error>> {
     let gpio = RpGpio::new()?;
-    let close_limit = gpio.get(close_pin)?.into_input();
-    let open_limit = gpio.get(open_pin)?.into_input();
-    let coupler = gpio.get(coupler_pin)?.into_output();
-    Ok((close_limit, open_limit, coupler))
+    let mut close_limit = gpio.get(config.close_limit_pin)?.into_input();
+    let mut open_limit = gpio.get(config.open_limit_pin)?.into_input();
+    // Outputs start out at their inactive level so nothing is pressed from boot until the first command
+    let coupler = inactive_output(&gpio, config.coupler_pin, config.coupler_active_low)?;
+    let warning = match &config.warning {
+        Some(warning) => Some(inactive_output(&gpio, warning.pin, warning.active_low)?),
+        None => None,
+    };
+
+    // Fall back to polling if the interrupts can't be set up
+    let interrupts = match interrupts {
+        Some(wakeup) => match wake_on_edges(&mut close_limit, &wakeup).and_then(|_| wake_on_edges(&mut open_limit, &wakeup)) {
+            Ok(()) => true,
+            Err(err) => {
+                eprintln!("Failed to set up limit switch interrupts, polling instead: {}", err);
+                let _ = close_limit.clear_async_interrupt();
+                let _ = open_limit.clear_async_interrupt();
+                false
+            }
+        },
+        None => false,
+    };
+
+    Ok(DoorPins { close_limit, open_limit, coupler, warning, interrupts })
+}
+
+fn inactive_output(gpio: &RpGpio, pin: u8, active_low: bool) -> rppal::gpio::Result<RpOutputPin> {
+    let pin = gpio.get(pin)?;
+    let mut output = if active_low { pin.into_output_high() } else { pin.into_output_low() };
+    // Keep the pin at that level if the server exits, rather than going back to an input
+    output.set_reset_on_drop(false);
+    Ok(output)
+}
+
+// The pin is still read through embedded-hal, the interrupt only wakes the GPIO loop
+fn wake_on_edges(pin: &mut RpInputPin, wakeup: &Wakeup) -> rppal::gpio::Result<()> {
+    let wakeup = wakeup.clone();
+    pin.set_async_interrupt(Trigger::Both, None, move |_| wakeup.notify())
 }
@@ -0,0 +1,464 @@
+// Door state changes and commands kept in SQLite, for GET /history
+use std::{
+    collections::HashMap,
+    error::Error,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+use chrono::{DateTime, FixedOffset, Local, TimeDelta, TimeZone};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use utoipa::ToSchema;
+use crate::{
+    commands::CommandSource,
+    config::HistoryConfig,
+    controller::{CloseRetry, CommandId, CommandPhase, DoorSetpoint, DoorState, DoorStatus, GpioCommand},
+};
+
+// How often to drop events past the retention period
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS events (
+        id INTEGER PRIMARY KEY,
+        -- Milliseconds since the Unix epoch
+        time INTEGER NOT NULL,
+        door TEXT NOT NULL,
+        type TEXT NOT NULL,
+        -- The event as JSON
+        data TEXT NOT NULL,
+        -- Final phase of a command, NULL until it finishes
+        outcome TEXT
+    );
+    CREATE INDEX IF NOT EXISTS events_time ON events (time);
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum EventType {
+    State,
+    Command,
+}
+
+impl EventType {
+    // The name the API uses, which is also what goes in the type column
+    fn name(self) -> String {
+        let value = serde_json::to_value(self).expect("Event types always serialize");
+        value.as_str().unwrap_or_default().to_string()
+    }
+}
+
+// What goes into the data column, flattened into the event the API returns
+#[derive(Serialize)]
+struct StateEvent {
+    #[serde(flatten)]
+    status: DoorStatus,
+    #[serde(flatten)]
+    setpoint: DoorSetpoint,
+    position: Option<f64>,
+    close_retry: Option<CloseRetry>,
+}
+
+#[derive(Serialize)]
+struct CommandEvent {
+    command_id: CommandId,
+    #[serde(flatten)]
+    command: GpioCommand,
+    source: CommandSource,
+}
+
+enum Message {
+    Record {
+        time: DateTime<Local>,
+        door: String,
+        event_type: EventType,
+        data: String,
+        command_id: Option<CommandId>,
+        outcome: Option<String>,
+    },
+    Finished {
+        door: String,
+        command_id: CommandId,
+        outcome: String,
+    },
+}
+
+/// Which events to return, newest first
+#[derive(Debug, Clone)]
+pub struct HistoryFilter {
+    pub from: Option<DateTime<FixedOffset>>,
+    pub to: Option<DateTime<FixedOffset>>,
+    pub event_type: Option<EventType>,
+    pub door: Option<String>,
+    pub limit: u32,
+    /// Only events with a lower ID, for paging back through older events
+    pub before: Option<i64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct HistoryPage {
+    /// Every event has `id`, `time`, `door` and `type`. State events add the status, setpoint,
+    /// position and close retry like `/status`. Command events add `command_id`, the command,
+    /// its `source` and the `outcome`, which is null while the command is still going.
+    pub events: Vec<Value>,
+    /// Pass as `before` to get the next page, null on the last one
+    pub next_before: Option<i64>,
+}
+
+/// The event store. Writes go through a thread of their own so the GPIO loops never wait on the disk.
+#[derive(Debug, Clone)]
+pub struct History {
+    reader: Arc<Mutex<Connection>>,
+    tx: mpsc::Sender<Message>,
+}
+
+impl History {
+    pub fn open(config: &HistoryConfig) -> Result<Self, Box<dyn Error>> {
+        let path = config.path.as_deref().unwrap_or("history.db");
+        let writer = open_connection(path)?;
+        writer.execute_batch(SCHEMA)?;
+        let reader = open_connection(path)?;
+
+        let retention = TimeDelta::days(config.retention_days.unwrap_or(90).into());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || write_events(writer, rx, retention));
+
+        Ok(Self {
+            reader: Arc::new(Mutex::new(reader)),
+            tx,
+        })
+    }
+
+    /// Records events for one door
+    pub fn recorder(&self, door: &str) -> HistoryRecorder {
+        HistoryRecorder {
+            door: door.to_string(),
+            tx: self.tx.clone(),
+        }
+    }
+
+    pub async fn query(&self, filter: HistoryFilter) -> Result<HistoryPage, rusqlite::Error> {
+        let reader = self.reader.clone();
+        tokio::task::spawn_blocking(move || query_events(&reader.lock().unwrap(), &filter))
+            .await
+            .expect("History query doesn't panic")
+    }
+}
+
+/// Hands the events of one door to the writer thread
+#[derive(Debug, Clone)]
+pub struct HistoryRecorder {
+    door: String,
+    tx: mpsc::Sender<Message>,
+}
+
+impl HistoryRecorder {
+    pub fn state(&self, state: &DoorState) {
+        let event = StateEvent {
+            status: state.status,
+            setpoint: state.setpoint,
+            position: state.position,
+            close_retry: state.close_retry,
+        };
+        self.record(EventType::State, &event, None, None);
+    }
+
+    /// A command was received, its outcome follows with `finished`
+    pub fn command(&self, command_id: CommandId, command: GpioCommand, source: CommandSource) {
+        let event = CommandEvent {
+            command_id,
+            command,
+            source,
+        };
+        self.record(EventType::Command, &event, Some(command_id), None);
+    }
+
+    /// A command was refused before it reached the GPIO loop
+    pub fn refused(&self, command_id: CommandId, command: GpioCommand, source: CommandSource) {
+        let event = CommandEvent {
+            command_id,
+            command,
+            source,
+        };
+        // Already finished, so there is no outcome to fill in later
+        self.record(EventType::Command, &event, None, Some(phase_name(CommandPhase::Refused)));
+    }
+
+    pub fn finished(&self, command_id: CommandId, phase: CommandPhase) {
+        let _ = self.tx.send(Message::Finished {
+            door: self.door.clone(),
+            command_id,
+            outcome: phase_name(phase),
+        });
+    }
+
+    fn record(&self, event_type: EventType, event: &impl Serialize, command_id: Option<CommandId>, outcome: Option<String>) {
+        // The writer thread only stops if the database is gone, and then there is nothing to do
+        let _ = self.tx.send(Message::Record {
+            time: Local::now(),
+            door: self.door.clone(),
+            event_type,
+            data: serde_json::to_string(event).expect("Events always serialize"),
+            command_id,
+            outcome,
+        });
+    }
+}
+
+fn open_connection(path: &str) -> Result<Connection, rusqlite::Error> {
+    let connection = Connection::open(path)?;
+    // Lets queries read while the writer thread writes
+    connection.pragma_update(None, "journal_mode", "WAL")?;
+    connection.busy_timeout(Duration::from_secs(5))?;
+    Ok(connection)
+}
+
+fn write_events(connection: Connection, rx: mpsc::Receiver<Message>, retention: TimeDelta) {
+    // Row of every command that hasn't finished yet. IDs start over with every run of the server.
+    let mut pending: HashMap<(String, CommandId), i64> = HashMap::new();
+    let mut next_prune = Instant::now();
+    loop {
+        if Instant::now() >= next_prune {
+            prune(&connection, retention);
+            next_prune = Instant::now() + PRUNE_INTERVAL;
+        }
+        let message = match rx.recv_timeout(next_prune.saturating_duration_since(Instant::now())) {
+            Ok(message) => message,
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            Err(mpsc::RecvTimeoutError::Disconnected) => return,
+        };
+        if let Err(err) = write(&connection, &mut pending, message) {
+            eprintln!("Failed to write to the history: {}", err);
+        }
+    }
+}
+
+fn write(connection: &Connection, pending: &mut HashMap<(String, CommandId), i64>, message: Message) -> Result<(), rusqlite::Error> {
+    match message {
+        Message::Record { time, door, event_type, data, command_id, outcome } => {
+            connection.execute(
+                "INSERT INTO events (time, door, type, data, outcome) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![time.timestamp_millis(), door, event_type.name(), data, outcome],
+            )?;
+            if let Some(command_id) = command_id {
+                pending.insert((door, command_id), connection.last_insert_rowid());
+            }
+        }
+        Message::Finished { door, command_id, outcome } => {
+            if let Some(row) = pending.remove(&(door, command_id)) {
+                connection.execute("UPDATE events SET outcome = ?1 WHERE id = ?2", params![outcome, row])?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn prune(connection: &Connection, retention: TimeDelta) {
+    let cutoff = (Local::now() - retention).timestamp_millis();
+    if let Err(err) = connection.execute("DELETE FROM events WHERE time < ?1", params![cutoff]) {
+        eprintln!("Failed to drop old history events: {}", err);
+    }
+}
+
+fn query_events(connection: &Connection, filter: &HistoryFilter) -> Result<HistoryPage, rusqlite::Error> {
+    let mut statement = connection.prepare_cached(
+        "SELECT id, time, door, type, data, outcome FROM events
+         WHERE (?1 IS NULL OR time >= ?1)
+           AND (?2 IS NULL OR time < ?2)
+           AND (?3 IS NULL OR type = ?3)
+           AND (?4 IS NULL OR door = ?4)
+           AND (?5 IS NULL OR id < ?5)
+         ORDER BY id DESC
+         LIMIT ?6",
+    )?;
+    // One more than asked for tells whether there is another page
+    let rows = statement.query_map(
+        params![
+            filter.from.map(|from| from.timestamp_millis()),
+            filter.to.map(|to| to.timestamp_millis()),
+            filter.event_type.map(EventType::name),
+            filter.door,
+            filter.before,
+            filter.limit + 1,
+        ],
+        |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, String>(4)?,
+                row.get::<_, Option<String>>(5)?,
+            ))
+        },
+    )?;
+
+    let mut events = Vec::new();
+    let mut last_id = None;
+    for row in rows {
+        let (id, time, door, event_type, data, outcome) = row?;
+        if events.len() == filter.limit as usize {
+            return Ok(HistoryPage { events, next_before: last_id });
+        }
+        let mut event: Map<String, Value> = serde_json::from_str(&data).unwrap_or_default();
+        event.insert("id".to_string(), id.into());
+        let time = Local.timestamp_millis_opt(time).single().map(|time| time.to_rfc3339());
+        event.insert("time".to_string(), time.into());
+        event.insert("door".to_string(), door.into());
+        if event_type == EventType::Command.name() {
+            // Null while the command is still going
+            event.insert("outcome".to_string(), outcome.into());
+        }
+        event.insert("type".to_string(), event_type.into());
+        events.push(Value::Object(event));
+        last_id = Some(id);
+    }
+    Ok(HistoryPage { events, next_before: None })
+}
+
+// "completed", "timed_out" and so on
+fn phase_name(phase: CommandPhase) -> String {
+    let value = serde_json::to_value(phase).expect("Phases always serialize");
+    value["phase"].as_str().unwrap_or_default().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    type Pending = HashMap<(String, CommandId), i64>;
+
+    fn database() -> Connection {
+        let connection = Connection::open_in_memory().unwrap();
+        connection.execute_batch(SCHEMA).unwrap();
+        connection
+    }
+
+    fn at(hour: u32) -> DateTime<Local> {
+        Local.with_ymd_and_hms(2025, 6, 2, hour, 0, 0).unwrap()
+    }
+
+    fn record(connection: &Connection, pending: &mut Pending, time: DateTime<Local>, door: &str, command_id: Option<CommandId>) {
+        let (event_type, data) = match command_id {
+            Some(command_id) => (EventType::Command, json!({"command_id": command_id, "command": "open", "source": "api"})),
+            None => (EventType::State, json!({"status": "open", "setpoint": "open", "position": 1.0})),
+        };
+        let message = Message::Record {
+            time,
+            door: door.to_string(),
+            event_type,
+            data: data.to_string(),
+            command_id,
+            outcome: None,
+        };
+        write(connection, pending, message).unwrap();
+    }
+
+    fn filter() -> HistoryFilter {
+        HistoryFilter {
+            from: None,
+            to: None,
+            event_type: None,
+            door: None,
+            limit: 100,
+            before: None,
+        }
+    }
+
+    fn ids(connection: &Connection, filter: HistoryFilter) -> Vec<i64> {
+        let page = query_events(connection, &filter).unwrap();
+        page.events.iter().map(|event| event["id"].as_i64().unwrap()).collect()
+    }
+
+    #[test]
+    fn filters_events() {
+        let connection = database();
+        let mut pending = Pending::new();
+        record(&connection, &mut pending, at(8), "default", None);
+        record(&connection, &mut pending, at(9), "default", Some(1));
+        record(&connection, &mut pending, at(10), "side", None);
+        record(&connection, &mut pending, at(11), "side", Some(1));
+
+        assert_eq!(ids(&connection, filter()), [4, 3, 2, 1]);
+        let time = |hour| Some(at(hour).fixed_offset());
+        assert_eq!(ids(&connection, HistoryFilter { from: time(9), ..filter() }), [4, 3, 2]);
+        assert_eq!(ids(&connection, HistoryFilter { to: time(10), ..filter() }), [2, 1]);
+        assert_eq!(ids(&connection, HistoryFilter { from: time(9), to: time(11), ..filter() }), [3, 2]);
+        assert_eq!(ids(&connection, HistoryFilter { event_type: Some(EventType::Command), ..filter() }), [4, 2]);
+        assert_eq!(ids(&connection, HistoryFilter { event_type: Some(EventType::State), ..filter() }), [3, 1]);
+        assert_eq!(ids(&connection, HistoryFilter { door: Some("side".to_string()), ..filter() }), [4, 3]);
+        let side_commands = HistoryFilter {
+            event_type: Some(EventType::Command),
+            door: Some("side".to_string()),
+            ..filter()
+        };
+        assert_eq!(ids(&connection, side_commands), [4]);
+    }
+
+    #[test]
+    fn fills_in_command_outcomes() {
+        let connection = database();
+        let mut pending = Pending::new();
+        record(&connection, &mut pending, at(8), "default", None);
+        record(&connection, &mut pending, at(9), "default", Some(1));
+        record(&connection, &mut pending, at(10), "side", Some(1));
+        let finished = Message::Finished {
+            door: "default".to_string(),
+            command_id: 1,
+            outcome: phase_name(CommandPhase::Completed),
+        };
+        write(&connection, &mut pending, finished).unwrap();
+
+        let page = query_events(&connection, &filter()).unwrap();
+        let [side, command, state] = &page.events[..] else {
+            panic!("Expected three events, got {:?}", page.events);
+        };
+        assert_eq!(side["outcome"], Value::Null);
+        assert_eq!(command["type"], "command");
+        assert_eq!(command["door"], "default");
+        assert_eq!(command["command"], "open");
+        assert_eq!(command["outcome"], "completed");
+        assert_eq!(command["time"], at(9).to_rfc3339());
+        assert_eq!(state["type"], "state");
+        assert_eq!(state["status"], "open");
+        assert!(state.get("outcome").is_none());
+    }
+
+    #[test]
+    fn pages_back_through_older_events() {
+        let connection = database();
+        let mut pending = Pending::new();
+        for hour in 8..13 {
+            record(&connection, &mut pending, at(hour), "default", None);
+        }
+
+        let page = query_events(&connection, &HistoryFilter { limit: 2, ..filter() }).unwrap();
+        assert_eq!(page.events.len(), 2);
+        assert_eq!(page.next_before, Some(4));
+        let before = |before| HistoryFilter { limit: 2, before: Some(before), ..filter() };
+        assert_eq!(ids(&connection, before(4)), [3, 2]);
+        assert_eq!(query_events(&connection, &before(4)).unwrap().next_before, Some(2));
+        assert_eq!(ids(&connection, before(2)), [1]);
+        assert_eq!(query_events(&connection, &before(2)).unwrap().next_before, None);
+
+        // Exactly a full page left is still the last one
+        let page = query_events(&connection, &HistoryFilter { limit: 5, ..filter() }).unwrap();
+        assert_eq!((page.events.len(), page.next_before), (5, None));
+    }
+
+    #[test]
+    fn prunes_events_past_the_retention() {
+        let connection = database();
+        let mut pending = Pending::new();
+        let now = Local::now();
+        record(&connection, &mut pending, now - TimeDelta::days(100), "default", None);
+        record(&connection, &mut pending, now - TimeDelta::days(89), "default", None);
+        record(&connection, &mut pending, now, "default", None);
+
+        prune(&connection, TimeDelta::days(90));
+        assert_eq!(ids(&connection, filter()), [3, 2]);
+    }
+}
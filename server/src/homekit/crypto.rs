@@ -0,0 +1,55 @@
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use hkdf::Hkdf;
+use sha2::Sha512;
+
+/// HKDF-SHA-512 with the salt and info strings from the HAP spec
+pub fn derive_key(secret: &[u8], salt: &str, info: &str) -> [u8; 32] {
+    let mut key = [0; 32];
+    Hkdf::<Sha512>::new(Some(salt.as_bytes()), secret)
+        .expand(info.as_bytes(), &mut key)
+        .expect("32 bytes is a valid HKDF-SHA-512 length");
+    key
+}
+
+/// Nonce for a pairing message, like `PS-Msg05`
+pub fn message_nonce(label: &[u8; 8]) -> [u8; 12] {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(label);
+    nonce
+}
+
+/// Nonce for the nth frame of a session in one direction
+pub fn counter_nonce(counter: u64) -> [u8; 12] {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    nonce
+}
+
+/// ChaCha20-Poly1305 encryption, with the tag appended
+pub fn seal(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], message: &[u8]) -> Vec<u8> {
+    ChaCha20Poly1305::new(Key::from_slice(key))
+        .encrypt(Nonce::from_slice(nonce), Payload { msg: message, aad })
+        .expect("Messages are far below the ChaCha20 length limit")
+}
+
+/// Decrypt and check the tag. `None` if it doesn't match.
+pub fn open(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+    ChaCha20Poly1305::new(Key::from_slice(key))
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: sealed, aad })
+        .ok()
+}
+
+/// Check an Ed25519 signature made by a controller
+pub fn verify_signature(public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    let Ok(public_key) = <[u8; 32]>::try_from(public_key) else {
+        return false;
+    };
+    let (Ok(public_key), Ok(signature)) = (VerifyingKey::from_bytes(&public_key), Signature::from_slice(signature)) else {
+        return false;
+    };
+    public_key.verify(message, &signature).is_ok()
+}
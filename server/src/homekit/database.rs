@@ -0,0 +1,257 @@
+// The accessories, services and characteristics we show to controllers
+use serde_json::{json, Value};
+use crate::{
+    commands::CommandSource,
+    controller::{DoorSetpoint, DoorState, DoorStatus, FaultReason, GpioCommand, RetryPhase},
+    submit_command,
+};
+use super::Accessory;
+
+// Instance IDs, the same on every accessory
+pub const IID_INFORMATION: u64 = 1;
+pub const IID_IDENTIFY: u64 = 2;
+pub const IID_MANUFACTURER: u64 = 3;
+pub const IID_MODEL: u64 = 4;
+pub const IID_NAME: u64 = 5;
+pub const IID_SERIAL_NUMBER: u64 = 6;
+pub const IID_FIRMWARE_REVISION: u64 = 7;
+pub const IID_PROTOCOL_INFORMATION: u64 = 8;
+pub const IID_VERSION: u64 = 9;
+pub const IID_GARAGE_DOOR_OPENER: u64 = 10;
+pub const IID_CURRENT_DOOR_STATE: u64 = 11;
+pub const IID_TARGET_DOOR_STATE: u64 = 12;
+pub const IID_OBSTRUCTION_DETECTED: u64 = 13;
+pub const IID_OPENER_NAME: u64 = 14;
+
+// Status codes for single characteristics
+pub const STATUS_INSUFFICIENT_PRIVILEGES: i32 = -70401;
+pub const STATUS_COMMUNICATION_FAILURE: i32 = -70402;
+pub const STATUS_READ_ONLY: i32 = -70404;
+pub const STATUS_WRITE_ONLY: i32 = -70405;
+pub const STATUS_NO_NOTIFICATION: i32 = -70406;
+pub const STATUS_NOT_FOUND: i32 = -70409;
+pub const STATUS_INVALID_VALUE: i32 = -70410;
+
+const CURRENT_OPEN: u8 = 0;
+const CURRENT_CLOSED: u8 = 1;
+const CURRENT_OPENING: u8 = 2;
+const CURRENT_CLOSING: u8 = 3;
+const CURRENT_STOPPED: u8 = 4;
+const TARGET_OPEN: u8 = 0;
+const TARGET_CLOSED: u8 = 1;
+
+/// What a door's garage door opener service shows
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DoorValues {
+    pub current: u8,
+    pub target: u8,
+    pub obstruction: bool,
+}
+
+impl From<&DoorState> for DoorValues {
+    fn from(state: &DoorState) -> Self {
+        let current = match state.status {
+            DoorStatus::Open => CURRENT_OPEN,
+            DoorStatus::Closed => CURRENT_CLOSED,
+            DoorStatus::MovingUp => CURRENT_OPENING,
+            DoorStatus::MovingDown => CURRENT_CLOSING,
+            // HAP has nothing closer for a door that isn't at either end
+            DoorStatus::Ajar | DoorStatus::Unknown | DoorStatus::Fault { .. } => CURRENT_STOPPED,
+        };
+        // There is no partly open target, so anything but closed counts as open
+        let target = match state.setpoint {
+            DoorSetpoint::Closed => TARGET_CLOSED,
+            DoorSetpoint::Open | DoorSetpoint::Ajar | DoorSetpoint::Position(_) => TARGET_OPEN,
+        };
+        let obstruction = matches!(
+            state.status,
+            DoorStatus::Fault { reason: FaultReason::Reversed | FaultReason::Stalled }
+        ) || state.close_retry.is_some_and(|retry| retry.phase == RetryPhase::GaveUp);
+        Self { current, target, obstruction }
+    }
+}
+
+impl DoorValues {
+    /// Door characteristics that differ from `previous`
+    pub fn changes(&self, previous: &DoorValues) -> Vec<(u64, Value)> {
+        let mut changes = Vec::new();
+        if self.current != previous.current {
+            changes.push((IID_CURRENT_DOOR_STATE, json!(self.current)));
+        }
+        if self.target != previous.target {
+            changes.push((IID_TARGET_DOOR_STATE, json!(self.target)));
+        }
+        if self.obstruction != previous.obstruction {
+            changes.push((IID_OBSTRUCTION_DETECTED, json!(self.obstruction)));
+        }
+        changes
+    }
+}
+
+impl Accessory {
+    /// Body of `/accessories`
+    pub fn database(&self, values: &[DoorValues]) -> Value {
+        let mut accessories = Vec::new();
+        if self.bridge {
+            accessories.push(json!({
+                "aid": 1,
+                "services": [self.information_service(1), protocol_service()],
+            }));
+        }
+        for (index, (aid, _)) in self.doors.iter().enumerate() {
+            let mut services = vec![self.information_service(*aid)];
+            if *aid == 1 {
+                services.push(protocol_service());
+            }
+            services.push(self.garage_door_service(*aid, values[index]));
+            accessories.push(json!({ "aid": aid, "services": services }));
+        }
+        json!({ "accessories": accessories })
+    }
+
+    fn information_service(&self, aid: u64) -> Value {
+        json!({
+            "type": "3E",
+            "iid": IID_INFORMATION,
+            "characteristics": [
+                { "type": "14", "iid": IID_IDENTIFY, "perms": ["pw"], "format": "bool" },
+                self.string_characteristic("20", aid, IID_MANUFACTURER),
+                self.string_characteristic("21", aid, IID_MODEL),
+                self.string_characteristic("23", aid, IID_NAME),
+                self.string_characteristic("30", aid, IID_SERIAL_NUMBER),
+                self.string_characteristic("52", aid, IID_FIRMWARE_REVISION),
+            ],
+        })
+    }
+
+    fn garage_door_service(&self, aid: u64, values: DoorValues) -> Value {
+        json!({
+            "type": "41",
+            "iid": IID_GARAGE_DOOR_OPENER,
+            "primary": true,
+            "characteristics": [
+                {
+                    "type": "0E", "iid": IID_CURRENT_DOOR_STATE, "perms": ["pr", "ev"], "format": "uint8",
+                    "minValue": 0, "maxValue": 4, "minStep": 1, "value": values.current,
+                },
+                {
+                    "type": "32", "iid": IID_TARGET_DOOR_STATE, "perms": ["pr", "pw", "ev"], "format": "uint8",
+                    "minValue": 0, "maxValue": 1, "minStep": 1, "value": values.target,
+                },
+                {
+                    "type": "24", "iid": IID_OBSTRUCTION_DETECTED, "perms": ["pr", "ev"], "format": "bool",
+                    "value": values.obstruction,
+                },
+                self.string_characteristic("23", aid, IID_OPENER_NAME),
+            ],
+        })
+    }
+
+    fn string_characteristic(&self, kind: &str, aid: u64, iid: u64) -> Value {
+        json!({
+            "type": kind,
+            "iid": iid,
+            "perms": ["pr"],
+            "format": "string",
+            "value": self.string_value(aid, iid),
+        })
+    }
+
+    fn string_value(&self, aid: u64, iid: u64) -> Option<String> {
+        let door = self.door(aid).map(|(_, door)| door);
+        let value = match iid {
+            IID_MANUFACTURER => "PiOpener".to_string(),
+            IID_MODEL if door.is_some() => "Garage Door Opener".to_string(),
+            IID_MODEL => "Bridge".to_string(),
+            // Bridged doors are told apart by their ID
+            IID_NAME | IID_OPENER_NAME => match door {
+                Some(door) if self.bridge => format!("{} {}", self.name, door.id),
+                _ => self.name.clone(),
+            },
+            IID_SERIAL_NUMBER => match door {
+                Some(door) if self.bridge => door.id.clone(),
+                _ => self.device_id(),
+            },
+            IID_FIRMWARE_REVISION => env!("CARGO_PKG_VERSION").to_string(),
+            IID_VERSION if aid == 1 => "1.1.0".to_string(),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    // Whether the accessory has this characteristic
+    fn exists(&self, aid: u64, iid: u64) -> bool {
+        let door = self.door(aid).is_some();
+        match iid {
+            IID_IDENTIFY | IID_MANUFACTURER | IID_MODEL | IID_NAME | IID_SERIAL_NUMBER | IID_FIRMWARE_REVISION => {
+                aid == 1 || door
+            }
+            IID_VERSION => aid == 1,
+            IID_CURRENT_DOOR_STATE | IID_TARGET_DOOR_STATE | IID_OBSTRUCTION_DETECTED | IID_OPENER_NAME => door,
+            _ => false,
+        }
+    }
+
+    /// Value of one characteristic, or the HAP status code to answer with instead
+    pub fn read(&self, aid: u64, iid: u64, values: &[DoorValues]) -> Result<Value, i32> {
+        if !self.exists(aid, iid) {
+            return Err(STATUS_NOT_FOUND);
+        }
+        if iid == IID_IDENTIFY {
+            return Err(STATUS_WRITE_ONLY);
+        }
+        if let Some(value) = self.string_value(aid, iid) {
+            return Ok(json!(value));
+        }
+        let (index, _) = self.door(aid).ok_or(STATUS_NOT_FOUND)?;
+        let values = values[index];
+        match iid {
+            IID_CURRENT_DOOR_STATE => Ok(json!(values.current)),
+            IID_TARGET_DOOR_STATE => Ok(json!(values.target)),
+            _ => Ok(json!(values.obstruction)),
+        }
+    }
+
+    /// Set a characteristic. Target door state goes through the same path as `/open` and `/close`.
+    pub fn write(&self, aid: u64, iid: u64, value: &Value) -> Result<(), i32> {
+        if !self.exists(aid, iid) {
+            return Err(STATUS_NOT_FOUND);
+        }
+        match iid {
+            // There is nothing to blink, so identifying does nothing
+            IID_IDENTIFY => Ok(()),
+            IID_TARGET_DOOR_STATE => {
+                let (_, door) = self.door(aid).ok_or(STATUS_NOT_FOUND)?;
+                let command = match value.as_u64().or_else(|| value.as_bool().map(u64::from)) {
+                    Some(0) => GpioCommand::Open,
+                    Some(1) => GpioCommand::Close,
+                    _ => return Err(STATUS_INVALID_VALUE),
+                };
+                submit_command(door, command, CommandSource::Homekit).map(|_| ()).map_err(|err| {
+                    eprintln!("HomeKit command for door {} failed: {}", door.id, err.message);
+                    STATUS_COMMUNICATION_FAILURE
+                })
+            }
+            _ => Err(STATUS_READ_ONLY),
+        }
+    }
+
+    /// Whether a characteristic can send events
+    pub fn notifies(&self, aid: u64, iid: u64) -> Result<(), i32> {
+        match iid {
+            _ if !self.exists(aid, iid) => Err(STATUS_NOT_FOUND),
+            IID_CURRENT_DOOR_STATE | IID_TARGET_DOOR_STATE | IID_OBSTRUCTION_DETECTED => Ok(()),
+            _ => Err(STATUS_NO_NOTIFICATION),
+        }
+    }
+}
+
+fn protocol_service() -> Value {
+    json!({
+        "type": "A2",
+        "iid": IID_PROTOCOL_INFORMATION,
+        "characteristics": [
+            { "type": "37", "iid": IID_VERSION, "perms": ["pr"], "format": "string", "value": "1.1.0" },
+        ],
+    })
+}
@@ -0,0 +1,234 @@
+// HomeKit Accessory Protocol over IP, so the doors show up in the Home app
+mod crypto;
+mod database;
+mod pairing;
+mod session;
+mod srp;
+mod tlv;
+
+use std::{
+    error::Error,
+    path::PathBuf,
+    sync::{atomic::AtomicU32, Arc, Mutex},
+    time::Duration,
+};
+use ed25519_dalek::SigningKey;
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use tokio::{net::TcpListener, sync::watch, task::JoinHandle};
+use crate::{
+    config::HomekitConfig,
+    door::{DoorHandle, Doors},
+};
+use database::DoorValues;
+use pairing::{Pairing, Store};
+
+const SERVICE_TYPE: &str = "_hap._tcp.local.";
+const CATEGORY_BRIDGE: u8 = 2;
+const CATEGORY_GARAGE_DOOR_OPENER: u8 = 4;
+
+/// Everything the controller connections share
+pub struct Accessory {
+    name: String,
+    setup_code: String,
+    /// One door is shown as a plain accessory, more sit behind a bridge at aid 1
+    bridge: bool,
+    /// Each door with its accessory ID
+    doors: Vec<(u64, DoorHandle)>,
+    values: watch::Receiver<Vec<DoorValues>>,
+    signing_key: SigningKey,
+    store: Mutex<Store>,
+    store_path: PathBuf,
+    /// Wrong setup codes since startup
+    failed_setups: AtomicU32,
+    advertiser: Advertiser,
+}
+
+impl Accessory {
+    fn door(&self, aid: u64) -> Option<(usize, &DoorHandle)> {
+        self.doors
+            .iter()
+            .enumerate()
+            .find(|(_, (door_aid, _))| *door_aid == aid)
+            .map(|(index, (_, door))| (index, door))
+    }
+
+    fn device_id(&self) -> String {
+        self.store.lock().unwrap().device_id.clone()
+    }
+
+    fn is_paired(&self) -> bool {
+        !self.store.lock().unwrap().pairings.is_empty()
+    }
+
+    fn pairing(&self, id: &str) -> Option<Pairing> {
+        self.store.lock().unwrap().pairing(id).cloned()
+    }
+
+    fn pairings(&self) -> Vec<Pairing> {
+        self.store.lock().unwrap().pairings.clone()
+    }
+
+    // Change the store and save it. Nothing changes if saving fails.
+    fn update_store(&self, update: impl FnOnce(&mut Store)) -> bool {
+        let mut store = self.store.lock().unwrap();
+        let mut updated = store.clone();
+        update(&mut updated);
+        if let Err(err) = updated.save(&self.store_path) {
+            eprintln!("Failed to save HomeKit pairings to {}: {}", self.store_path.display(), err);
+            return false;
+        }
+        let was_paired = !store.pairings.is_empty();
+        *store = updated;
+        let paired = !store.pairings.is_empty();
+        // Controllers look for unpaired accessories through the status flags
+        if paired != was_paired {
+            self.advertiser.advertise(&store);
+        }
+        true
+    }
+}
+
+// Announces us over mDNS
+struct Advertiser {
+    daemon: ServiceDaemon,
+    name: String,
+    host: String,
+    port: u16,
+    category: u8,
+}
+
+impl Advertiser {
+    fn advertise(&self, store: &Store) {
+        let properties = [
+            ("c#", store.config_number.to_string()),
+            ("ff", "0".to_string()),
+            ("id", store.device_id.clone()),
+            ("md", self.name.clone()),
+            ("pv", "1.1".to_string()),
+            ("s#", "1".to_string()),
+            ("sf", if store.pairings.is_empty() { "1" } else { "0" }.to_string()),
+            ("ci", self.category.to_string()),
+        ];
+        let registered = ServiceInfo::new(SERVICE_TYPE, &self.name, &self.host, "", self.port, &properties[..])
+            .and_then(|info| self.daemon.register(info.enable_addr_auto()));
+        if let Err(err) = registered {
+            eprintln!("Failed to advertise the HomeKit accessory: {}", err);
+        }
+    }
+}
+
+/// Lets main stop advertising on shutdown
+pub struct HomekitHandle {
+    accessory: Arc<Accessory>,
+    task: JoinHandle<()>,
+}
+
+impl HomekitHandle {
+    /// Stop taking connections and withdraw the mDNS announcement, waiting up to `timeout` for that to go out
+    pub async fn shutdown(self, timeout: Duration) {
+        self.task.abort();
+        let advertiser = &self.accessory.advertiser;
+        let Ok(unregistered) = advertiser.daemon.unregister(&format!("{}.{}", advertiser.name, SERVICE_TYPE)) else {
+            return;
+        };
+        let goodbye = tokio::task::spawn_blocking(move || unregistered.recv_timeout(timeout).is_ok());
+        if !goodbye.await.unwrap_or(false) {
+            eprintln!("HomeKit accessory was not withdrawn from mDNS in time");
+        }
+        let _ = advertiser.daemon.shutdown();
+    }
+}
+
+/// Start the HomeKit accessory server and announce it over mDNS
+pub async fn spawn(config: &HomekitConfig, doors: &Doors) -> Result<HomekitHandle, Box<dyn Error>> {
+    if !valid_setup_code(&config.setup_code) {
+        return Err("HomeKit setup_code must look like 123-45-678 and can't be a trivial code like 111-11-111".into());
+    }
+    let store_path = PathBuf::from(config.state_file.as_deref().unwrap_or("homekit.json"));
+    let mut store = Store::load(&store_path)?;
+    if store.update_doors(doors.iter().map(|door| door.id.clone()).collect()) {
+        store.save(&store_path)?;
+    }
+
+    let port = config.port.unwrap_or(51826);
+    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+
+    let bridge = doors.iter().count() > 1;
+    let first_aid = if bridge { 2 } else { 1 };
+    let doors: Vec<_> = (first_aid..).zip(doors.iter().cloned()).collect();
+    let (values_tx, values) = watch::channel(
+        doors
+            .iter()
+            .map(|(_, door)| DoorValues::from(&*door.state.borrow()))
+            .collect::<Vec<_>>(),
+    );
+    for (index, (_, door)) in doors.iter().enumerate() {
+        tokio::spawn(watch_door(door.clone(), index, values_tx.clone()));
+    }
+
+    let name = config.name.clone().unwrap_or_else(|| "PiOpener".to_string());
+    let advertiser = Advertiser {
+        daemon: ServiceDaemon::new()?,
+        name: name.clone(),
+        host: format!("piopener-{}.local.", store.device_id.replace(':', "").to_lowercase()),
+        port,
+        category: if bridge { CATEGORY_BRIDGE } else { CATEGORY_GARAGE_DOOR_OPENER },
+    };
+    advertiser.advertise(&store);
+
+    let accessory = Arc::new(Accessory {
+        name,
+        setup_code: config.setup_code.clone(),
+        bridge,
+        doors,
+        values,
+        signing_key: store.signing_key(),
+        store: Mutex::new(store),
+        store_path,
+        failed_setups: AtomicU32::new(0),
+        advertiser,
+    });
+    let task = tokio::spawn(accept(listener, accessory.clone()));
+    Ok(HomekitHandle { accessory, task })
+}
+
+async fn accept(listener: TcpListener, accessory: Arc<Accessory>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                // Requests and events are small, don't hold them back
+                let _ = stream.set_nodelay(true);
+                tokio::spawn(session::serve(accessory.clone(), stream));
+            }
+            Err(err) => {
+                eprintln!("Failed to accept a HomeKit connection: {}", err);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+// Keep the characteristic values of one door up to date
+async fn watch_door(door: DoorHandle, index: usize, values_tx: watch::Sender<Vec<DoorValues>>) {
+    let mut state_rx = door.state.subscribe();
+    while state_rx.changed().await.is_ok() {
+        let latest = DoorValues::from(&*state_rx.borrow_and_update());
+        values_tx.send_if_modified(|values| {
+            let changed = values[index] != latest;
+            values[index] = latest;
+            changed
+        });
+    }
+}
+
+// XXX-XX-XXX with digits, minus the codes the spec forbids
+fn valid_setup_code(code: &str) -> bool {
+    let formatted = code.len() == 10
+        && code
+            .bytes()
+            .enumerate()
+            .all(|(i, byte)| if i == 3 || i == 6 { byte == b'-' } else { byte.is_ascii_digit() });
+    let digits = code.replace('-', "");
+    let trivial = digits.bytes().all(|byte| byte == digits.as_bytes()[0]) || digits == "12345678" || digits == "87654321";
+    formatted && !trivial
+}
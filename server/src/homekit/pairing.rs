@@ -0,0 +1,353 @@
+use std::{fs, io, path::Path, sync::atomic::Ordering};
+use ed25519_dalek::{Signer, SigningKey};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+use super::{
+    crypto::{derive_key, message_nonce, open, seal, verify_signature},
+    srp::SrpServer,
+    tlv::{self, Tlv},
+    Accessory,
+};
+use crate::state_file::write_json;
+
+// The spec asks accessories to give up on pair setup after this many wrong setup codes
+const MAX_SETUP_ATTEMPTS: u32 = 100;
+const MAX_PAIRINGS: usize = 16;
+
+/// Our identity and the controllers allowed to talk to us, kept across restarts
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Store {
+    /// Looks like a MAC address, controllers tell accessories apart by it
+    pub device_id: String,
+    secret_key: Vec<u8>,
+    /// Bumped whenever the accessories change so controllers fetch them again
+    pub config_number: u32,
+    /// Door IDs the config number was handed out for
+    pub doors: Vec<String>,
+    pub pairings: Vec<Pairing>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Pairing {
+    pub id: String,
+    pub public_key: Vec<u8>,
+    pub admin: bool,
+}
+
+impl Store {
+    /// Load the store, or make up a new identity if there is none yet
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        match fs::read_to_string(path) {
+            Ok(contents) => {
+                let store: Self = serde_json::from_str(&contents)?;
+                if store.secret_key.len() != 32 {
+                    return Err(format!("{} has an invalid secret key", path.display()).into());
+                }
+                Ok(store)
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let mut id = [0; 6];
+                OsRng.fill_bytes(&mut id);
+                let device_id = id.iter().map(|byte| format!("{byte:02X}")).collect::<Vec<_>>().join(":");
+                Ok(Self {
+                    device_id,
+                    secret_key: SigningKey::generate(&mut OsRng).to_bytes().to_vec(),
+                    config_number: 1,
+                    doors: Vec::new(),
+                    pairings: Vec::new(),
+                })
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        write_json(path, self)
+    }
+
+    pub fn signing_key(&self) -> SigningKey {
+        SigningKey::from_bytes(&self.secret_key.as_slice().try_into().expect("Checked on load"))
+    }
+
+    /// Hand out a new config number if the doors changed since the last run
+    pub fn update_doors(&mut self, doors: Vec<String>) -> bool {
+        if self.doors == doors {
+            return false;
+        }
+        if !self.doors.is_empty() {
+            // c# has to stay between 1 and 65535
+            self.config_number = self.config_number % 65535 + 1;
+        }
+        self.doors = doors;
+        true
+    }
+
+    pub fn pairing(&self, id: &str) -> Option<&Pairing> {
+        self.pairings.iter().find(|pairing| pairing.id == id)
+    }
+}
+
+/// Pair setup that is in progress on a connection
+pub struct PairSetup {
+    srp: SrpServer,
+    // Set once the controller proved it knows the setup code
+    key: Option<Vec<u8>>,
+}
+
+/// `/pair-setup`: exchange long-term keys with a controller that knows the setup code
+pub fn pair_setup(accessory: &Accessory, setup: &mut Option<PairSetup>, body: &[u8]) -> Vec<u8> {
+    let Some(request) = Tlv::decode(body) else {
+        return tlv::error(2, tlv::ERROR_UNKNOWN);
+    };
+    match request.byte(tlv::STATE) {
+        Some(1) => {
+            if accessory.is_paired() {
+                return tlv::error(2, tlv::ERROR_UNAVAILABLE);
+            }
+            if accessory.failed_setups.load(Ordering::Relaxed) >= MAX_SETUP_ATTEMPTS {
+                return tlv::error(2, tlv::ERROR_MAX_TRIES);
+            }
+            let srp = SrpServer::new(&accessory.setup_code);
+            let reply = Tlv::default()
+                .push(tlv::STATE, [2])
+                .push(tlv::SALT, srp.salt())
+                .push(tlv::PUBLIC_KEY, srp.public_key())
+                .encode();
+            *setup = Some(PairSetup { srp, key: None });
+            reply
+        }
+        Some(3) => {
+            let (Some(pending), Some(public_key), Some(proof)) =
+                (setup.as_mut(), request.get(tlv::PUBLIC_KEY), request.get(tlv::PROOF))
+            else {
+                return tlv::error(4, tlv::ERROR_UNKNOWN);
+            };
+            match pending.srp.verify(public_key, proof) {
+                Some((key, proof)) => {
+                    pending.key = Some(key);
+                    Tlv::default().push(tlv::STATE, [4]).push(tlv::PROOF, proof).encode()
+                }
+                None => {
+                    *setup = None;
+                    accessory.failed_setups.fetch_add(1, Ordering::Relaxed);
+                    tlv::error(4, tlv::ERROR_AUTHENTICATION)
+                }
+            }
+        }
+        Some(5) => {
+            let key = setup.take().and_then(|setup| setup.key);
+            exchange_keys(accessory, key, &request).unwrap_or_else(|error| tlv::error(6, error))
+        }
+        _ => tlv::error(2, tlv::ERROR_UNKNOWN),
+    }
+}
+
+// M5 and M6 of pair setup, where both sides sign their long-term keys
+fn exchange_keys(accessory: &Accessory, key: Option<Vec<u8>>, request: &Tlv) -> Result<Vec<u8>, u8> {
+    let key = key.ok_or(tlv::ERROR_UNKNOWN)?;
+    let encrypted = request.get(tlv::ENCRYPTED_DATA).ok_or(tlv::ERROR_UNKNOWN)?;
+    let session_key = derive_key(&key, "Pair-Setup-Encrypt-Salt", "Pair-Setup-Encrypt-Info");
+    let sub = open(&session_key, &message_nonce(b"PS-Msg05"), &[], encrypted)
+        .and_then(|decrypted| Tlv::decode(&decrypted))
+        .ok_or(tlv::ERROR_AUTHENTICATION)?;
+    let (Some(id), Some(public_key), Some(signature)) =
+        (sub.get(tlv::IDENTIFIER), sub.get(tlv::PUBLIC_KEY), sub.get(tlv::SIGNATURE))
+    else {
+        return Err(tlv::ERROR_UNKNOWN);
+    };
+
+    let controller_x = derive_key(&key, "Pair-Setup-Controller-Sign-Salt", "Pair-Setup-Controller-Sign-Info");
+    if !verify_signature(public_key, &[&controller_x, id, public_key].concat(), signature) {
+        return Err(tlv::ERROR_AUTHENTICATION);
+    }
+    let pairing = Pairing {
+        id: String::from_utf8(id.to_vec()).map_err(|_| tlv::ERROR_UNKNOWN)?,
+        public_key: public_key.to_vec(),
+        admin: true,
+    };
+    // Another connection may have finished pair setup since M1, checked under the same lock as the store
+    let mut taken = false;
+    let saved = accessory.update_store(|store| {
+        taken = !store.pairings.is_empty();
+        if !taken {
+            store.pairings.push(pairing);
+        }
+    });
+    if taken {
+        return Err(tlv::ERROR_UNAVAILABLE);
+    }
+    if !saved {
+        return Err(tlv::ERROR_UNKNOWN);
+    }
+
+    let accessory_x = derive_key(&key, "Pair-Setup-Accessory-Sign-Salt", "Pair-Setup-Accessory-Sign-Info");
+    let public_key = accessory.signing_key.verifying_key().to_bytes();
+    let device_id = accessory.device_id();
+    let signature = accessory
+        .signing_key
+        .sign(&[&accessory_x, device_id.as_bytes(), &public_key].concat());
+    let sub = Tlv::default()
+        .push(tlv::IDENTIFIER, device_id)
+        .push(tlv::PUBLIC_KEY, public_key)
+        .push(tlv::SIGNATURE, signature.to_bytes())
+        .encode();
+    let encrypted = seal(&session_key, &message_nonce(b"PS-Msg06"), &[], &sub);
+    Ok(Tlv::default().push(tlv::STATE, [6]).push(tlv::ENCRYPTED_DATA, encrypted).encode())
+}
+
+/// Pair verify that is in progress on a connection
+pub struct PairVerify {
+    shared_secret: [u8; 32],
+    accessory_public: [u8; 32],
+    controller_public: [u8; 32],
+    session_key: [u8; 32],
+}
+
+/// A controller that proved it is paired with us
+pub struct Verified {
+    pub controller: String,
+    pub shared_secret: [u8; 32],
+}
+
+/// `/pair-verify`: agree on session keys with a paired controller
+pub fn pair_verify(accessory: &Accessory, verify: &mut Option<PairVerify>, body: &[u8]) -> (Vec<u8>, Option<Verified>) {
+    let Some(request) = Tlv::decode(body) else {
+        return (tlv::error(2, tlv::ERROR_UNKNOWN), None);
+    };
+    match request.byte(tlv::STATE) {
+        Some(1) => {
+            let Some(controller_public) = request.get(tlv::PUBLIC_KEY).and_then(|key| <[u8; 32]>::try_from(key).ok())
+            else {
+                return (tlv::error(2, tlv::ERROR_UNKNOWN), None);
+            };
+            let secret = EphemeralSecret::random_from_rng(OsRng);
+            let accessory_public = PublicKey::from(&secret).to_bytes();
+            let shared_secret = secret.diffie_hellman(&PublicKey::from(controller_public)).to_bytes();
+
+            let device_id = accessory.device_id();
+            let signature = accessory
+                .signing_key
+                .sign(&[&accessory_public, device_id.as_bytes(), &controller_public].concat());
+            let sub = Tlv::default()
+                .push(tlv::IDENTIFIER, device_id)
+                .push(tlv::SIGNATURE, signature.to_bytes())
+                .encode();
+            let session_key = derive_key(&shared_secret, "Pair-Verify-Encrypt-Salt", "Pair-Verify-Encrypt-Info");
+            let encrypted = seal(&session_key, &message_nonce(b"PV-Msg02"), &[], &sub);
+
+            *verify = Some(PairVerify {
+                shared_secret,
+                accessory_public,
+                controller_public,
+                session_key,
+            });
+            let reply = Tlv::default()
+                .push(tlv::STATE, [2])
+                .push(tlv::PUBLIC_KEY, accessory_public)
+                .push(tlv::ENCRYPTED_DATA, encrypted)
+                .encode();
+            (reply, None)
+        }
+        Some(3) => match finish_verify(accessory, verify.take(), &request) {
+            Ok(verified) => (Tlv::default().push(tlv::STATE, [4]).encode(), Some(verified)),
+            Err(error) => (tlv::error(4, error), None),
+        },
+        _ => (tlv::error(2, tlv::ERROR_UNKNOWN), None),
+    }
+}
+
+// M3 of pair verify, where the controller signs the exchanged keys
+fn finish_verify(accessory: &Accessory, verify: Option<PairVerify>, request: &Tlv) -> Result<Verified, u8> {
+    let verify = verify.ok_or(tlv::ERROR_UNKNOWN)?;
+    let encrypted = request.get(tlv::ENCRYPTED_DATA).ok_or(tlv::ERROR_UNKNOWN)?;
+    let sub = open(&verify.session_key, &message_nonce(b"PV-Msg03"), &[], encrypted)
+        .and_then(|decrypted| Tlv::decode(&decrypted))
+        .ok_or(tlv::ERROR_AUTHENTICATION)?;
+    let (Some(id), Some(signature)) = (sub.get(tlv::IDENTIFIER), sub.get(tlv::SIGNATURE)) else {
+        return Err(tlv::ERROR_UNKNOWN);
+    };
+    let controller = String::from_utf8(id.to_vec()).map_err(|_| tlv::ERROR_AUTHENTICATION)?;
+    let public_key = accessory
+        .pairing(&controller)
+        .ok_or(tlv::ERROR_AUTHENTICATION)?
+        .public_key;
+    let message = [&verify.controller_public, id, &verify.accessory_public].concat();
+    if !verify_signature(&public_key, &message, signature) {
+        return Err(tlv::ERROR_AUTHENTICATION);
+    }
+    Ok(Verified {
+        controller,
+        shared_secret: verify.shared_secret,
+    })
+}
+
+/// `/pairings`: let an admin controller add, remove and list pairings
+pub fn pairings(accessory: &Accessory, controller: &str, body: &[u8]) -> Vec<u8> {
+    let Some(request) = Tlv::decode(body) else {
+        return tlv::error(2, tlv::ERROR_UNKNOWN);
+    };
+    if !accessory.pairing(controller).is_some_and(|pairing| pairing.admin) {
+        return tlv::error(2, tlv::ERROR_AUTHENTICATION);
+    }
+    let done = Tlv::default().push(tlv::STATE, [2]).encode();
+
+    match request.byte(tlv::METHOD) {
+        Some(tlv::METHOD_ADD_PAIRING) => {
+            let (Some(id), Some(public_key), Some(permissions)) = (
+                request.get(tlv::IDENTIFIER).and_then(|id| String::from_utf8(id.to_vec()).ok()),
+                request.get(tlv::PUBLIC_KEY),
+                request.byte(tlv::PERMISSIONS),
+            ) else {
+                return tlv::error(2, tlv::ERROR_UNKNOWN);
+            };
+            let mut error = None;
+            let saved = accessory.update_store(|store| {
+                let full = store.pairings.len() >= MAX_PAIRINGS;
+                match store.pairings.iter_mut().find(|pairing| pairing.id == id) {
+                    // Only the permissions of an existing pairing can change
+                    Some(existing) if existing.public_key != public_key => error = Some(tlv::ERROR_UNKNOWN),
+                    Some(existing) => existing.admin = permissions & 1 == 1,
+                    None if full => error = Some(tlv::ERROR_MAX_PEERS),
+                    None => store.pairings.push(Pairing {
+                        id,
+                        public_key: public_key.to_vec(),
+                        admin: permissions & 1 == 1,
+                    }),
+                }
+            });
+            match error {
+                Some(error) => tlv::error(2, error),
+                None if !saved => tlv::error(2, tlv::ERROR_UNKNOWN),
+                None => done,
+            }
+        }
+        Some(tlv::METHOD_REMOVE_PAIRING) => {
+            let Some(id) = request.get(tlv::IDENTIFIER) else {
+                return tlv::error(2, tlv::ERROR_UNKNOWN);
+            };
+            let saved = accessory.update_store(|store| {
+                store.pairings.retain(|pairing| pairing.id.as_bytes() != id);
+                // Without an admin nobody could manage us anymore, so go back to unpaired
+                if !store.pairings.iter().any(|pairing| pairing.admin) {
+                    store.pairings.clear();
+                }
+            });
+            if saved { done } else { tlv::error(2, tlv::ERROR_UNKNOWN) }
+        }
+        Some(tlv::METHOD_LIST_PAIRINGS) => {
+            let mut reply = Tlv::default().push(tlv::STATE, [2]);
+            for (i, pairing) in accessory.pairings().into_iter().enumerate() {
+                if i > 0 {
+                    reply = reply.push(tlv::SEPARATOR, []);
+                }
+                reply = reply
+                    .push(tlv::IDENTIFIER, pairing.id)
+                    .push(tlv::PUBLIC_KEY, pairing.public_key)
+                    .push(tlv::PERMISSIONS, [pairing.admin as u8]);
+            }
+            reply.encode()
+        }
+        _ => tlv::error(2, tlv::ERROR_UNKNOWN),
+    }
+}
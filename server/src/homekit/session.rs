@@ -0,0 +1,474 @@
+// One controller connection: plain HTTP until pair verify, encrypted frames after
+use std::{collections::HashSet, sync::Arc};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use super::{
+    crypto::{counter_nonce, derive_key, open, seal},
+    database::{DoorValues, STATUS_INSUFFICIENT_PRIVILEGES, STATUS_INVALID_VALUE},
+    pairing::{self, PairSetup, PairVerify, Verified},
+    Accessory,
+};
+
+// Frames carry at most this much plaintext
+const MAX_FRAME: usize = 1024;
+// Drop connections that send more than this without finishing a request
+const MAX_REQUEST: usize = 64 * 1024;
+
+#[derive(Default)]
+struct Connection {
+    setup: Option<PairSetup>,
+    verify: Option<PairVerify>,
+    session: Option<Session>,
+    /// Characteristics the controller asked for events on, as (aid, iid)
+    events: HashSet<(u64, u64)>,
+}
+
+// Keys of a verified connection
+struct Session {
+    controller: String,
+    /// Accessory to controller
+    read_key: [u8; 32],
+    read_count: u64,
+    /// Controller to accessory
+    write_key: [u8; 32],
+    write_count: u64,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    body: Vec<u8>,
+}
+
+struct Reply {
+    status: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Reply {
+    fn json(status: &'static str, body: Value) -> Self {
+        Self {
+            status,
+            content_type: "application/hap+json",
+            body: body.to_string().into_bytes(),
+        }
+    }
+
+    fn tlv(body: Vec<u8>) -> Self {
+        Self {
+            status: "200 OK",
+            content_type: "application/pairing+tlv8",
+            body,
+        }
+    }
+
+    fn empty(status: &'static str) -> Self {
+        Self {
+            status,
+            content_type: "application/hap+json",
+            body: Vec::new(),
+        }
+    }
+
+    fn encode(&self, start: &str) -> Vec<u8> {
+        let mut bytes = format!(
+            "{start} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
+            self.status,
+            self.content_type,
+            self.body.len()
+        )
+        .into_bytes();
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+}
+
+pub async fn serve(accessory: Arc<Accessory>, mut stream: TcpStream) {
+    let mut connection = Connection::default();
+    let mut values_rx = accessory.values.clone();
+    let mut values = values_rx.borrow_and_update().clone();
+    // Bytes off the wire that don't make up a whole frame yet
+    let mut frames = Vec::new();
+    // Decrypted bytes that don't make up a whole request yet
+    let mut requests = Vec::new();
+    let mut buf = [0; 4096];
+
+    loop {
+        tokio::select! {
+            read = stream.read(&mut buf) => {
+                let read = match read {
+                    Ok(0) | Err(_) => break,
+                    Ok(read) => read,
+                };
+                match connection.session.as_mut() {
+                    Some(session) => {
+                        frames.extend_from_slice(&buf[..read]);
+                        if !session.decrypt(&mut frames, &mut requests) {
+                            break;
+                        }
+                    }
+                    None => requests.extend_from_slice(&buf[..read]),
+                }
+
+                let mut failed = false;
+                loop {
+                    let request = match parse_request(&mut requests) {
+                        Ok(Some(request)) => request,
+                        Ok(None) => break,
+                        Err(()) => {
+                            failed = true;
+                            break;
+                        }
+                    };
+                    let (reply, verified) = handle(&accessory, &mut connection, &request, &values);
+                    if connection.send(&mut stream, &reply.encode("HTTP/1.1")).await.is_err() {
+                        failed = true;
+                        break;
+                    }
+                    // Everything after the last pair verify reply is encrypted
+                    if let Some(verified) = verified {
+                        connection.session = Some(Session::new(verified));
+                    }
+                }
+                if failed || requests.len() > MAX_REQUEST {
+                    break;
+                }
+            }
+            Ok(()) = values_rx.changed() => {
+                let latest = values_rx.borrow_and_update().clone();
+                let events = connection.events(&accessory, &values, &latest);
+                values = latest;
+                if !events.is_empty() {
+                    let event = Reply::json("200 OK", json!({ "characteristics": events }));
+                    if connection.send(&mut stream, &event.encode("EVENT/1.0")).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        // Controllers that were unpaired while connected lose access right away
+        if let Some(session) = &connection.session
+            && accessory.pairing(&session.controller).is_none()
+        {
+            break;
+        }
+    }
+}
+
+impl Session {
+    fn new(verified: Verified) -> Self {
+        Self {
+            controller: verified.controller,
+            read_key: derive_key(&verified.shared_secret, "Control-Salt", "Control-Read-Encryption-Key"),
+            read_count: 0,
+            write_key: derive_key(&verified.shared_secret, "Control-Salt", "Control-Write-Encryption-Key"),
+            write_count: 0,
+        }
+    }
+
+    // Move every whole frame from `frames` to `plaintext`. False if one doesn't decrypt.
+    fn decrypt(&mut self, frames: &mut Vec<u8>, plaintext: &mut Vec<u8>) -> bool {
+        while let [low, high, rest @ ..] = frames.as_slice() {
+            let len = u16::from_le_bytes([*low, *high]) as usize;
+            if len > MAX_FRAME {
+                return false;
+            }
+            let Some(sealed) = rest.get(..len + 16) else {
+                break;
+            };
+            let Some(frame) = open(&self.write_key, &counter_nonce(self.write_count), &[*low, *high], sealed) else {
+                return false;
+            };
+            self.write_count += 1;
+            plaintext.extend_from_slice(&frame);
+            frames.drain(..2 + len + 16);
+        }
+        true
+    }
+
+    fn encrypt(&mut self, message: &[u8]) -> Vec<u8> {
+        let mut frames = Vec::new();
+        for chunk in message.chunks(MAX_FRAME) {
+            let len = (chunk.len() as u16).to_le_bytes();
+            frames.extend_from_slice(&len);
+            frames.extend_from_slice(&seal(&self.read_key, &counter_nonce(self.read_count), &len, chunk));
+            self.read_count += 1;
+        }
+        frames
+    }
+}
+
+impl Connection {
+    async fn send(&mut self, stream: &mut TcpStream, message: &[u8]) -> std::io::Result<()> {
+        match self.session.as_mut() {
+            Some(session) => stream.write_all(&session.encrypt(message)).await,
+            None => stream.write_all(message).await,
+        }
+    }
+
+    // Changed characteristics this connection subscribed to
+    fn events(&self, accessory: &Accessory, previous: &[DoorValues], latest: &[DoorValues]) -> Vec<Value> {
+        let mut events = Vec::new();
+        for (index, (aid, _)) in accessory.doors.iter().enumerate() {
+            for (iid, value) in latest[index].changes(&previous[index]) {
+                if self.events.contains(&(*aid, iid)) {
+                    events.push(json!({ "aid": aid, "iid": iid, "value": value }));
+                }
+            }
+        }
+        events
+    }
+}
+
+// Take the first complete request off `buffer`. Errors if it can never fit in MAX_REQUEST.
+fn parse_request(buffer: &mut Vec<u8>) -> Result<Option<Request>, ()> {
+    let Some(header_end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") else {
+        return Ok(None);
+    };
+    let head = String::from_utf8_lossy(&buffer[..header_end]).into_owned();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().ok_or(())?.split(' ');
+    let method = request_line.next().ok_or(())?.to_string();
+    let target = request_line.next().ok_or(())?;
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let content_length = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .map(|(_, value)| value.trim().parse::<usize>().map_err(|_| ()))
+        .transpose()?
+        .unwrap_or(0);
+
+    let body_start = header_end + 4;
+    let body_end = body_start
+        .checked_add(content_length)
+        .filter(|end| *end <= MAX_REQUEST)
+        .ok_or(())?;
+    if buffer.len() < body_end {
+        return Ok(None);
+    }
+    let body = buffer[body_start..body_end].to_vec();
+    let request = Request {
+        method,
+        path: path.to_string(),
+        query: query.to_string(),
+        body,
+    };
+    buffer.drain(..body_end);
+    Ok(Some(request))
+}
+
+// Answer a request. Also gives the verified controller once pair verify finishes.
+fn handle(
+    accessory: &Accessory,
+    connection: &mut Connection,
+    request: &Request,
+    values: &[DoorValues],
+) -> (Reply, Option<Verified>) {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("POST", "/pair-setup") => {
+            let reply = pairing::pair_setup(accessory, &mut connection.setup, &request.body);
+            return (Reply::tlv(reply), None);
+        }
+        ("POST", "/pair-verify") => {
+            let (reply, verified) = pairing::pair_verify(accessory, &mut connection.verify, &request.body);
+            return (Reply::tlv(reply), verified);
+        }
+        // Only allowed before anybody paired, to find the accessory
+        ("POST", "/identify") if !accessory.is_paired() => return (Reply::empty("204 No Content"), None),
+        ("POST", "/identify") => {
+            return (Reply::json("400 Bad Request", json!({ "status": STATUS_INSUFFICIENT_PRIVILEGES })), None);
+        }
+        _ => {}
+    }
+
+    let Some(controller) = connection.session.as_ref().map(|session| session.controller.clone()) else {
+        let reply = Reply::json(
+            "470 Connection Authorization Required",
+            json!({ "status": STATUS_INSUFFICIENT_PRIVILEGES }),
+        );
+        return (reply, None);
+    };
+    let reply = match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/accessories") => Reply::json("200 OK", accessory.database(values)),
+        ("GET", "/characteristics") => read_characteristics(accessory, &request.query, values),
+        ("PUT", "/characteristics") => write_characteristics(accessory, connection, &request.body),
+        ("POST", "/pairings") => Reply::tlv(pairing::pairings(accessory, &controller, &request.body)),
+        _ => Reply::empty("404 Not Found"),
+    };
+    (reply, None)
+}
+
+// `GET /characteristics?id=1.11,1.12`
+fn read_characteristics(accessory: &Accessory, query: &str, values: &[DoorValues]) -> Reply {
+    let ids = query
+        .split('&')
+        .find_map(|param| param.strip_prefix("id="))
+        .unwrap_or("");
+    let Some(ids) = ids
+        .split(',')
+        .map(|id| {
+            let (aid, iid) = id.split_once('.')?;
+            Some((aid.parse::<u64>().ok()?, iid.parse::<u64>().ok()?))
+        })
+        .collect::<Option<Vec<_>>>()
+    else {
+        return Reply::json("400 Bad Request", json!({ "status": STATUS_INVALID_VALUE }));
+    };
+
+    let results: Vec<_> = ids
+        .iter()
+        .map(|&(aid, iid)| (aid, iid, accessory.read(aid, iid, values)))
+        .collect();
+    if results.iter().all(|(_, _, result)| result.is_ok()) {
+        let characteristics: Vec<_> = results
+            .into_iter()
+            .map(|(aid, iid, value)| json!({ "aid": aid, "iid": iid, "value": value.ok() }))
+            .collect();
+        return Reply::json("200 OK", json!({ "characteristics": characteristics }));
+    }
+    // Every entry gets a status as soon as one of them failed
+    let characteristics: Vec<_> = results
+        .into_iter()
+        .map(|(aid, iid, result)| match result {
+            Ok(value) => json!({ "aid": aid, "iid": iid, "value": value, "status": 0 }),
+            Err(status) => json!({ "aid": aid, "iid": iid, "status": status }),
+        })
+        .collect();
+    Reply::json("207 Multi-Status", json!({ "characteristics": characteristics }))
+}
+
+#[derive(Deserialize)]
+struct WriteRequest {
+    characteristics: Vec<CharacteristicWrite>,
+}
+
+#[derive(Deserialize)]
+struct CharacteristicWrite {
+    aid: u64,
+    iid: u64,
+    value: Option<Value>,
+    /// Turns events on or off
+    ev: Option<bool>,
+}
+
+// `PUT /characteristics` with values to set and events to turn on or off
+fn write_characteristics(accessory: &Accessory, connection: &mut Connection, body: &[u8]) -> Reply {
+    let Ok(request) = serde_json::from_slice::<WriteRequest>(body) else {
+        return Reply::json("400 Bad Request", json!({ "status": STATUS_INVALID_VALUE }));
+    };
+
+    let mut statuses = Vec::new();
+    for write in &request.characteristics {
+        let mut result = Ok(());
+        if let Some(events) = write.ev {
+            result = accessory.notifies(write.aid, write.iid);
+            if result.is_ok() {
+                if events {
+                    connection.events.insert((write.aid, write.iid));
+                } else {
+                    connection.events.remove(&(write.aid, write.iid));
+                }
+            }
+        }
+        if let Some(value) = &write.value
+            && result.is_ok()
+        {
+            result = accessory.write(write.aid, write.iid, value);
+        }
+        statuses.push((write.aid, write.iid, result));
+    }
+
+    if statuses.iter().all(|(_, _, result)| result.is_ok()) {
+        return Reply::empty("204 No Content");
+    }
+    let characteristics: Vec<_> = statuses
+        .into_iter()
+        .map(|(aid, iid, result)| json!({ "aid": aid, "iid": iid, "status": result.err().unwrap_or(0) }))
+        .collect();
+    Reply::json("207 Multi-Status", json!({ "characteristics": characteristics }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Both ends of a verified connection, the controller's with the keys the other way around
+    fn sessions() -> (Session, Session) {
+        let accessory = Session::new(Verified {
+            controller: "controller".to_string(),
+            shared_secret: [7; 32],
+        });
+        let controller = Session {
+            controller: String::new(),
+            read_key: accessory.write_key,
+            read_count: 0,
+            write_key: accessory.read_key,
+            write_count: 0,
+        };
+        (accessory, controller)
+    }
+
+    #[test]
+    fn round_trips_frames() {
+        let (mut accessory, mut controller) = sessions();
+        let message: Vec<u8> = (0..2500).map(|i| i as u8).collect();
+        let mut frames = accessory.encrypt(&message);
+        // Three frames, each with a length and a tag
+        assert_eq!(frames.len(), message.len() + 3 * (2 + 16));
+
+        // Frames may arrive in pieces
+        let rest = frames.split_off(1100);
+        let mut plaintext = Vec::new();
+        assert!(controller.decrypt(&mut frames, &mut plaintext));
+        assert_eq!(plaintext.len(), MAX_FRAME);
+        frames.extend_from_slice(&rest);
+        assert!(controller.decrypt(&mut frames, &mut plaintext));
+        assert!(frames.is_empty());
+        assert_eq!(plaintext, message);
+
+        // The counters move on, so the next message still decrypts
+        let mut frames = accessory.encrypt(b"again");
+        let mut plaintext = Vec::new();
+        assert!(controller.decrypt(&mut frames, &mut plaintext));
+        assert_eq!(plaintext, b"again");
+    }
+
+    #[test]
+    fn rejects_tampered_and_replayed_frames() {
+        let (mut accessory, mut controller) = sessions();
+        let frames = accessory.encrypt(b"GET /accessories HTTP/1.1\r\n\r\n");
+
+        let mut tampered = frames.clone();
+        tampered[4] ^= 1;
+        assert!(!controller.decrypt(&mut tampered, &mut Vec::new()));
+
+        assert!(controller.decrypt(&mut frames.clone(), &mut Vec::new()));
+        assert!(!controller.decrypt(&mut frames.clone(), &mut Vec::new()));
+    }
+
+    #[test]
+    fn parses_requests_once_the_body_is_in() {
+        let mut buffer = b"POST /pair-setup HTTP/1.1\r\nContent-Length: 3\r\n\r\nab".to_vec();
+        assert!(matches!(parse_request(&mut buffer), Ok(None)));
+        buffer.extend_from_slice(b"cGET");
+        let request = parse_request(&mut buffer).unwrap().unwrap();
+        assert_eq!((request.method.as_str(), request.path.as_str()), ("POST", "/pair-setup"));
+        assert_eq!(request.body, b"abc");
+        assert_eq!(buffer, b"GET");
+    }
+
+    #[test]
+    fn rejects_bodies_over_the_request_limit() {
+        for length in [(MAX_REQUEST + 1).to_string(), usize::MAX.to_string(), "-1".to_string()] {
+            let mut buffer = format!("PUT /characteristics HTTP/1.1\r\nContent-Length: {length}\r\n\r\n").into_bytes();
+            assert!(parse_request(&mut buffer).is_err());
+        }
+    }
+}
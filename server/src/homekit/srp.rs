@@ -0,0 +1,237 @@
+// SRP-6a with SHA-512 and the 3072-bit group from RFC 5054, the flavour pair setup uses
+use std::marker::PhantomData;
+use num_bigint::BigUint;
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha512};
+use subtle::ConstantTimeEq;
+
+const N_HEX: &str = concat!(
+    "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74",
+    "020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F1437",
+    "4FE1356D6D51C245E485B576625E7EC6F44C42E9A637ED6B0BFF5CB6F406B7ED",
+    "EE386BFB5A899FA5AE9F24117C4B1FE649286651ECE45B3DC2007CB8A163BF05",
+    "98DA48361C55D39A69163FA8FD24CF5F83655D23DCA3AD961C62F356208552BB",
+    "9ED529077096966D670C354E4ABC9804F1746C08CA18217C32905E462E36CE3B",
+    "E39E772C180E86039B2783A2EC07A28FB5C55DF06F4C52C9DE2BCBF695581718",
+    "3995497CEA956AE515D2261898FA051015728E5A8AAAC42DAD33170D04507A33",
+    "A85521ABDF1CBA64ECFB850458DBEF0A8AEA71575D060C7DB3970F85A6E1E4C7",
+    "ABF5AE8CDB0933D71E8C94E04A25619DCEE3D2261AD2EE6BF12FFA06D98A0864",
+    "D87602733EC86A64521F2B18177B200CBBE117577A615D6C770988C0BAD946E2",
+    "08E24FA074E5AB3143DB5BFCE0FD108E4B82D120A93AD2CAFFFFFFFFFFFFFFFF",
+);
+const G: u32 = 5;
+const USERNAME: &[u8] = b"Pair-Setup";
+
+/// Server side of one pair setup attempt. The hash is only swapped out to check the RFC 5054 test vector.
+pub struct SrpServer<D = Sha512> {
+    n: BigUint,
+    g: BigUint,
+    username: &'static [u8],
+    salt: [u8; 16],
+    verifier: BigUint,
+    b: BigUint,
+    public_b: BigUint,
+    digest: PhantomData<D>,
+}
+
+impl SrpServer {
+    pub fn new(setup_code: &str) -> Self {
+        let n = BigUint::parse_bytes(N_HEX.as_bytes(), 16).expect("N is valid hex");
+        let mut salt = [0; 16];
+        OsRng.fill_bytes(&mut salt);
+        let mut b = [0; 32];
+        OsRng.fill_bytes(&mut b);
+        Self::with_secrets(n, BigUint::from(G), USERNAME, setup_code.as_bytes(), salt, &b)
+    }
+}
+
+impl<D: Digest> SrpServer<D> {
+    fn with_secrets(n: BigUint, g: BigUint, username: &'static [u8], password: &[u8], salt: [u8; 16], b: &[u8]) -> Self {
+        let b = BigUint::from_bytes_be(b);
+        let inner = D::new()
+            .chain_update(username)
+            .chain_update(b":")
+            .chain_update(password)
+            .finalize();
+        let x = BigUint::from_bytes_be(&D::new().chain_update(salt).chain_update(inner).finalize());
+        let verifier = g.modpow(&x, &n);
+
+        let k = BigUint::from_bytes_be(&D::new().chain_update(n.to_bytes_be()).chain_update(pad(&n, &g)).finalize());
+        let public_b = (k * &verifier + g.modpow(&b, &n)) % &n;
+
+        Self {
+            n,
+            g,
+            username,
+            salt,
+            verifier,
+            b,
+            public_b,
+            digest: PhantomData,
+        }
+    }
+
+    pub fn salt(&self) -> &[u8] {
+        &self.salt
+    }
+
+    /// B, padded to the length of N like controllers expect
+    pub fn public_key(&self) -> Vec<u8> {
+        pad(&self.n, &self.public_b)
+    }
+
+    /// Check the controller's proof. Gives the shared session key and our own proof if it matches.
+    pub fn verify(&self, public_a: &[u8], proof: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+        let a = BigUint::from_bytes_be(public_a);
+        if (&a % &self.n) == BigUint::ZERO {
+            return None;
+        }
+        let u = BigUint::from_bytes_be(
+            &D::new()
+                .chain_update(pad(&self.n, &a))
+                .chain_update(pad(&self.n, &self.public_b))
+                .finalize(),
+        );
+        let s = (a * self.verifier.modpow(&u, &self.n)).modpow(&self.b, &self.n);
+        let key = D::digest(s.to_bytes_be()).to_vec();
+
+        let hash_n = D::digest(self.n.to_bytes_be());
+        let hash_g = D::digest(self.g.to_bytes_be());
+        let hash_ng: Vec<u8> = hash_n.iter().zip(hash_g.iter()).map(|(n, g)| n ^ g).collect();
+        let expected = D::new()
+            .chain_update(hash_ng)
+            .chain_update(D::digest(self.username))
+            .chain_update(self.salt)
+            .chain_update(public_a)
+            .chain_update(self.public_key())
+            .chain_update(&key)
+            .finalize();
+        // Constant time, so the timing doesn't tell how much of a guessed proof was right
+        if !bool::from(expected.as_slice().ct_eq(proof)) {
+            return None;
+        }
+
+        let server_proof = D::new()
+            .chain_update(public_a)
+            .chain_update(proof)
+            .chain_update(&key)
+            .finalize()
+            .to_vec();
+        Some((key, server_proof))
+    }
+}
+
+// Left pad to the length of N
+fn pad(n: &BigUint, value: &BigUint) -> Vec<u8> {
+    let len = n.to_bytes_be().len();
+    let bytes = value.to_bytes_be();
+    let mut padded = vec![0; len.saturating_sub(bytes.len())];
+    padded.extend_from_slice(&bytes);
+    padded
+}
+
+#[cfg(test)]
+mod tests {
+    use sha1::Sha1;
+    use super::*;
+
+    // The 1024-bit group and the values from RFC 5054 appendix B
+    const RFC_N: &str = concat!(
+        "EEAF0AB9ADB38DD69C33F80AFA8FC5E86072618775FF3C0B9EA2314C9C256576",
+        "D674DF7496EA81D3383B4813D692C6E0E0D5D8E250B98BE48E495C1D6089DAD1",
+        "5DC7D7B46154D6B6CE8EF4AD69B15D4982559B297BCF1885C529F566660E57EC",
+        "68EDBC3C05726CC02FD4CBF4976EAA9AFD5138FE8376435B9FC61D2FC0EB06E3",
+    );
+    const RFC_SALT: &str = "BEB25379D1A8581EB5A727673A2441EE";
+    const RFC_V: &str = concat!(
+        "7E273DE8696FFC4F4E337D05B4B375BEB0DDE1569E8FA00A9886D8129BADA1F1",
+        "822223CA1A605B530E379BA4729FDC59F105B4787E5186F5C671085A1447B52A",
+        "48CF1970B4FB6F8400BBF4CEBFBB168152E08AB5EA53D15C1AFF87B2B9DA6E04",
+        "E058AD51CC72BFC9033B564E26480D78E955A5E29E7AB245DB2BE315E2099AFB",
+    );
+    const RFC_B_SECRET: &str = "E487CB59D31AC550471E81F00F6928E01DDA08E974A004F49E61F5D105284D20";
+    const RFC_B: &str = concat!(
+        "BD0C61512C692C0CB6D041FA01BB152D4916A1E77AF46AE105393011BAF38964",
+        "DC46A0670DD125B95A981652236F99D9B681CBF87837EC996C6DA04453728610",
+        "D0C6DDB58B318885D7D82C7F8DEB75CE7BD4FBAA37089E6F9C6059F388838E7A",
+        "00030B331EB76840910440B1B27AAEAEEB4012B7D7665238A8E3FB004B117B58",
+    );
+    const RFC_A: &str = concat!(
+        "61D5E490F6F1B79547B0704C436F523DD0E560F0C64115BB72557EC44352E890",
+        "3211C04692272D8B2D1A5358A2CF1B6E0BFCF99F921530EC8E39356179EAE45E",
+        "42BA92AEACED825171E1E8B9AF6D9C03E1327F44BE087EF06530E69F66615261",
+        "EEF54073CA11CF5858F0EDFDFE15EFEAB349EF5D76988A3672FAC47B0769447B",
+    );
+    const RFC_S: &str = concat!(
+        "B0DC82BABCF30674AE450C0287745E7990A3381F63B387AAF271A10D233861E3",
+        "59B48220F7C4693C9AE12B0A6F67809F0876E2D013800D6C41BB59B6D5979B5C",
+        "00A172B4A2A5903A0BDCAF8A709585EB2AFAFA8F3499B200210DCC1F10EB3394",
+        "3CD67FC88A2F39A4BE5BEC4EC0A3212DC346D7E474B29EDE8A469FFECA686E5A",
+    );
+
+    fn hex(hex: &str) -> Vec<u8> {
+        BigUint::parse_bytes(hex.as_bytes(), 16).unwrap().to_bytes_be()
+    }
+
+    fn rfc_server() -> SrpServer<Sha1> {
+        let n = BigUint::parse_bytes(RFC_N.as_bytes(), 16).unwrap();
+        let salt = hex(RFC_SALT).try_into().unwrap();
+        SrpServer::with_secrets(n, BigUint::from(2u32), b"alice", b"password123", salt, &hex(RFC_B_SECRET))
+    }
+
+    // M1 as the controller works it out, from the premaster secret in the RFC
+    fn client_proof(server: &SrpServer<Sha1>, public_a: &[u8]) -> Vec<u8> {
+        let key = Sha1::digest(hex(RFC_S));
+        let hash_ng: Vec<u8> = Sha1::digest(hex(RFC_N))
+            .iter()
+            .zip(Sha1::digest([2]).iter())
+            .map(|(n, g)| n ^ g)
+            .collect();
+        Sha1::new()
+            .chain_update(hash_ng)
+            .chain_update(Sha1::digest(b"alice"))
+            .chain_update(hex(RFC_SALT))
+            .chain_update(public_a)
+            .chain_update(server.public_key())
+            .chain_update(key)
+            .finalize()
+            .to_vec()
+    }
+
+    #[test]
+    fn matches_the_rfc_5054_test_vector() {
+        let server = rfc_server();
+        assert_eq!(server.verifier.to_bytes_be(), hex(RFC_V));
+        assert_eq!(server.public_key(), hex(RFC_B));
+
+        let public_a = hex(RFC_A);
+        let proof = client_proof(&server, &public_a);
+        let (key, server_proof) = server.verify(&public_a, &proof).expect("The proof matches");
+        assert_eq!(key, Sha1::digest(hex(RFC_S)).to_vec());
+        let expected: Vec<u8> = Sha1::new().chain_update(&public_a).chain_update(&proof).chain_update(&key).finalize().to_vec();
+        assert_eq!(server_proof, expected);
+    }
+
+    #[test]
+    fn rejects_a_wrong_proof() {
+        let server = rfc_server();
+        let public_a = hex(RFC_A);
+        let mut proof = client_proof(&server, &public_a);
+        proof[0] ^= 1;
+        assert!(server.verify(&public_a, &proof).is_none());
+        assert!(server.verify(&public_a, &proof[1..]).is_none());
+    }
+
+    #[test]
+    fn rejects_a_public_key_that_is_a_multiple_of_n() {
+        let server = rfc_server();
+        assert!(server.verify(&[0], &[0; 20]).is_none());
+        assert!(server.verify(&hex(RFC_N), &[0; 20]).is_none());
+    }
+
+    #[test]
+    fn pads_b_to_the_length_of_n() {
+        let server = SrpServer::new("031-45-154");
+        assert_eq!(server.public_key().len(), 384);
+    }
+}
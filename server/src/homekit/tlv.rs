@@ -0,0 +1,139 @@
+// TLV8 as used by the pairing endpoints: one byte type, one byte length, then the value.
+// Values longer than 255 bytes are split over several items of the same type.
+
+pub const METHOD: u8 = 0x00;
+pub const IDENTIFIER: u8 = 0x01;
+pub const SALT: u8 = 0x02;
+pub const PUBLIC_KEY: u8 = 0x03;
+pub const PROOF: u8 = 0x04;
+pub const ENCRYPTED_DATA: u8 = 0x05;
+pub const STATE: u8 = 0x06;
+pub const ERROR: u8 = 0x07;
+pub const SIGNATURE: u8 = 0x0a;
+pub const PERMISSIONS: u8 = 0x0b;
+pub const SEPARATOR: u8 = 0xff;
+
+pub const ERROR_UNKNOWN: u8 = 0x01;
+pub const ERROR_AUTHENTICATION: u8 = 0x02;
+pub const ERROR_MAX_PEERS: u8 = 0x04;
+pub const ERROR_MAX_TRIES: u8 = 0x05;
+pub const ERROR_UNAVAILABLE: u8 = 0x06;
+
+// Values of the METHOD item sent to /pairings
+pub const METHOD_ADD_PAIRING: u8 = 3;
+pub const METHOD_REMOVE_PAIRING: u8 = 4;
+pub const METHOD_LIST_PAIRINGS: u8 = 5;
+
+/// Decoded items in the order they were sent
+#[derive(Debug, Default)]
+pub struct Tlv {
+    items: Vec<(u8, Vec<u8>)>,
+}
+
+impl Tlv {
+    pub fn decode(mut bytes: &[u8]) -> Option<Self> {
+        let mut items: Vec<(u8, Vec<u8>)> = Vec::new();
+        // Only a full length item can be continued by the next one
+        let mut continues = false;
+        while let [kind, len, rest @ ..] = bytes {
+            let len = *len as usize;
+            let value = rest.get(..len)?;
+            match items.last_mut() {
+                Some((last, existing)) if continues && last == kind => existing.extend_from_slice(value),
+                _ => items.push((*kind, value.to_vec())),
+            }
+            continues = len == 255;
+            bytes = &rest[len..];
+        }
+        bytes.is_empty().then_some(Self { items })
+    }
+
+    pub fn get(&self, kind: u8) -> Option<&[u8]> {
+        self.items.iter().find(|(k, _)| *k == kind).map(|(_, value)| value.as_slice())
+    }
+
+    pub fn byte(&self, kind: u8) -> Option<u8> {
+        match self.get(kind)? {
+            [byte] => Some(*byte),
+            _ => None,
+        }
+    }
+
+    pub fn push(mut self, kind: u8, value: impl AsRef<[u8]>) -> Self {
+        self.items.push((kind, value.as_ref().to_vec()));
+        self
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for (kind, value) in &self.items {
+            if value.is_empty() {
+                bytes.extend_from_slice(&[*kind, 0]);
+            }
+            for chunk in value.chunks(255) {
+                bytes.extend_from_slice(&[*kind, chunk.len() as u8]);
+                bytes.extend_from_slice(chunk);
+            }
+        }
+        bytes
+    }
+}
+
+/// Reply for the given pairing step that only carries an error
+pub fn error(state: u8, error: u8) -> Vec<u8> {
+    Tlv::default().push(STATE, [state]).push(ERROR, [error]).encode()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_short_values() {
+        let bytes = Tlv::default().push(STATE, [3]).push(IDENTIFIER, b"controller").push(PROOF, []).encode();
+        assert_eq!(bytes[..3], [STATE, 1, 3]);
+        let tlv = Tlv::decode(&bytes).unwrap();
+        assert_eq!(tlv.byte(STATE), Some(3));
+        assert_eq!(tlv.get(IDENTIFIER), Some(&b"controller"[..]));
+        assert_eq!(tlv.get(PROOF), Some(&[][..]));
+        assert_eq!(tlv.get(SALT), None);
+        assert_eq!(tlv.encode(), bytes);
+    }
+
+    #[test]
+    fn fragments_values_over_255_bytes() {
+        let key: Vec<u8> = (0..384).map(|i| i as u8).collect();
+        let bytes = Tlv::default().push(PUBLIC_KEY, &key).push(STATE, [2]).encode();
+        assert_eq!(bytes.len(), 2 + 255 + 2 + 129 + 3);
+        assert_eq!(bytes[..2], [PUBLIC_KEY, 255]);
+        assert_eq!(bytes[257..259], [PUBLIC_KEY, 129]);
+
+        let tlv = Tlv::decode(&bytes).unwrap();
+        assert_eq!(tlv.get(PUBLIC_KEY), Some(key.as_slice()));
+        assert_eq!(tlv.byte(STATE), Some(2));
+        assert_eq!(tlv.encode(), bytes);
+    }
+
+    #[test]
+    fn keeps_items_apart_after_a_short_one() {
+        let bytes = Tlv::default()
+            .push(IDENTIFIER, b"first")
+            .push(SEPARATOR, [])
+            .push(IDENTIFIER, b"second")
+            .encode();
+        let tlv = Tlv::decode(&bytes).unwrap();
+        assert_eq!(tlv.get(IDENTIFIER), Some(&b"first"[..]));
+        assert_eq!(tlv.encode(), bytes);
+
+        // Without the separator a short item still isn't continued
+        let tlv = Tlv::decode(&[IDENTIFIER, 1, b'a', IDENTIFIER, 1, b'b']).unwrap();
+        assert_eq!(tlv.get(IDENTIFIER), Some(&b"a"[..]));
+    }
+
+    #[test]
+    fn rejects_truncated_items() {
+        assert!(Tlv::decode(&[STATE, 2, 1]).is_none());
+        assert!(Tlv::decode(&[STATE, 1, 1, PROOF]).is_none());
+        assert!(Tlv::decode(&[]).is_some());
+    }
+}
@@ -0,0 +1,177 @@
+use std::time::{Duration, Instant};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+/// Glitch filter for a limit switch input. A new level is only accepted once
+/// it has been read `samples` times in a row and has held for `stable_time`.
+#[derive(Debug, Clone)]
+pub struct InputFilter {
+    samples: u32,
+    stable_time: Duration,
+    level: Option<bool>,
+    // How many reads in a row disagreed with `level`, and since when
+    pending: Option<(u32, Instant)>,
+    glitches: u64,
+}
+
+impl InputFilter {
+    pub fn new(samples: u32, stable_time: Duration) -> Self {
+        Self {
+            samples,
+            stable_time,
+            level: None,
+            pending: None,
+            glitches: 0,
+        }
+    }
+
+    /// A filter that lets every reading through
+    pub fn passthrough() -> Self {
+        Self::new(1, Duration::ZERO)
+    }
+
+    /// Feed a raw reading and get the filtered level back
+    pub fn update(&mut self, raw: bool, now: Instant) -> bool {
+        // Trust the very first reading so startup isn't delayed
+        let Some(level) = self.level else {
+            self.level = Some(raw);
+            return raw;
+        };
+
+        if raw == level {
+            if self.pending.take().is_some() {
+                self.glitches += 1;
+            }
+            return level;
+        }
+
+        let (count, since) = match self.pending {
+            Some((count, since)) => (count + 1, since),
+            None => (1, now),
+        };
+        if count >= self.samples && now.duration_since(since) >= self.stable_time {
+            self.level = Some(raw);
+            self.pending = None;
+            raw
+        } else {
+            self.pending = Some((count, since));
+            level
+        }
+    }
+
+    /// A level change is waiting to be confirmed by more readings
+    pub fn is_settling(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    pub fn level(&self) -> Option<bool> {
+        self.level
+    }
+
+    /// How many level changes were thrown away for not lasting long enough
+    pub fn glitches(&self) -> u64 {
+        self.glitches
+    }
+}
+
+/// Raw and filtered readings of both limit switches, for diagnostics
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, ToSchema)]
+pub struct InputDiagnostics {
+    pub close_limit: InputLevels,
+    pub open_limit: InputLevels,
+}
+
+/// Levels are true while the switch is pressed. Raw is None if the pin could not be read.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, ToSchema)]
+pub struct InputLevels {
+    pub raw: Option<bool>,
+    pub filtered: Option<bool>,
+    pub glitches: u64,
+}
+
+impl InputLevels {
+    pub fn new(raw: Option<bool>, filter: &InputFilter) -> Self {
+        Self {
+            raw,
+            filtered: filter.level(),
+            glitches: filter.glitches(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Feed (raw reading, milliseconds since the first reading) pairs and collect the filtered levels
+    fn run(filter: &mut InputFilter, start: Instant, readings: &[(bool, u64)]) -> Vec<bool> {
+        readings
+            .iter()
+            .map(|&(raw, ms)| filter.update(raw, start + Duration::from_millis(ms)))
+            .collect()
+    }
+
+    #[test]
+    fn passthrough_follows_every_reading() {
+        let mut filter = InputFilter::passthrough();
+        let readings = [(false, 0), (true, 1), (false, 2), (false, 3), (true, 4)];
+        assert_eq!(run(&mut filter, Instant::now(), &readings), [false, true, false, false, true]);
+        assert_eq!(filter.glitches(), 0);
+        assert!(!filter.is_settling());
+    }
+
+    #[test]
+    fn trusts_the_first_reading() {
+        let mut filter = InputFilter::new(3, Duration::from_secs(1));
+        assert_eq!(filter.level(), None);
+        assert!(filter.update(true, Instant::now()));
+        assert_eq!(filter.level(), Some(true));
+    }
+
+    #[test]
+    fn waits_for_enough_samples() {
+        let mut filter = InputFilter::new(3, Duration::ZERO);
+        let readings = [(false, 0), (true, 10), (true, 20), (true, 30), (true, 40)];
+        assert_eq!(run(&mut filter, Instant::now(), &readings), [false, false, false, true, true]);
+        assert!(!filter.is_settling());
+        assert_eq!(filter.glitches(), 0);
+    }
+
+    #[test]
+    fn waits_for_the_level_to_hold() {
+        let mut filter = InputFilter::new(1, Duration::from_millis(50));
+        let readings = [(false, 0), (true, 10), (true, 40), (true, 59), (true, 60)];
+        assert_eq!(run(&mut filter, Instant::now(), &readings), [false, false, false, false, true]);
+    }
+
+    #[test]
+    fn needs_both_samples_and_time() {
+        let mut filter = InputFilter::new(3, Duration::from_millis(50));
+        let start = Instant::now();
+        // Enough time but only two samples
+        assert_eq!(run(&mut filter, start, &[(false, 0), (true, 10), (true, 100)]), [false, false, false]);
+        assert!(filter.is_settling());
+        // The third sample is in, so the change holds
+        assert_eq!(run(&mut filter, start, &[(true, 110)]), [true]);
+
+        // Enough samples but not enough time
+        let mut filter = InputFilter::new(3, Duration::from_millis(50));
+        let readings = [(false, 0), (true, 10), (true, 20), (true, 30), (true, 59), (true, 60)];
+        assert_eq!(run(&mut filter, start, &readings), [false, false, false, false, false, true]);
+    }
+
+    #[test]
+    fn counts_changes_that_do_not_last() {
+        let mut filter = InputFilter::new(3, Duration::ZERO);
+        let start = Instant::now();
+        let readings = [(false, 0), (true, 10), (false, 20), (true, 30), (true, 40), (false, 50), (false, 60)];
+        assert_eq!(run(&mut filter, start, &readings), [false; 7]);
+        assert_eq!(filter.glitches(), 2);
+        assert!(!filter.is_settling());
+
+        // A change that goes through isn't a glitch
+        let readings = [(true, 70), (true, 80), (true, 90)];
+        assert_eq!(run(&mut filter, start, &readings), [false, false, true]);
+        assert_eq!(filter.glitches(), 2);
+    }
+}
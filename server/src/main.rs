@@ -1,80 +1,60 @@
+use alerts::Alerts;
+use auto_close::{AutoCloseHandle, AutoCloseStatus};
 use axum::{
-    extract::{FromRequestParts, State}, http::{request::Parts, StatusCode}, response::{sse::Event, IntoResponse, Response, Sse}, routing::{get, post}, Json, RequestPartsExt, Router
+    extract::{FromRequestParts, Path, Query, State}, http::{header, request::Parts, HeaderValue, StatusCode}, middleware, response::{sse::Event, Response, Sse}, routing::{delete, get, post}, Json, RequestPartsExt, Router
 };
-use axum_extra::{headers::{authorization::Bearer, Authorization}, TypedHeader};
-use std::{collections::VecDeque, sync::{Arc, Mutex}};
+use axum_extra::{extract::WithRejection, headers::{authorization::Bearer, Authorization}, TypedHeader};
+use chrono::{DateTime, Local};
 use futures::stream::Stream;
-use std::{
-    error::Error,
-    thread,
-    time::{Duration, Instant},
-};
-use tokio::sync::watch;
-use serde::Serialize;
-use embedded_hal::digital::{InputPin, OutputPin, PinState};
-use config::AppConfig;
+use std::{collections::HashMap, error::Error, time::{Duration, Instant}};
+use tokio::sync::{broadcast::error::RecvError, watch};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use config::{AppConfig, ScheduleAction, ScheduleCondition, ScheduleConfig};
+use calibration::TravelCalibration;
+use controller::{CommandId, CommandPhase, CommandProgress, CloseRetry, DoorSetpoint, DoorState, DoorStatus, GpioCommand, Warning};
+use commands::CommandSource;
+use door::{DoorHandle, Doors};
+use error::{ApiError, ErrorCode};
+use history::{EventType, History, HistoryFilter, HistoryPage};
+use input_filter::InputDiagnostics;
+use metrics::Metrics;
+use schedule::{RunOutcome, ScheduleId, ScheduleRun, ScheduledAction, Scheduler};
+use webhooks::{Delivery, DeliveryId, DeliveryOutcome, Webhooks};
 
 mod gpio;
 mod config;
-
-#[derive(Debug, Clone, Copy, PartialEq)]
-struct DoorState {
-    status: DoorStatus,
-    setpoint: DoorSetpoint,
-    position: f64,
-}
-
-// State tracking and GPIO command enums
-#[derive(Debug, Clone, Copy, PartialEq)]
-enum DoorStatus {
-    Closed,
-    Open,
-    Ajar,
-    MovingUp,
-    MovingDown,
-}
-
-impl DoorStatus {
-    fn value(&self) -> &'static str {
-        match self {
-            DoorStatus::Closed => "closed",
-            DoorStatus::Open => "open",
-            DoorStatus::Ajar => "ajar",
-            DoorStatus::MovingUp => "moving_up",
-            DoorStatus::MovingDown => "moving_down",
-        }
-    }
-}
-
-#[derive(Debug, Clone, Copy, PartialEq)]
-enum DoorSetpoint {
-    Closed,
-    Open,
-    Ajar,
-}
-
-impl DoorSetpoint {
-    fn value(&self) -> &'static str {
-        match self {
-            DoorSetpoint::Closed => "closed",
-            DoorSetpoint::Open => "open",
-            DoorSetpoint::Ajar => "ajar",
-        }
-    }
-}
-
-#[derive(Debug)]
-enum GpioCommand {
-    Toggle,
-    Open,
-    Close,
-}
+mod alerts;
+mod auto_close;
+mod calibration;
+mod commands;
+mod controller;
+mod coupler;
+mod door;
+mod driver;
+mod error;
+mod history;
+#[cfg(feature = "homekit")]
+mod homekit;
+mod state_file;
+mod input_filter;
+mod metrics;
+mod mqtt;
+mod notifiers;
+mod openapi;
+mod schedule;
+mod webhooks;
+mod ws;
 
 // Application state for Axum
 #[derive(Debug, Clone)]
 struct AppState {
-    door_state: watch::Sender<DoorState>,
-    latest_command: Arc<Mutex<Option<GpioCommand>>>,
+    doors: Doors,
+    scheduler: Scheduler,
+    metrics: Metrics,
+    history: Option<History>,
+    webhooks: Webhooks,
+    alerts: Alerts,
 }
 
 struct Authenticated;
@@ -83,13 +63,13 @@ impl<S> FromRequestParts<S> for Authenticated
 where
     S: Send + Sync,
 {
-    type Rejection = Response;
+    type Rejection = ApiError;
 
     async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
         let TypedHeader(Authorization(bearer)) = parts
             .extract::<TypedHeader<Authorization<Bearer>>>()
             .await
-            .map_err(|_| (StatusCode::UNAUTHORIZED, "Missing authorization header").into_response())?;
+            .map_err(|_| ApiError::new(ErrorCode::MissingAuthorization, "Missing authorization header"))?;
 
         let config = parts
             .extensions
@@ -97,447 +77,1120 @@ where
             .expect("AppConfig missing in extensions");
 
         if bearer.token() != config.garage_door.api_key {
-            return Err((StatusCode::UNAUTHORIZED, "Invalid API key").into_response());
+            return Err(ApiError::new(ErrorCode::InvalidApiKey, "Invalid API key"));
         }
 
         Ok(Authenticated)
     }
 }
 
+/// The door from the `/doors/{door}/...` path, or the default door for the plain routes
+struct SelectedDoor(DoorHandle);
+
+impl FromRequestParts<AppState> for SelectedDoor {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let id = parts
+            .extract::<Path<HashMap<String, String>>>()
+            .await
+            .ok()
+            .and_then(|Path(mut params)| params.remove("door"));
+
+        match id {
+            Some(id) => state
+                .doors
+                .get(&id)
+                .cloned()
+                .map(SelectedDoor)
+                .ok_or_else(|| ApiError::new(ErrorCode::UnknownDoor, format!("Unknown door: {}", id))),
+            None => Ok(SelectedDoor(state.doors.default_door().clone())),
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     // Load configuration
     let config = config::load_config()?;
 
-    // Convert config durations
-    let poll_interval = Duration::from_millis(config.garage_door.poll_interval_ms);
-    let expected_shut_time = Duration::from_secs(config.garage_door.expected_shut_time_sec);
-    let _shut_time_buffer = Duration::from_secs(config.garage_door.shut_time_buffer_sec);
-    let limit_cooldown = Duration::from_millis(config.garage_door.limit_cooldown_ms);
-
-    // Initialize GPIO components with config values
-    let (close_limit_switch, open_limit_switch, coupler) = gpio::create_pins(
-        config.garage_door.close_limit_pin,
-        config.garage_door.open_limit_pin,
-        config.garage_door.coupler_pin,
-        poll_interval,
-        expected_shut_time
-    )?;
-
-    // Create communication channels
-    let (door_state_tx, _) = watch::channel(DoorState{
-        status: DoorStatus::Ajar,
-        setpoint: DoorSetpoint::Ajar,
-        position: 0_f64,
-    });
-    let app_state = AppState {
-        door_state: door_state_tx.clone(),
-        latest_command: Arc::new(Mutex::new(None)),
+    let metrics = Metrics::new();
+    let history = config.history.as_ref().map(History::open).transpose()?;
+    let mut doors = Vec::new();
+    for (id, door) in config.doors() {
+        if doors.iter().any(|existing: &DoorHandle| existing.id == id) {
+            return Err(format!("Door ID {} is used more than once", id).into());
+        }
+        doors.push(door::start(
+            id,
+            door,
+            config.auto_close.as_ref(),
+            metrics.door(id),
+            history.as_ref().map(|history| history.recorder(id)),
+        )?);
+    }
+    let doors = Doors::new(doors);
+
+    let mqtt = config.mqtt.as_ref().map(|mqtt| mqtt::spawn(mqtt, &doors));
+    #[cfg(feature = "homekit")]
+    let homekit = match &config.homekit {
+        Some(homekit) => Some(homekit::spawn(homekit, &doors).await?),
+        None => None,
     };
+    #[cfg(not(feature = "homekit"))]
+    if config.homekit.is_some() {
+        eprintln!("Ignoring the [homekit] config, the server was built without the homekit feature");
+    }
 
-    monitor_gpio(
-        close_limit_switch,
-        open_limit_switch,
-        coupler,
-        door_state_tx,
-        app_state.latest_command.clone(),
-        poll_interval,
-        expected_shut_time,
-        config.garage_door.coupler_active_low,
-        config.garage_door.coupler_active_intervals,
-        config.garage_door.coupler_rest_intervals,
-        limit_cooldown,
-    );
+    let webhooks = Webhooks::spawn(&config.webhooks, &doors)?;
+    let alerts = Alerts::spawn(&config.alerts, &config.notifiers, &doors, webhooks.clone())?;
 
-    let app = Router::new()
+    let scheduler = Scheduler::new(&config.schedules, doors.clone())?;
+    scheduler.spawn();
+    let app_state = AppState {
+        doors: doors.clone(),
+        scheduler,
+        metrics: metrics.clone(),
+        history,
+        webhooks,
+        alerts,
+    };
+
+    // Routes for a single door. The plain ones act on the default door.
+    let door_routes = Router::new()
         .route("/watch-status", get(watch_status_handler))
         .route("/status", get(current_status_handler))
         .route("/toggle", post(toggle_door))
         .route("/open", post(open_door))
         .route("/close", post(close_door))
+        .route("/stop", post(stop_door))
+        .route("/ws", get(ws::ws_handler))
+        .route("/position", post(position_door))
+        .route("/commands/{id}", get(command_status_handler))
+        .route("/calibration", get(calibration_handler))
+        .route("/diagnostics/inputs", get(inputs_handler))
+        .route("/auto-close/cancel", post(cancel_auto_close))
+        .route("/warning/cancel", post(cancel_warning));
+
+    let api = Router::new()
+        .merge(door_routes.clone())
+        .nest("/doors/{door}", door_routes)
+        .route("/doors", get(doors_handler))
+        .route("/schedules", get(list_schedules).post(add_schedule))
+        .route("/schedules/runs", get(schedule_runs))
+        .route("/schedules/{id}", delete(remove_schedule))
+        .route("/schedules/{id}/skip", post(skip_schedule).delete(unskip_schedule))
+        .route("/history", get(history_handler))
+        .route("/webhooks/deliveries", get(webhook_deliveries))
+        .route("/notifiers/{name}/test", post(test_notifier));
+
+    // The unversioned paths are kept for older clients
+    let app = Router::new()
+        .route("/openapi.json", get(openapi_handler))
+        .route("/metrics", get(metrics_handler))
+        .nest("/api/v1", api.clone().fallback(unknown_route).method_not_allowed_fallback(method_not_allowed))
+        .merge(api.layer(middleware::map_response(deprecated)))
         .with_state(app_state)
+        .layer(middleware::from_fn_with_state(metrics, metrics::track_requests))
         .layer(axum::Extension(config.clone()));
 
     let listener = tokio::net::TcpListener::bind(&config.garage_door.server_address).await?;
-    axum::serve(listener, app).await?;
+    tokio::select! {
+        result = axum::serve(listener, app) => result?,
+        _ = shutdown_signal() => {}
+    }
 
-    Ok(())
-}
+    if let Some(mqtt) = mqtt {
+        mqtt.shutdown(Duration::from_secs(1)).await;
+    }
+    #[cfg(feature = "homekit")]
+    if let Some(homekit) = homekit {
+        homekit.shutdown(Duration::from_secs(1)).await;
+    }
 
-// GPIO monitoring and control thread
-// Modify monitor_gpio to use generic types
-fn monitor_gpio<I1, I2, O>(
-    mut close_limit: I1,
-    mut open_limit: I2,
-    mut coupler: O,
-    state_tx: watch::Sender<DoorState>,
-    latest_command: Arc<Mutex<Option<GpioCommand>>>,
-    poll_interval: Duration,
-    expected_shut_time: Duration,
-    coupler_active_low: bool,
-    coupler_active_intervals: u64,
-    coupler_rest_intervals: u64,
-    limit_cooldown: Duration,
-) where
-    I1: InputPin + Send + 'static,
-    I2: InputPin + Send + 'static,
-    O: OutputPin + Send + 'static,
-{
-    thread::spawn(move || {
-        let close_triggered = close_limit.is_low().unwrap_or(false);
-        let open_triggered = open_limit.is_low().unwrap_or(false);
-
-        let mut last_state = DoorState {
-            status: match (close_triggered, open_triggered) {
-                (true, false) => DoorStatus::Closed,
-                (false, true) => DoorStatus::Open,
-                _ => DoorStatus::Ajar,
-            },
-            setpoint: match (close_triggered, open_triggered) {
-                (true, false) => DoorSetpoint::Closed,
-                (false, true) => DoorSetpoint::Open,
-                _ => DoorSetpoint::Ajar,
-            },
-            position: 0.0,
-        };
-        state_tx.send_replace(last_state);
+    // Leave every relay released before exiting
+    for door in doors.iter() {
+        if door.commands.shutdown(Duration::from_secs(1)).await.is_err() {
+            eprintln!("GPIO loop for door {} did not stop, its outputs may still be active", door.id);
+        }
+    }
 
-        let mut last_direction = 0_f64;
-        let mut last_time = Instant::now();
+    Ok(())
+}
 
-        let mut coupler_queue: VecDeque<PinState> = VecDeque::with_capacity(10);
+// Point clients on an unversioned path at its replacement
+async fn deprecated(mut response: Response) -> Response {
+    let headers = response.headers_mut();
+    headers.insert("deprecation", HeaderValue::from_static("true"));
+    headers.insert(header::LINK, HeaderValue::from_static("</api/v1>; rel=\"successor-version\""));
+    response
+}
 
-        let mut last_full_close = Instant::now();
-        let mut last_full_open = Instant::now();
+async fn unknown_route() -> ApiError {
+    ApiError::new(ErrorCode::UnknownRoute, "Unknown route")
+}
 
-        let start_state = if coupler_active_low {
-            PinState::Low
-        } else {
-            PinState::High
-        };
+async fn method_not_allowed() -> ApiError {
+    ApiError::new(ErrorCode::MethodNotAllowed, "Method not allowed")
+}
 
-        let end_state = if coupler_active_low {
-            PinState::High
-        } else {
-            PinState::Low
-        };
+// Resolves on Ctrl-C or SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            eprintln!("Failed to listen for Ctrl-C: {}", err);
+            std::future::pending::<()>().await;
+        }
+    };
 
-        let toggle_coupler = |queue: &mut VecDeque<PinState>, queue_intervals: u64| {
-            for _ in 0..queue_intervals {
-                queue.push_back(start_state);
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
             }
-            for _ in 0..queue_intervals {
-                queue.push_back(end_state);
+            Err(err) => {
+                eprintln!("Failed to listen for SIGTERM: {}", err);
+                std::future::pending::<()>().await;
             }
-        };
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
-        let rest_coupler = |queue: &mut VecDeque<PinState>, rest_intervals: u64| {
-            for _ in 0..rest_intervals {
-                queue.push_back(end_state);
-            }
-        };
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
 
-        loop {
-            // Update door state with timing consideration
-            let now = Instant::now();
-            let close_triggered = close_limit.is_low().unwrap_or(false);
-            let open_triggered = open_limit.is_low().unwrap_or(false);
-
-            let mut new_state = match (close_triggered, open_triggered) {
-                (true, true) => {
-                    DoorState {
-                        status: DoorStatus::Ajar,
-                        setpoint: last_state.setpoint,
-                        position: last_state.position,
-                    }
-                },
-                (true, false) => {
-                    if now.duration_since(last_full_open) < limit_cooldown {
-                        last_state
-                    } else {
-                        last_direction = -1_f64;
-                        DoorState {
-                            status: DoorStatus::Closed,
-                            setpoint: last_state.setpoint,
-                            position: 0_f64,
-                        }
-                    }  
-                },
-                (false, true) => {
-                    if now.duration_since(last_full_close) < limit_cooldown {
-                        last_state
-                    } else {
-                        last_direction = 1_f64;
-                        DoorState {
-                            status: DoorStatus::Open,
-                            setpoint: last_state.setpoint,
-                            position: 1_f64,
-                        }
-                    }
-                },
-                (false, false) => {
-                    match last_state.status {
-                        DoorStatus::Closed => DoorState {
-                            status: DoorStatus::MovingUp,
-                            setpoint: last_state.setpoint,
-                            position: (last_state.position + last_direction * (now.duration_since(last_time).as_secs_f64() / expected_shut_time.as_secs_f64())).clamp(0_f64, 1_f64),
-                        },
-                        DoorStatus::Open => DoorState {
-                            status: DoorStatus::MovingDown,
-                            setpoint: last_state.setpoint,
-                            position: (last_state.position + last_direction * (now.duration_since(last_time).as_secs_f64() / expected_shut_time.as_secs_f64())).clamp(0_f64, 1_f64),
-                        },
-                        DoorStatus::MovingUp | DoorStatus::MovingDown => DoorState {
-                            status: last_state.status,
-                            setpoint: last_state.setpoint,
-                            position: (last_state.position + last_direction * (now.duration_since(last_time).as_secs_f64() / expected_shut_time.as_secs_f64())).clamp(0_f64, 1_f64),
-                        },
-                        _ => {
-                            last_state
-                        },
-                    }
-                }
-            };
+#[derive(Serialize, ToSchema)]
+struct StatusResponse {
+    /// Includes `fault` with the reason when the door is faulted
+    #[serde(flatten)]
+    status: DoorStatus,
+    /// Includes `target_position` when the setpoint is a position
+    #[serde(flatten)]
+    setpoint: DoorSetpoint,
+    /// Estimated opening from 0 (closed) to 1 (open), null if unknown
+    position: Option<f64>,
+    /// The most recent command and how far along it is
+    command: Option<CommandProgress>,
+    /// Automatic close retries after a close didn't make it
+    close_retry: Option<CloseRetry>,
+    /// A close that can still be cancelled before the door moves
+    warning: Option<WarningResponse>,
+    /// Only present when auto-close is enabled
+    auto_close: Option<AutoCloseResponse>,
+}
 
-            // Process commands
-            let command = latest_command.lock().unwrap().take();
-            if let Some(cmd) = command {
-                match cmd {
-                    GpioCommand::Toggle => {
-                        // If toggled we will always activate coupler exactly once
-                        toggle_coupler(&mut coupler_queue, coupler_active_intervals);
-                        // We will invert setpoint. If stopped, we will use the direction opposite the last direction.
-                        new_state = match new_state.status {
-                            DoorStatus::Closed => {
-                                last_full_open = now;
-                                last_direction = 1_f64;
-                                DoorState {
-                                    status: DoorStatus::MovingUp,
-                                    setpoint: DoorSetpoint::Open,
-                                    position: new_state.position,
-                                }
-                            },
-                            DoorStatus::Open => {
-                                last_full_close = now;
-                                last_direction = -1_f64;
-                                DoorState {
-                                    status: DoorStatus::MovingDown,
-                                    setpoint: DoorSetpoint::Closed,
-                                    position: new_state.position,
-                                }
-                            },
-                            DoorStatus::MovingUp | DoorStatus::MovingDown => DoorState {
-                                status: DoorStatus::Ajar,
-                                setpoint: DoorSetpoint::Ajar,
-                                position: new_state.position,
-                            },
-                            _ => if last_direction > 0_f64 {
-                                last_direction = -1_f64;
-                                DoorState {
-                                    status: DoorStatus::MovingDown,
-                                    setpoint: DoorSetpoint::Closed,
-                                    position: new_state.position,
-                                }
-                            } else {
-                                last_direction = 1_f64;
-                                DoorState {
-                                    status: DoorStatus::MovingUp,
-                                    setpoint: DoorSetpoint::Open,
-                                    position: new_state.position,
-                                }
-                            }
-                        };
-                    },
-                    GpioCommand::Open => {
-                        // If command is open or close then we need to decide if we need 0, 1, 2, or 3 clicks
-                        new_state = match new_state.status {
-                            DoorStatus::MovingDown => {
-                                toggle_coupler(&mut coupler_queue, coupler_active_intervals);
-                                rest_coupler(&mut coupler_queue, coupler_rest_intervals);
-                                toggle_coupler(&mut coupler_queue, coupler_active_intervals);
-
-                                DoorState {
-                                    status: DoorStatus::MovingUp,
-                                    setpoint: DoorSetpoint::Open,
-                                    position: new_state.position,
-                                }
-                            },
-                            DoorStatus::Ajar => {
-                                // If it went up last time, now it will go down, so we need three clicks. Otherwise we just need 1
-                                if last_direction > 0_f64 {
-                                    toggle_coupler(&mut coupler_queue, coupler_active_intervals);
-                                    toggle_coupler(&mut coupler_queue, coupler_active_intervals);
-                                    rest_coupler(&mut coupler_queue, coupler_rest_intervals);
-                                    toggle_coupler(&mut coupler_queue, coupler_active_intervals);
-                                } else {
-                                    toggle_coupler(&mut coupler_queue, coupler_active_intervals);
-                                }
-                                DoorState {
-                                    status: DoorStatus::MovingUp,
-                                    setpoint: DoorSetpoint::Open,
-                                    position: new_state.position,
-                                }
-                            },
-                            DoorStatus::Closed => {
-                                last_full_open = now;
-                                toggle_coupler(&mut coupler_queue, coupler_active_intervals);
-                                DoorState {
-                                    status: DoorStatus::MovingUp,
-                                    setpoint: DoorSetpoint::Open,
-                                    position: new_state.position,
-                                }
-                            }
-                            _ => DoorState {
-                                status: new_state.status,
-                                setpoint: DoorSetpoint::Open,
-                                position: new_state.position,
-                            }
-                        };
-                        // In all cases, we are now moving up (hopefully)
-                        last_direction = 1_f64;
-                    },
-                    GpioCommand::Close => {
-                        // If command is close then we need to decide if we need 0, 1, 2, or 3 clicks
-                        new_state = match new_state.status {
-                            DoorStatus::MovingUp => {
-                                toggle_coupler(&mut coupler_queue, coupler_active_intervals);
-                                rest_coupler(&mut coupler_queue, coupler_rest_intervals);
-                                toggle_coupler(&mut coupler_queue, coupler_active_intervals);
-
-                                DoorState {
-                                    status: DoorStatus::MovingDown,
-                                    setpoint: DoorSetpoint::Closed,
-                                    position: new_state.position,
-                                }
-                            },
-                            DoorStatus::Ajar => {
-                                // If it went down last time, now it will go up, so we need three clicks. Otherwise we just need 1
-                                if last_direction < 0_f64 {
-                                    toggle_coupler(&mut coupler_queue, coupler_active_intervals);
-                                    toggle_coupler(&mut coupler_queue, coupler_active_intervals);
-                                    rest_coupler(&mut coupler_queue, coupler_rest_intervals);
-                                    toggle_coupler(&mut coupler_queue, coupler_active_intervals);
-                                } else {
-                                    toggle_coupler(&mut coupler_queue, coupler_active_intervals);
-                                }
-                                DoorState {
-                                    status: DoorStatus::MovingDown,
-                                    setpoint: DoorSetpoint::Closed,
-                                    position: new_state.position,
-                                }
-                            },
-                            DoorStatus::Open => {
-                                last_full_close = now;
-                                toggle_coupler(&mut coupler_queue, coupler_active_intervals);
-                                DoorState {
-                                    status: DoorStatus::MovingDown,
-                                    setpoint: DoorSetpoint::Closed,
-                                    position: new_state.position,
-                                }
-                            }
-                            _ => DoorState {
-                                status: new_state.status,
-                                setpoint: DoorSetpoint::Closed,
-                                position: new_state.position,
-                            }
-                        };
-                        // In all cases we should be moving down
-                        last_direction = -1_f64;
-                    }
-                }
-            }
+impl From<DoorState> for StatusResponse {
+    fn from(state: DoorState) -> Self {
+        StatusResponse {
+            status: state.status,
+            setpoint: state.setpoint,
+            position: state.position,
+            command: state.command,
+            close_retry: state.close_retry,
+            warning: state.warning.map(WarningResponse::from),
+            auto_close: None,
+        }
+    }
+}
 
-             // Toggle coupler if requested
-             if let Some(pin_state) = coupler_queue.pop_front() {
-                let _ = coupler.set_state(pin_state);
-            }
+#[derive(Serialize, ToSchema)]
+struct WarningResponse {
+    /// Null when the close is a retry
+    #[schema(value_type = Option<u64>)]
+    command_id: Option<CommandId>,
+    /// RFC 3339 time the door starts closing
+    closes_at: String,
+    /// Seconds left until then
+    remaining_sec: f64,
+}
 
-            if new_state != last_state {
-                state_tx.send_replace(new_state);
-                last_state = new_state;
-            }
-            last_time = now;
+impl From<Warning> for WarningResponse {
+    fn from(warning: Warning) -> Self {
+        let remaining = warning.until.saturating_duration_since(Instant::now());
+        WarningResponse {
+            command_id: warning.command,
+            closes_at: (Local::now() + remaining).to_rfc3339(),
+            remaining_sec: remaining.as_secs_f64(),
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+struct AutoCloseResponse {
+    /// RFC 3339 time the door will be closed at
+    deadline: Option<String>,
+    /// Seconds left until then, for a countdown
+    remaining_sec: Option<i64>,
+    /// RFC 3339 time auto-close is held off until, see `hold_open_min`
+    hold_until: Option<String>,
+    /// Auto-close won't act until the door has been closed another way
+    cancelled: bool,
+}
 
-            thread::sleep(poll_interval);
+impl From<AutoCloseStatus> for AutoCloseResponse {
+    fn from(status: AutoCloseStatus) -> Self {
+        AutoCloseResponse {
+            deadline: status.deadline.map(|deadline| deadline.to_rfc3339()),
+            remaining_sec: status
+                .deadline
+                .map(|deadline| (deadline - Local::now()).num_seconds().max(0)),
+            hold_until: status.hold_until.map(|hold_until| hold_until.to_rfc3339()),
+            cancelled: status.cancelled,
         }
-    });
+    }
 }
 
-#[derive(Serialize)]
-struct StatusResponse {
-    status: &'static str,
-    setpoint: &'static str,
-    position: f64,
+// Door state combined with everything else that goes into a status update
+fn current_status(door: &DoorHandle) -> StatusResponse {
+    let door_state = *door.state.borrow();
+    StatusResponse {
+        auto_close: door
+            .auto_close
+            .as_ref()
+            .map(|auto_close| AutoCloseResponse::from(auto_close.status())),
+        ..StatusResponse::from(door_state)
+    }
 }
 
 // Axum handlers
+/// Get this OpenAPI document
+#[utoipa::path(
+    get,
+    tag = "server",
+    path = "/openapi.json",
+    security(()),
+    responses(
+        (status = 200, description = "OpenAPI document"),
+    ),
+)]
+async fn openapi_handler() -> Json<utoipa::openapi::OpenApi> {
+    Json(openapi::document())
+}
+
+/// Prometheus metrics
+#[utoipa::path(
+    get,
+    tag = "server",
+    path = "/metrics",
+    responses(
+        (status = 200, description = "Metrics in the Prometheus text format", body = String, content_type = "text/plain"),
+        (status = 401, description = "Missing or invalid API key", body = ApiError),
+    ),
+)]
+async fn metrics_handler(
+    _: Authenticated,
+    State(app_state): State<AppState>,
+) -> ([(header::HeaderName, &'static str); 1], String) {
+    ([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], app_state.metrics.encode(&app_state.doors))
+}
+
+/// Stream status updates
+///
+/// Sends the current status right away, then again every time it changes.
+/// Alerts about the door come as `alert` events, starting with the ones still going on.
+#[utoipa::path(
+    get,
+    tag = "door",
+    path = "/api/v1/watch-status",
+    responses(
+        (status = 200, description = "Server-sent events, each holding a status update", body = StatusResponse, content_type = "text/event-stream"),
+        (status = 401, description = "Missing or invalid API key", body = ApiError),
+    ),
+)]
 async fn watch_status_handler(
     _: Authenticated,
+    SelectedDoor(door): SelectedDoor,
     State(app_state): State<AppState>,
 ) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
-    let mut rx = app_state.door_state.subscribe();
+    let mut rx = door.state.subscribe();
+    let mut auto_close_rx = door.auto_close.as_ref().map(AutoCloseHandle::subscribe);
+    let mut alerts_rx = app_state.alerts.subscribe();
+    let active_alerts = app_state.alerts.active(&door.id);
+    let subscriber = app_state.metrics.watch_subscriber(&door.id);
     let stream = async_stream::try_stream! {
-        let initial = *rx.borrow();
-        yield Event::default().json_data(StatusResponse { status: initial.status.value(), setpoint: initial.setpoint.value(), position: initial.position }).unwrap();
+        // Counted for as long as the client stays connected
+        let _subscriber = subscriber;
+        yield Event::default().json_data(current_status(&door)).unwrap();
+        for alert in active_alerts {
+            yield Event::default().event("alert").json_data(alert).unwrap();
+        }
 
-        while let Ok(()) = rx.changed().await {
-            let current = *rx.borrow();
-            yield Event::default().json_data(StatusResponse { status: current.status.value(), setpoint: current.setpoint.value(), position: current.position }).unwrap();
+        loop {
+            let auto_close_changed = async {
+                match &mut auto_close_rx {
+                    Some(auto_close_rx) => auto_close_rx.changed().await,
+                    None => std::future::pending().await,
+                }
+            };
+            let alert = tokio::select! {
+                changed = rx.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                    None
+                }
+                changed = auto_close_changed => {
+                    if changed.is_err() {
+                        break;
+                    }
+                    None
+                }
+                alert = alerts_rx.recv() => match alert {
+                    Ok(alert) if alert.door == door.id => Some(alert),
+                    // Another door's alert, or alerts missed while the client was behind
+                    Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                },
+            };
+            match alert {
+                Some(alert) => yield Event::default().event("alert").json_data(alert).unwrap(),
+                None => yield Event::default().json_data(current_status(&door)).unwrap(),
+            }
         }
     };
     Sse::new(stream).keep_alive(axum::response::sse::KeepAlive::default())
 }
 
-// Handler to get current door status without streaming
+/// Get the current status
+#[utoipa::path(
+    get,
+    tag = "door",
+    path = "/api/v1/status",
+    responses(
+        (status = 200, description = "Current status", body = StatusResponse),
+        (status = 401, description = "Missing or invalid API key", body = ApiError),
+    ),
+)]
 async fn current_status_handler(
     _: Authenticated,
-    State(app_state): State<AppState>,
+    SelectedDoor(door): SelectedDoor,
 ) -> Json<StatusResponse> {
-    let rx = app_state.door_state.subscribe();
-    let current = *rx.borrow();
-    Json(StatusResponse { status: current.status.value(), setpoint: current.setpoint.value(), position: current.position })
+    Json(current_status(&door))
+}
+
+#[derive(Serialize, ToSchema)]
+struct DoorSummaryResponse {
+    /// The ID for the `/doors/{door}/...` routes
+    id: String,
+    #[serde(flatten)]
+    status: StatusResponse,
+}
+
+/// Get the status of every door
+///
+/// The default door comes first. Every door route is also available as
+/// `/api/v1/doors/{door}/...` for the other doors.
+#[utoipa::path(
+    get,
+    tag = "door",
+    path = "/api/v1/doors",
+    responses(
+        (status = 200, description = "Every door with its status", body = Vec<DoorSummaryResponse>),
+        (status = 401, description = "Missing or invalid API key", body = ApiError),
+    ),
+)]
+async fn doors_handler(
+    _: Authenticated,
+    State(app_state): State<AppState>,
+) -> Json<Vec<DoorSummaryResponse>> {
+    Json(
+        app_state
+            .doors
+            .iter()
+            .map(|door| DoorSummaryResponse {
+                id: door.id.clone(),
+                status: current_status(door),
+            })
+            .collect(),
+    )
+}
+
+/// Skip auto-close until the door has been closed again
+#[utoipa::path(
+    post,
+    tag = "door",
+    path = "/api/v1/auto-close/cancel",
+    responses(
+        (status = 200, description = "Auto-close cancelled", body = StatusResponse),
+        (status = 401, description = "Missing or invalid API key", body = ApiError),
+        (status = 404, description = "Auto-close is not enabled", body = ApiError),
+    ),
+)]
+async fn cancel_auto_close(
+    _: Authenticated,
+    SelectedDoor(door): SelectedDoor,
+) -> Result<Json<StatusResponse>, ApiError> {
+    let auto_close = door
+        .auto_close
+        .as_ref()
+        .ok_or_else(|| ApiError::new(ErrorCode::AutoCloseDisabled, "Auto-close is not enabled"))?;
+    auto_close.cancel();
+    Ok(Json(current_status(&door)))
+}
+
+/// Call off a close that is still in its warning
+#[utoipa::path(
+    post,
+    tag = "door",
+    path = "/api/v1/warning/cancel",
+    responses(
+        (status = 200, description = "Close cancelled", body = StatusResponse),
+        (status = 401, description = "Missing or invalid API key", body = ApiError),
+        (status = 409, description = "No close is waiting to start", body = ApiError),
+        (status = 503, description = "The GPIO loop has stopped", body = ApiError),
+    ),
+)]
+async fn cancel_warning(
+    _: Authenticated,
+    SelectedDoor(door): SelectedDoor,
+) -> Result<Json<StatusResponse>, ApiError> {
+    if door.state.borrow().warning.is_none() {
+        return Err(ApiError::new(ErrorCode::NoWarning, "No close is waiting to start"));
+    }
+    door.commands.cancel_warning()?;
+
+    // Give the GPIO loop a moment so the response shows the cancelled close
+    let mut rx = door.state.subscribe();
+    let _ = tokio::time::timeout(Duration::from_secs(1), rx.wait_for(|state| state.warning.is_none())).await;
+    Ok(Json(current_status(&door)))
+}
+
+/// Get the learned travel times
+#[utoipa::path(
+    get,
+    tag = "door",
+    path = "/api/v1/calibration",
+    responses(
+        (status = 200, description = "Learned travel times for each direction", body = TravelCalibration),
+        (status = 401, description = "Missing or invalid API key", body = ApiError),
+    ),
+)]
+async fn calibration_handler(
+    _: Authenticated,
+    SelectedDoor(door): SelectedDoor,
+) -> Json<TravelCalibration> {
+    Json(door.state.borrow().calibration)
+}
+
+/// Get the raw and filtered limit switch levels
+#[utoipa::path(
+    get,
+    tag = "door",
+    path = "/api/v1/diagnostics/inputs",
+    responses(
+        (status = 200, description = "Limit switch levels, true while pressed", body = InputDiagnostics),
+        (status = 401, description = "Missing or invalid API key", body = ApiError),
+    ),
+)]
+async fn inputs_handler(
+    _: Authenticated,
+    SelectedDoor(door): SelectedDoor,
+) -> Json<InputDiagnostics> {
+    Json(*door.inputs.borrow())
+}
+
+// The door ID may be in the path as well
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Path)]
+struct CommandPath {
+    /// `command_id` from the response to the command
+    #[param(value_type = u64)]
+    id: CommandId,
+}
+
+/// Look up a command submitted earlier
+#[utoipa::path(
+    get,
+    tag = "door",
+    path = "/api/v1/commands/{id}",
+    params(CommandPath),
+    responses(
+        (status = 200, description = "The command and how far along it is", body = CommandProgress),
+        (status = 401, description = "Missing or invalid API key", body = ApiError),
+        (status = 404, description = "No such command, or it was forgotten", body = ApiError),
+    ),
+)]
+async fn command_status_handler(
+    _: Authenticated,
+    SelectedDoor(door): SelectedDoor,
+    WithRejection(Path(CommandPath { id }), _): WithRejection<Path<CommandPath>, ApiError>,
+) -> Result<Json<CommandProgress>, ApiError> {
+    door
+        .commands
+        .tracker()
+        .get(id)
+        .map(Json)
+        .ok_or_else(|| ApiError::new(ErrorCode::UnknownCommand, "Unknown command"))
+}
+
+// How many upcoming runs to list for each schedule
+const UPCOMING_RUNS: usize = 5;
+
+#[derive(Serialize, ToSchema)]
+struct ScheduleResponse {
+    #[schema(value_type = u64)]
+    id: ScheduleId,
+    door: String,
+    name: String,
+    cron: String,
+    action: ScheduleAction,
+    only_if: Option<ScheduleCondition>,
+    /// RFC 3339 times of the next runs, starting with the one that may be skipped
+    upcoming: Vec<String>,
+    /// The first of the upcoming runs will be skipped
+    skip_next: bool,
+}
+
+impl From<ScheduledAction> for ScheduleResponse {
+    fn from(action: ScheduledAction) -> Self {
+        ScheduleResponse {
+            id: action.id,
+            door: action.door.clone(),
+            upcoming: action.upcoming(UPCOMING_RUNS).iter().map(|time| time.to_rfc3339()).collect(),
+            name: action.config.name,
+            cron: action.config.cron,
+            action: action.config.action,
+            only_if: action.config.only_if,
+            skip_next: action.skip_next,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+struct ScheduleRunResponse {
+    #[schema(value_type = u64)]
+    schedule_id: ScheduleId,
+    door: String,
+    name: String,
+    /// RFC 3339 time the schedule fired
+    time: String,
+    /// Includes `command` with its latest progress if one was sent
+    #[serde(flatten)]
+    outcome: RunOutcome,
+}
+
+impl From<ScheduleRun> for ScheduleRunResponse {
+    fn from(run: ScheduleRun) -> Self {
+        ScheduleRunResponse {
+            schedule_id: run.schedule_id,
+            door: run.door,
+            name: run.name,
+            time: run.time.to_rfc3339(),
+            outcome: run.outcome,
+        }
+    }
+}
+
+/// List the schedules
+#[utoipa::path(
+    get,
+    tag = "schedule",
+    path = "/api/v1/schedules",
+    responses(
+        (status = 200, description = "Every schedule with its next runs", body = Vec<ScheduleResponse>),
+        (status = 401, description = "Missing or invalid API key", body = ApiError),
+    ),
+)]
+async fn list_schedules(
+    _: Authenticated,
+    State(app_state): State<AppState>,
+) -> Json<Vec<ScheduleResponse>> {
+    Json(app_state.scheduler.schedules().into_iter().map(ScheduleResponse::from).collect())
+}
+
+/// Add a schedule
+///
+/// It is only kept until the server restarts.
+#[utoipa::path(
+    post,
+    tag = "schedule",
+    path = "/api/v1/schedules",
+    request_body = ScheduleConfig,
+    responses(
+        (status = 200, description = "The new schedule", body = ScheduleResponse),
+        (status = 400, description = "Invalid cron expression or unknown door", body = ApiError),
+        (status = 401, description = "Missing or invalid API key", body = ApiError),
+    ),
+)]
+async fn add_schedule(
+    _: Authenticated,
+    State(app_state): State<AppState>,
+    WithRejection(Json(config), _): WithRejection<Json<ScheduleConfig>, ApiError>,
+) -> Result<Json<ScheduleResponse>, ApiError> {
+    app_state
+        .scheduler
+        .add(config)
+        .map(|action| Json(ScheduleResponse::from(action)))
+        .map_err(|err| ApiError::new(ErrorCode::InvalidRequest, err.to_string()))
+}
+
+/// Remove a schedule
+#[utoipa::path(
+    delete,
+    tag = "schedule",
+    path = "/api/v1/schedules/{id}",
+    params(("id" = u64, Path, description = "Schedule ID")),
+    responses(
+        (status = 204, description = "Schedule removed"),
+        (status = 401, description = "Missing or invalid API key", body = ApiError),
+        (status = 404, description = "Unknown schedule", body = ApiError),
+    ),
+)]
+async fn remove_schedule(
+    _: Authenticated,
+    State(app_state): State<AppState>,
+    WithRejection(Path(id), _): WithRejection<Path<ScheduleId>, ApiError>,
+) -> Result<StatusCode, ApiError> {
+    if app_state.scheduler.remove(id) {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(unknown_schedule())
+    }
+}
+
+/// Skip the next run of a schedule
+#[utoipa::path(
+    post,
+    tag = "schedule",
+    path = "/api/v1/schedules/{id}/skip",
+    params(("id" = u64, Path, description = "Schedule ID")),
+    responses(
+        (status = 200, description = "The schedule", body = ScheduleResponse),
+        (status = 401, description = "Missing or invalid API key", body = ApiError),
+        (status = 404, description = "Unknown schedule", body = ApiError),
+    ),
+)]
+async fn skip_schedule(
+    _: Authenticated,
+    State(app_state): State<AppState>,
+    WithRejection(Path(id), _): WithRejection<Path<ScheduleId>, ApiError>,
+) -> Result<Json<ScheduleResponse>, ApiError> {
+    set_skip_next(app_state, id, true)
+}
+
+/// Run a skipped schedule after all
+#[utoipa::path(
+    delete,
+    tag = "schedule",
+    path = "/api/v1/schedules/{id}/skip",
+    params(("id" = u64, Path, description = "Schedule ID")),
+    responses(
+        (status = 200, description = "The schedule", body = ScheduleResponse),
+        (status = 401, description = "Missing or invalid API key", body = ApiError),
+        (status = 404, description = "Unknown schedule", body = ApiError),
+    ),
+)]
+async fn unskip_schedule(
+    _: Authenticated,
+    State(app_state): State<AppState>,
+    WithRejection(Path(id), _): WithRejection<Path<ScheduleId>, ApiError>,
+) -> Result<Json<ScheduleResponse>, ApiError> {
+    set_skip_next(app_state, id, false)
+}
+
+fn set_skip_next(app_state: AppState, id: ScheduleId, skip: bool) -> Result<Json<ScheduleResponse>, ApiError> {
+    app_state
+        .scheduler
+        .set_skip_next(id, skip)
+        .map(|action| Json(ScheduleResponse::from(action)))
+        .ok_or_else(unknown_schedule)
+}
+
+fn unknown_schedule() -> ApiError {
+    ApiError::new(ErrorCode::UnknownSchedule, "Unknown schedule")
+}
+
+/// See how past schedule runs went, newest first
+#[utoipa::path(
+    get,
+    tag = "schedule",
+    path = "/api/v1/schedules/runs",
+    responses(
+        (status = 200, description = "Recent runs", body = Vec<ScheduleRunResponse>),
+        (status = 401, description = "Missing or invalid API key", body = ApiError),
+    ),
+)]
+async fn schedule_runs(
+    _: Authenticated,
+    State(app_state): State<AppState>,
+) -> Json<Vec<ScheduleRunResponse>> {
+    Json(app_state.scheduler.runs().into_iter().map(ScheduleRunResponse::from).collect())
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct HistoryQuery {
+    /// Only events at or after this RFC 3339 time
+    from: Option<String>,
+    /// Only events before this RFC 3339 time
+    to: Option<String>,
+    /// Only events of this type
+    #[serde(rename = "type")]
+    event_type: Option<EventType>,
+    /// Only events of this door
+    door: Option<String>,
+    /// Events per page, 100 by default and at most 1000
+    limit: Option<u32>,
+    /// `next_before` from the previous page
+    before: Option<i64>,
 }
 
-#[derive(Serialize)]
+/// Get recorded state changes and commands, newest first
+#[utoipa::path(
+    get,
+    tag = "history",
+    path = "/api/v1/history",
+    params(HistoryQuery),
+    responses(
+        (status = 200, description = "A page of events", body = HistoryPage),
+        (status = 400, description = "Invalid time or type", body = ApiError),
+        (status = 401, description = "Missing or invalid API key", body = ApiError),
+        (status = 404, description = "History is not enabled, or unknown door", body = ApiError),
+        (status = 503, description = "The history database could not be read", body = ApiError),
+    ),
+)]
+async fn history_handler(
+    _: Authenticated,
+    State(app_state): State<AppState>,
+    WithRejection(Query(query), _): WithRejection<Query<HistoryQuery>, ApiError>,
+) -> Result<Json<HistoryPage>, ApiError> {
+    let history = app_state
+        .history
+        .ok_or_else(|| ApiError::new(ErrorCode::HistoryDisabled, "History is not enabled"))?;
+    if let Some(door) = &query.door
+        && app_state.doors.get(door).is_none()
+    {
+        return Err(ApiError::new(ErrorCode::UnknownDoor, format!("Unknown door: {}", door)));
+    }
+    let parse_time = |time: Option<String>, name: &str| {
+        time.map(|time| DateTime::parse_from_rfc3339(&time))
+            .transpose()
+            .map_err(|_| ApiError::new(ErrorCode::InvalidRequest, format!("{} must be an RFC 3339 time", name)))
+    };
+    let filter = HistoryFilter {
+        from: parse_time(query.from, "from")?,
+        to: parse_time(query.to, "to")?,
+        event_type: query.event_type,
+        door: query.door,
+        limit: query.limit.unwrap_or(100).clamp(1, 1000),
+        before: query.before,
+    };
+    let page = history.query(filter).await.map_err(|err| {
+        eprintln!("Failed to read the history: {}", err);
+        ApiError::new(ErrorCode::HistoryUnavailable, "Failed to read the history")
+    })?;
+    Ok(Json(page))
+}
+
+#[derive(Serialize, ToSchema)]
+struct DeliveryResponse {
+    /// Also sent in the `X-PiOpener-Delivery` header
+    #[schema(value_type = u64)]
+    id: DeliveryId,
+    url: String,
+    event: config::WebhookEvent,
+    door: String,
+    /// RFC 3339 time the event happened
+    time: String,
+    attempts: u32,
+    /// Includes the HTTP status once delivered, or the error
+    #[serde(flatten)]
+    outcome: DeliveryOutcome,
+}
+
+impl From<Delivery> for DeliveryResponse {
+    fn from(delivery: Delivery) -> Self {
+        DeliveryResponse {
+            id: delivery.id,
+            url: delivery.url,
+            event: delivery.event,
+            door: delivery.door,
+            time: delivery.time.to_rfc3339(),
+            attempts: delivery.attempts,
+            outcome: delivery.outcome,
+        }
+    }
+}
+
+/// Get recent webhook deliveries, oldest first
+#[utoipa::path(
+    get,
+    tag = "webhook",
+    path = "/api/v1/webhooks/deliveries",
+    responses(
+        (status = 200, description = "Recent deliveries", body = Vec<DeliveryResponse>),
+        (status = 401, description = "Missing or invalid API key", body = ApiError),
+    ),
+)]
+async fn webhook_deliveries(
+    _: Authenticated,
+    State(app_state): State<AppState>,
+) -> Json<Vec<DeliveryResponse>> {
+    Json(app_state.webhooks.deliveries().into_iter().map(DeliveryResponse::from).collect())
+}
+
+/// Send a test notification, to check a notifier is set up right
+#[utoipa::path(
+    post,
+    tag = "notifier",
+    path = "/api/v1/notifiers/{name}/test",
+    params(("name" = String, Path, description = "Notifier name from the config")),
+    responses(
+        (status = 204, description = "Notification sent"),
+        (status = 401, description = "Missing or invalid API key", body = ApiError),
+        (status = 404, description = "Unknown notifier", body = ApiError),
+        (status = 502, description = "The notification didn't go through", body = ApiError),
+    ),
+)]
+async fn test_notifier(
+    _: Authenticated,
+    State(app_state): State<AppState>,
+    WithRejection(Path(name), _): WithRejection<Path<String>, ApiError>,
+) -> Result<StatusCode, ApiError> {
+    let notifier = app_state
+        .alerts
+        .notifier(&name)
+        .ok_or_else(|| ApiError::new(ErrorCode::UnknownNotifier, format!("Unknown notifier: {}", name)))?;
+    app_state
+        .alerts
+        .test(&notifier)
+        .await
+        .map_err(|err| ApiError::new(ErrorCode::NotifierFailed, format!("Notification failed: {}", err)))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Serialize, ToSchema)]
 struct DoorResponse {
-    status: &'static str,
+    /// Follow the command at `/commands/{id}`
+    #[schema(value_type = u64)]
+    command_id: CommandId,
+    #[serde(flatten)]
+    phase: CommandPhase,
     message: &'static str,
 }
 
+impl From<CommandProgress> for DoorResponse {
+    fn from(progress: CommandProgress) -> Self {
+        DoorResponse {
+            phase: progress.phase,
+            message: match progress.phase {
+                CommandPhase::Pending => "Command accepted",
+                CommandPhase::Warning => "Warning before the door closes",
+                CommandPhase::Sequencing | CommandPhase::Travelling => "Command in progress",
+                CommandPhase::Retrying => "Door did not close, retrying",
+                CommandPhase::Completed => "Command completed",
+                CommandPhase::TimedOut => "Door did not reach its target in time, check the fault status",
+                CommandPhase::Superseded => "Command was superseded by a newer command",
+                CommandPhase::Refused => "Command refused, the door position is unknown or faulted",
+                CommandPhase::Merged(_) => "Command was merged into an identical pending command",
+                CommandPhase::Cancelled => "Close was cancelled during its warning",
+            },
+            command_id: progress.id,
+        }
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct CommandOptions {
+    /// Wait for the command to finish instead of returning once it is accepted
+    #[serde(default)]
+    wait: bool,
+    /// Only for /open: keep auto-close from closing the door for this many minutes, at most a week
+    hold_open_min: Option<u64>,
+}
+
+// Longest hold_open_min accepted
+const MAX_HOLD_OPEN_MIN: u64 = 7 * 24 * 60;
+
+/// Press the opener button once
+#[utoipa::path(
+    post,
+    tag = "door",
+    path = "/api/v1/toggle",
+    params(CommandOptions),
+    responses(
+        (status = 200, description = "Command accepted, or finished when waiting", body = DoorResponse),
+        (status = 401, description = "Missing or invalid API key", body = ApiError),
+        (status = 409, description = "The limit switches are faulted", body = ApiError),
+        (status = 503, description = "The GPIO loop has stopped", body = ApiError),
+    ),
+)]
 async fn toggle_door(
     _: Authenticated,
-    State(app_state): State<AppState>,
-) -> Json<DoorResponse> {
-    store_command(app_state.latest_command, GpioCommand::Toggle).await
+    SelectedDoor(door): SelectedDoor,
+    WithRejection(Query(options), _): WithRejection<Query<CommandOptions>, ApiError>,
+) -> Result<Json<DoorResponse>, ApiError> {
+    store_command(door, GpioCommand::Toggle, options).await
 }
 
+/// Open the door
+#[utoipa::path(
+    post,
+    tag = "door",
+    path = "/api/v1/open",
+    params(CommandOptions),
+    responses(
+        (status = 200, description = "Command accepted, or finished when waiting", body = DoorResponse),
+        (status = 400, description = "hold_open_min is more than a week", body = ApiError),
+        (status = 401, description = "Missing or invalid API key", body = ApiError),
+        (status = 409, description = "The door position is unknown or faulted", body = ApiError),
+        (status = 503, description = "The GPIO loop has stopped", body = ApiError),
+    ),
+)]
 async fn open_door(
     _: Authenticated,
-    State(app_state): State<AppState>,
-) -> Json<DoorResponse> {
-    store_command(app_state.latest_command, GpioCommand::Open).await
+    SelectedDoor(door): SelectedDoor,
+    WithRejection(Query(options), _): WithRejection<Query<CommandOptions>, ApiError>,
+) -> Result<Json<DoorResponse>, ApiError> {
+    if options.hold_open_min.is_some_and(|hold_open_min| hold_open_min > MAX_HOLD_OPEN_MIN) {
+        return Err(ApiError::new(
+            ErrorCode::InvalidRequest,
+            format!("hold_open_min can be at most {}", MAX_HOLD_OPEN_MIN),
+        ));
+    }
+    let progress_rx = submit_command(&door, GpioCommand::Open, CommandSource::Api)?;
+    // Only hold the door open once the open is on its way
+    if let (Some(hold_open_min), Some(auto_close)) = (options.hold_open_min, &door.auto_close) {
+        auto_close.hold_open(Duration::from_secs(hold_open_min * 60));
+    }
+    command_response(door, progress_rx, options.wait).await
 }
 
+/// Close the door
+///
+/// With a warning output configured, the door only starts moving after the warning.
+#[utoipa::path(
+    post,
+    tag = "door",
+    path = "/api/v1/close",
+    params(CommandOptions),
+    responses(
+        (status = 200, description = "Command accepted, or finished when waiting", body = DoorResponse),
+        (status = 401, description = "Missing or invalid API key", body = ApiError),
+        (status = 409, description = "The door position is unknown or faulted", body = ApiError),
+        (status = 503, description = "The GPIO loop has stopped", body = ApiError),
+    ),
+)]
 async fn close_door(
     _: Authenticated,
-    State(app_state): State<AppState>,
-) -> Json<DoorResponse> {
-    store_command(app_state.latest_command, GpioCommand::Close).await
+    SelectedDoor(door): SelectedDoor,
+    WithRejection(Query(options), _): WithRejection<Query<CommandOptions>, ApiError>,
+) -> Result<Json<DoorResponse>, ApiError> {
+    store_command(door, GpioCommand::Close, options).await
 }
 
-// Update store_command to be async and wait for execution status
+/// Stop the door if it is moving
+#[utoipa::path(
+    post,
+    tag = "door",
+    path = "/api/v1/stop",
+    params(CommandOptions),
+    responses(
+        (status = 200, description = "Command accepted, or finished when waiting", body = DoorResponse),
+        (status = 401, description = "Missing or invalid API key", body = ApiError),
+        (status = 409, description = "The limit switches are faulted", body = ApiError),
+        (status = 503, description = "The GPIO loop has stopped", body = ApiError),
+    ),
+)]
+async fn stop_door(
+    _: Authenticated,
+    SelectedDoor(door): SelectedDoor,
+    WithRejection(Query(options), _): WithRejection<Query<CommandOptions>, ApiError>,
+) -> Result<Json<DoorResponse>, ApiError> {
+    store_command(door, GpioCommand::Stop, options).await
+}
+
+#[derive(Deserialize, ToSchema)]
+struct PositionRequest {
+    /// Fraction from 0 (closed) to 1 (open)
+    position: f64,
+}
+
+/// Move the door to a partly open position
+///
+/// The position is estimated from the travel time, so it is only as good as the calibration.
+#[utoipa::path(
+    post,
+    tag = "door",
+    path = "/api/v1/position",
+    params(CommandOptions),
+    request_body = PositionRequest,
+    responses(
+        (status = 200, description = "Command accepted, or finished when waiting", body = DoorResponse),
+        (status = 400, description = "Position is not between 0 and 1", body = ApiError),
+        (status = 401, description = "Missing or invalid API key", body = ApiError),
+        (status = 409, description = "The door position is unknown or faulted", body = ApiError),
+        (status = 503, description = "The GPIO loop has stopped", body = ApiError),
+    ),
+)]
+async fn position_door(
+    _: Authenticated,
+    SelectedDoor(door): SelectedDoor,
+    WithRejection(Query(options), _): WithRejection<Query<CommandOptions>, ApiError>,
+    WithRejection(Json(request), _): WithRejection<Json<PositionRequest>, ApiError>,
+) -> Result<Json<DoorResponse>, ApiError> {
+    store_command(door, GpioCommand::Position(request.position), options).await
+}
+
+// Hand the command to the GPIO loop, optionally waiting until it finishes
 async fn store_command(
-    cmd_mutex: Arc<Mutex<Option<GpioCommand>>>,
-    cmd: GpioCommand,
-) -> Json<DoorResponse> {
-    // Set pending status and store command    
+    door: DoorHandle,
+    kind: GpioCommand,
+    options: CommandOptions,
+) -> Result<Json<DoorResponse>, ApiError> {
+    let progress_rx = submit_command(&door, kind, CommandSource::Api)?;
+    command_response(door, progress_rx, options.wait).await
+}
+
+// Answer for a submitted command, once it has finished if the caller wants to wait
+async fn command_response(
+    door: DoorHandle,
+    progress_rx: watch::Receiver<CommandProgress>,
+    wait: bool,
+) -> Result<Json<DoorResponse>, ApiError> {
+    let progress = if wait {
+        door.commands.wait(progress_rx).await
+    } else {
+        *progress_rx.borrow()
+    };
+    // The door may have faulted before the GPIO loop got to the command
+    if progress.phase == CommandPhase::Refused {
+        return Err(command_refused(*door.state.borrow()));
+    }
+    Ok(Json(DoorResponse::from(progress)))
+}
+
+// Check a command and hand it to the GPIO loop
+fn submit_command(
+    door: &DoorHandle,
+    kind: GpioCommand,
+    source: CommandSource,
+) -> Result<watch::Receiver<CommandProgress>, ApiError> {
+    if let GpioCommand::Position(position) = kind
+        && !(0.0..=1.0).contains(&position)
     {
-        let mut lock = cmd_mutex.lock().unwrap();
-        *lock = Some(cmd);
+        return Err(ApiError::new(ErrorCode::InvalidRequest, "Position must be between 0 and 1"));
+    }
+    // Turn away commands the controller would refuse anyway
+    let state = *door.state.borrow();
+    if state.refuses(kind) {
+        door.commands.tracker().refused(kind, source);
+        return Err(command_refused(state));
     }
-    
-    Json(DoorResponse {
-        status: "success",
-        message: "Command executed",
-    })
+    Ok(door.commands.send(kind, source)?)
+}
+
+fn command_refused(state: DoorState) -> ApiError {
+    let message = match state.status {
+        DoorStatus::Fault { .. } => "Command refused, the limit switches can't be trusted until the fault clears",
+        DoorStatus::Unknown => "Command refused, the door position is unknown so only toggle and stop are allowed",
+        _ => "Command refused, the door position is not known well enough to move to a position",
+    };
+    ApiError::new(ErrorCode::CommandRefused, message)
 }
@@ -0,0 +1,275 @@
+use std::time::Instant;
+use axum::{
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use prometheus::{
+    HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, GaugeVec, Opts, Registry, TextEncoder,
+};
+use serde::Serialize;
+use crate::{
+    controller::{DoorStatus, FaultReason, GpioCommand},
+    door::Doors,
+};
+
+// Every value the status gauge can take, one series each. Faults share a series whatever the reason.
+const STATUSES: [DoorStatus; 7] = [
+    DoorStatus::Closed,
+    DoorStatus::Open,
+    DoorStatus::Ajar,
+    DoorStatus::MovingUp,
+    DoorStatus::MovingDown,
+    DoorStatus::Unknown,
+    DoorStatus::Fault {
+        reason: FaultReason::Stalled,
+    },
+];
+
+/// Prometheus metrics for the whole server, served at `/metrics`
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    registry: Registry,
+    door_status: IntGaugeVec,
+    door_position: GaugeVec,
+    last_travel: GaugeVec,
+    coupler_clicks: IntCounterVec,
+    cycles: IntCounterVec,
+    pin_read_errors: IntCounterVec,
+    watch_subscribers: IntGaugeVec,
+    http_requests: IntCounterVec,
+    http_duration: HistogramVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("piopener".to_string()), None).expect("Prefix is valid");
+        let metrics = Self {
+            door_status: IntGaugeVec::new(
+                Opts::new("door_status", "1 for the status the door is in, 0 for the others"),
+                &["door", "status"],
+            )
+            .expect("Metric is valid"),
+            door_position: GaugeVec::new(
+                Opts::new("door_position", "Estimated opening from 0 (closed) to 1 (open), missing while unknown"),
+                &["door"],
+            )
+            .expect("Metric is valid"),
+            last_travel: GaugeVec::new(
+                Opts::new("door_last_travel_seconds", "Duration of the most recent full limit-to-limit travel"),
+                &["door", "direction"],
+            )
+            .expect("Metric is valid"),
+            coupler_clicks: IntCounterVec::new(
+                Opts::new("coupler_clicks_total", "Opener button presses, by the command they were for"),
+                &["door", "command"],
+            )
+            .expect("Metric is valid"),
+            cycles: IntCounterVec::new(
+                Opts::new("door_cycles_total", "Travels that ended at the open or closed limit switch"),
+                &["door", "direction"],
+            )
+            .expect("Metric is valid"),
+            pin_read_errors: IntCounterVec::new(
+                Opts::new("pin_read_errors_total", "Limit switch reads that failed"),
+                &["door", "pin"],
+            )
+            .expect("Metric is valid"),
+            watch_subscribers: IntGaugeVec::new(
+                Opts::new("watch_status_subscribers", "Clients connected to /watch-status"),
+                &["door"],
+            )
+            .expect("Metric is valid"),
+            http_requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "HTTP requests by route and response status"),
+                &["method", "route", "status"],
+            )
+            .expect("Metric is valid"),
+            http_duration: HistogramVec::new(
+                HistogramOpts::new("http_request_duration_seconds", "Time until the response headers went out"),
+                &["method", "route"],
+            )
+            .expect("Metric is valid"),
+            registry,
+        };
+
+        let collectors: [Box<dyn prometheus::core::Collector>; 9] = [
+            Box::new(metrics.door_status.clone()),
+            Box::new(metrics.door_position.clone()),
+            Box::new(metrics.last_travel.clone()),
+            Box::new(metrics.coupler_clicks.clone()),
+            Box::new(metrics.cycles.clone()),
+            Box::new(metrics.pin_read_errors.clone()),
+            Box::new(metrics.watch_subscribers.clone()),
+            Box::new(metrics.http_requests.clone()),
+            Box::new(metrics.http_duration.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector).expect("Metrics are only registered once");
+        }
+        metrics
+    }
+
+    /// Counters the GPIO loop of one door updates
+    pub fn door(&self, id: &str) -> DoorMetrics {
+        DoorMetrics {
+            id: id.to_string(),
+            metrics: self.clone(),
+        }
+    }
+
+    /// Counts a `/watch-status` client until the returned guard is dropped
+    pub fn watch_subscriber(&self, door: &str) -> SubscriberGuard {
+        let gauge = self.watch_subscribers.with_label_values(&[door]);
+        gauge.inc();
+        SubscriberGuard(gauge)
+    }
+
+    /// Everything in the Prometheus text format. The door gauges are read from the current state.
+    pub fn encode(&self, doors: &Doors) -> String {
+        for door in doors.iter() {
+            let state = *door.state.borrow();
+            let status = label(state.status, "status");
+            for name in STATUSES.map(|status| label(status, "status")) {
+                self.door_status
+                    .with_label_values(&[door.id.as_str(), name.as_str()])
+                    .set((name == status) as i64);
+            }
+            match state.position {
+                Some(position) => self.door_position.with_label_values(&[&door.id]).set(position),
+                // Leave the series out rather than make up a value
+                None => {
+                    let _ = self.door_position.remove_label_values(&[&door.id]);
+                }
+            }
+            for (direction, stats) in [("up", state.calibration.up), ("down", state.calibration.down)] {
+                if let Some(last_sec) = stats.last_sec {
+                    self.last_travel.with_label_values(&[door.id.as_str(), direction]).set(last_sec);
+                }
+            }
+        }
+        TextEncoder::new()
+            .encode_to_string(&self.registry.gather())
+            .expect("Metrics always encode")
+    }
+}
+
+/// Metrics of a single door, for the GPIO loop
+#[derive(Debug, Clone)]
+pub struct DoorMetrics {
+    id: String,
+    metrics: Metrics,
+}
+
+impl DoorMetrics {
+    /// `clicks` presses of the opener button for `command`, or for nothing in particular
+    pub fn coupler_clicks(&self, command: Option<GpioCommand>, clicks: u64) {
+        let command = command.map_or_else(|| "none".to_string(), |command| label(command, "command"));
+        self.metrics
+            .coupler_clicks
+            .with_label_values(&[self.id.as_str(), command.as_str()])
+            .inc_by(clicks);
+    }
+
+    /// The door arrived at a limit switch after travelling, `direction` is "open" or "close"
+    pub fn cycle(&self, direction: &str) {
+        self.metrics.cycles.with_label_values(&[self.id.as_str(), direction]).inc();
+    }
+
+    pub fn pin_read_error(&self, pin: &str) {
+        self.metrics.pin_read_errors.with_label_values(&[self.id.as_str(), pin]).inc();
+    }
+}
+
+/// Takes a `/watch-status` client off the count when dropped
+pub struct SubscriberGuard(IntGauge);
+
+impl Drop for SubscriberGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// Middleware counting requests and timing them per route
+pub async fn track_requests(State(metrics): State<Metrics>, request: Request, next: Next) -> Response {
+    // Label by the route rather than the path so door IDs and such don't make new series
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", MatchedPath::as_str)
+        .to_string();
+    let method = request.method().to_string();
+    let started = Instant::now();
+    let response = next.run(request).await;
+
+    metrics
+        .http_duration
+        .with_label_values(&[method.as_str(), route.as_str()])
+        .observe(started.elapsed().as_secs_f64());
+    metrics
+        .http_requests
+        .with_label_values(&[method.as_str(), route.as_str(), response.status().as_str()])
+        .inc();
+    response
+}
+
+// The name the API uses for a status or command, which sits in the `tag` field of its JSON
+fn label(value: impl Serialize, tag: &str) -> String {
+    let value = serde_json::to_value(value).expect("Labels always serialize");
+    value[tag].as_str().unwrap_or_default().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::door::DoorHandle;
+    use super::*;
+
+    #[test]
+    fn renders_door_state_and_counters() {
+        let metrics = Metrics::new();
+        let (door, _commands) = DoorHandle::detached("garage");
+        let state = door.state.clone();
+        let doors = Doors::new(vec![door]);
+
+        let text = metrics.encode(&doors);
+        assert!(text.contains(r#"piopener_door_status{door="garage",status="closed"} 1"#), "{text}");
+        assert!(text.contains(r#"piopener_door_status{door="garage",status="moving_up"} 0"#), "{text}");
+        assert!(text.contains(r#"piopener_door_position{door="garage"} 0"#), "{text}");
+        assert!(!text.contains("piopener_door_last_travel_seconds{"), "{text}");
+
+        state.send_modify(|state| {
+            state.status = DoorStatus::MovingUp;
+            state.position = None;
+            state.calibration.up.last_sec = Some(12.5);
+        });
+        let door = metrics.door("garage");
+        door.coupler_clicks(Some(GpioCommand::Open), 1);
+        door.coupler_clicks(None, 2);
+        door.cycle("open");
+
+        let text = metrics.encode(&doors);
+        assert!(text.contains(r#"piopener_door_status{door="garage",status="closed"} 0"#), "{text}");
+        assert!(text.contains(r#"piopener_door_status{door="garage",status="moving_up"} 1"#), "{text}");
+        assert!(text.contains(r#"piopener_door_status{door="garage",status="fault"} 0"#), "{text}");
+        // An unknown position drops the series rather than reporting a made up value
+        assert!(!text.contains("piopener_door_position{"), "{text}");
+        assert!(text.contains(r#"piopener_door_last_travel_seconds{direction="up",door="garage"} 12.5"#), "{text}");
+        assert!(text.contains(r#"piopener_coupler_clicks_total{command="open",door="garage"} 1"#), "{text}");
+        assert!(text.contains(r#"piopener_coupler_clicks_total{command="none",door="garage"} 2"#), "{text}");
+        assert!(text.contains(r#"piopener_door_cycles_total{direction="open",door="garage"} 1"#), "{text}");
+    }
+
+    #[test]
+    fn counts_watch_subscribers_while_connected() {
+        let metrics = Metrics::new();
+        let (door, _commands) = DoorHandle::detached("garage");
+        let doors = Doors::new(vec![door]);
+
+        let first = metrics.watch_subscriber("garage");
+        let second = metrics.watch_subscriber("garage");
+        assert!(metrics.encode(&doors).contains(r#"piopener_watch_status_subscribers{door="garage"} 2"#));
+        drop(first);
+        drop(second);
+        assert!(metrics.encode(&doors).contains(r#"piopener_watch_status_subscribers{door="garage"} 0"#));
+    }
+}
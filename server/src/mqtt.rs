@@ -0,0 +1,407 @@
+use std::time::Duration;
+use rumqttc::{AsyncClient, Event, EventLoop, LastWill, MqttOptions, Outgoing, Packet, Publish, QoS};
+use serde_json::json;
+use tokio::task::JoinHandle;
+use crate::{
+    commands::CommandSource,
+    config::MqttConfig,
+    controller::GpioCommand,
+    current_status,
+    door::{DoorHandle, Doors},
+    submit_command,
+};
+
+// Don't publish position updates of a moving door more often than this
+const MIN_PUBLISH_INTERVAL: Duration = Duration::from_millis(500);
+// How long to wait before connecting again after losing the broker
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Topics for one door, all under `<topic_prefix>/<door ID>/`
+#[derive(Debug, Clone)]
+struct DoorTopics {
+    /// Retained JSON status, the same as `/status` returns
+    state: String,
+    /// `open`, `close`, `stop`, `toggle`, or a command as JSON like `{"command": "position", "position": 0.5}`
+    command: String,
+    /// Position to go to from 0 (closed) to 100 (open)
+    set_position: String,
+}
+
+impl DoorTopics {
+    fn new(prefix: &str, door: &str) -> Self {
+        Self {
+            state: format!("{prefix}/{door}/state"),
+            command: format!("{prefix}/{door}/command"),
+            set_position: format!("{prefix}/{door}/set_position"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Settings {
+    client_id: String,
+    /// "online" while we are connected, "offline" through the last will otherwise
+    availability: String,
+    discovery_prefix: Option<String>,
+    doors: Vec<(DoorHandle, DoorTopics)>,
+}
+
+/// Lets main take the MQTT connection down cleanly
+#[derive(Debug)]
+pub struct MqttHandle {
+    client: AsyncClient,
+    availability: String,
+    task: JoinHandle<()>,
+}
+
+impl MqttHandle {
+    /// Mark us offline and disconnect, waiting up to `timeout` for that to go out
+    pub async fn shutdown(self, timeout: Duration) {
+        // A clean disconnect doesn't trigger the last will, so say it ourselves
+        let shutdown = async {
+            let _ = self.client.publish(&self.availability, QoS::AtLeastOnce, true, "offline").await;
+            let _ = self.client.disconnect().await;
+            let _ = self.task.await;
+        };
+        if tokio::time::timeout(timeout, shutdown).await.is_err() {
+            eprintln!("MQTT did not disconnect in time");
+        }
+    }
+}
+
+/// Connect to the broker and start publishing door state. Reconnects on its own.
+pub fn spawn(config: &MqttConfig, doors: &Doors) -> MqttHandle {
+    let client_id = config.client_id.clone().unwrap_or_else(|| "piopener".to_string());
+    let prefix = config.topic_prefix.clone().unwrap_or_else(|| client_id.clone());
+    let settings = Settings {
+        availability: format!("{prefix}/availability"),
+        discovery_prefix: config
+            .discovery
+            .unwrap_or(true)
+            .then(|| config.discovery_prefix.clone().unwrap_or_else(|| "homeassistant".to_string())),
+        doors: doors.iter().map(|door| (door.clone(), DoorTopics::new(&prefix, &door.id))).collect(),
+        client_id,
+    };
+
+    let mut options = MqttOptions::new(&settings.client_id, &config.host, config.port.unwrap_or(1883));
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_last_will(LastWill::new(&settings.availability, "offline", QoS::AtLeastOnce, true));
+    if let (Some(username), Some(password)) = (&config.username, &config.password) {
+        options.set_credentials(username, password);
+    }
+    let (client, eventloop) = AsyncClient::new(options, 32);
+
+    for (door, topics) in &settings.doors {
+        tokio::spawn(publish_state(client.clone(), door.clone(), topics.state.clone()));
+    }
+
+    MqttHandle {
+        client: client.clone(),
+        availability: settings.availability.clone(),
+        task: tokio::spawn(run(client, eventloop, settings)),
+    }
+}
+
+// Drive the connection. rumqttc connects again on the next poll after an error.
+async fn run(client: AsyncClient, mut eventloop: EventLoop, settings: Settings) {
+    let mut failing = false;
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                failing = false;
+                // Sending from here could block the event loop we are running, so hand it off
+                let (client, settings) = (client.clone(), settings.clone());
+                tokio::spawn(async move {
+                    if let Err(err) = announce(client, settings).await {
+                        eprintln!("Failed to set up MQTT topics: {}", err);
+                    }
+                });
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => handle_publish(&settings, &publish),
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+            Ok(_) => {}
+            Err(err) => {
+                // Only log the first failure, not every attempt while the broker is away
+                if !failing {
+                    eprintln!("MQTT connection failed, retrying: {}", err);
+                    failing = true;
+                }
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
+
+// Everything to send after (re)connecting. The session starts out clean every time.
+async fn announce(client: AsyncClient, settings: Settings) -> Result<(), rumqttc::ClientError> {
+    for (door, topics) in &settings.doors {
+        client.subscribe(&topics.command, QoS::AtLeastOnce).await?;
+        client.subscribe(&topics.set_position, QoS::AtLeastOnce).await?;
+        if let Some(discovery_prefix) = &settings.discovery_prefix {
+            let object_id = format!("{}_{}", settings.client_id, door.id);
+            let config = discovery_config(&settings, &object_id, &door.id, topics);
+            client
+                .publish(format!("{discovery_prefix}/cover/{object_id}/config"), QoS::AtLeastOnce, true, config.to_string())
+                .await?;
+        }
+        client
+            .publish(&topics.state, QoS::AtLeastOnce, true, status_payload(door))
+            .await?;
+    }
+    client.publish(&settings.availability, QoS::AtLeastOnce, true, "online").await
+}
+
+// Home Assistant MQTT discovery config for a garage door cover
+fn discovery_config(settings: &Settings, object_id: &str, door_id: &str, topics: &DoorTopics) -> serde_json::Value {
+    json!({
+        "name": null,
+        "unique_id": object_id,
+        "object_id": object_id,
+        "device_class": "garage",
+        "device": {
+            "identifiers": [object_id],
+            "name": format!("Garage door {door_id}"),
+            "manufacturer": "PiOpener",
+        },
+        "availability_topic": settings.availability,
+        "state_topic": topics.state,
+        "value_template": "{{ value_json.status if value_json.status in ('open', 'closed', 'moving_up', 'moving_down', 'ajar') else 'None' }}",
+        "state_open": "open",
+        "state_opening": "moving_up",
+        "state_closed": "closed",
+        "state_closing": "moving_down",
+        "state_stopped": "ajar",
+        "json_attributes_topic": topics.state,
+        "command_topic": topics.command,
+        "payload_open": "open",
+        "payload_close": "close",
+        "payload_stop": "stop",
+        "position_topic": topics.state,
+        "position_template": "{{ (value_json.position * 100) | round | int if value_json.position is number else 'None' }}",
+        "set_position_topic": topics.set_position,
+        "position_open": 100,
+        "position_closed": 0,
+    })
+}
+
+// Run a command received on one of the door command topics
+fn handle_publish(settings: &Settings, publish: &Publish) {
+    let Some((door, topics)) = settings
+        .doors
+        .iter()
+        .find(|(_, topics)| publish.topic == topics.command || publish.topic == topics.set_position)
+    else {
+        return;
+    };
+    // The broker hands out a retained command again on every reconnect, long after it was meant
+    if publish.retain {
+        eprintln!("Ignoring retained MQTT command on {}", publish.topic);
+        return;
+    }
+    let payload = String::from_utf8_lossy(&publish.payload);
+    let payload = payload.trim();
+
+    match parse_command(topics, &publish.topic, payload) {
+        Some(command) => {
+            if let Err(err) = submit_command(door, command, CommandSource::Mqtt) {
+                eprintln!("MQTT command for door {} failed: {}", door.id, err.message);
+            }
+        }
+        None => eprintln!("Ignoring MQTT command for door {}: {:?}", door.id, payload),
+    }
+}
+
+fn parse_command(topics: &DoorTopics, topic: &str, payload: &str) -> Option<GpioCommand> {
+    if topic == topics.set_position {
+        payload
+            .parse::<f64>()
+            .ok()
+            .filter(|position| (0.0..=100.0).contains(position))
+            .map(|position| GpioCommand::Position(position / 100.0))
+    } else {
+        match payload.to_ascii_lowercase().as_str() {
+            "open" => Some(GpioCommand::Open),
+            "close" => Some(GpioCommand::Close),
+            "stop" => Some(GpioCommand::Stop),
+            "toggle" => Some(GpioCommand::Toggle),
+            _ => serde_json::from_str(payload).ok(),
+        }
+    }
+}
+
+// Publish the door status every time it changes
+async fn publish_state(client: AsyncClient, door: DoorHandle, topic: String) {
+    let mut state_rx = door.state.subscribe();
+    while state_rx.changed().await.is_ok() {
+        // Waits while the broker is away. The watch channel only keeps the latest state,
+        // and announce() publishes that again once we are back.
+        if client.publish(&topic, QoS::AtLeastOnce, true, status_payload(&door)).await.is_err() {
+            break;
+        }
+        tokio::time::sleep(MIN_PUBLISH_INTERVAL).await;
+    }
+}
+
+fn status_payload(door: &DoorHandle) -> String {
+    serde_json::to_string(&current_status(door)).expect("Status always serializes")
+}
+
+#[cfg(test)]
+mod tests {
+    use rumqttc::{Event, Packet};
+    use tokio::sync::mpsc;
+    use crate::commands::LoopMessage;
+    use super::*;
+
+    fn settings(door: DoorHandle) -> Settings {
+        Settings {
+            client_id: "piopener".to_string(),
+            availability: "piopener/availability".to_string(),
+            discovery_prefix: Some("homeassistant".to_string()),
+            doors: vec![(door, DoorTopics::new("piopener", "default"))],
+        }
+    }
+
+    fn sent(command_rx: &mut mpsc::UnboundedReceiver<LoopMessage>) -> Option<GpioCommand> {
+        match command_rx.try_recv() {
+            Ok(LoopMessage::Command(command)) => Some(command.kind),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn parses_commands() {
+        let topics = DoorTopics::new("piopener", "default");
+        let command = |payload| parse_command(&topics, &topics.command, payload);
+        assert_eq!(command("open"), Some(GpioCommand::Open));
+        assert_eq!(command("CLOSE"), Some(GpioCommand::Close));
+        assert_eq!(command("stop"), Some(GpioCommand::Stop));
+        assert_eq!(command("toggle"), Some(GpioCommand::Toggle));
+        assert_eq!(
+            command(r#"{"command": "position", "position": 0.25}"#),
+            Some(GpioCommand::Position(0.25))
+        );
+        assert_eq!(command("lock"), None);
+        assert_eq!(command(""), None);
+    }
+
+    #[test]
+    fn parses_positions_as_percentages() {
+        let topics = DoorTopics::new("piopener", "default");
+        let position = |payload| parse_command(&topics, &topics.set_position, payload);
+        assert_eq!(position("50"), Some(GpioCommand::Position(0.5)));
+        assert_eq!(position("0"), Some(GpioCommand::Position(0.0)));
+        assert_eq!(position("100"), Some(GpioCommand::Position(1.0)));
+        assert_eq!(position("12.5"), Some(GpioCommand::Position(0.125)));
+        assert_eq!(position("101"), None);
+        assert_eq!(position("-1"), None);
+        assert_eq!(position("NaN"), None);
+        assert_eq!(position("half"), None);
+        // Only commands go on the command topic
+        assert_eq!(parse_command(&topics, &topics.command, "50"), None);
+    }
+
+    #[test]
+    fn ignores_retained_commands() {
+        let (door, mut command_rx) = DoorHandle::detached("default");
+        let settings = settings(door);
+
+        let mut publish = Publish::new("piopener/default/command", QoS::AtLeastOnce, "open");
+        publish.retain = true;
+        handle_publish(&settings, &publish);
+        let mut publish = Publish::new("piopener/default/set_position", QoS::AtLeastOnce, "50");
+        publish.retain = true;
+        handle_publish(&settings, &publish);
+        assert_eq!(sent(&mut command_rx), None);
+
+        handle_publish(&settings, &Publish::new("piopener/default/command", QoS::AtLeastOnce, "open"));
+        assert_eq!(sent(&mut command_rx), Some(GpioCommand::Open));
+        handle_publish(&settings, &Publish::new("piopener/default/set_position", QoS::AtLeastOnce, "50"));
+        assert_eq!(sent(&mut command_rx), Some(GpioCommand::Position(0.5)));
+        // Out of range positions never reach the door
+        handle_publish(&settings, &Publish::new("piopener/default/set_position", QoS::AtLeastOnce, "150"));
+        assert_eq!(sent(&mut command_rx), None);
+    }
+
+    #[test]
+    fn discovery_config_points_at_the_door_topics() {
+        let (door, _) = DoorHandle::detached("default");
+        let settings = settings(door);
+        let topics = &settings.doors[0].1;
+        let config = discovery_config(&settings, "piopener_default", "default", topics);
+
+        assert_eq!(config["unique_id"], "piopener_default");
+        assert_eq!(config["device_class"], "garage");
+        assert_eq!(config["device"]["identifiers"], json!(["piopener_default"]));
+        assert_eq!(config["availability_topic"], "piopener/availability");
+        assert_eq!(config["state_topic"], "piopener/default/state");
+        assert_eq!(config["position_topic"], "piopener/default/state");
+        assert_eq!(config["command_topic"], "piopener/default/command");
+        assert_eq!(config["set_position_topic"], "piopener/default/set_position");
+        assert_eq!((config["payload_open"].as_str(), config["payload_close"].as_str()), (Some("open"), Some("close")));
+        assert_eq!((config["position_open"].as_u64(), config["position_closed"].as_u64()), (Some(100), Some(0)));
+    }
+
+    // Run with `cargo test -- --ignored` with mosquitto listening on localhost:1883
+    #[tokio::test]
+    #[ignore = "needs an MQTT broker on localhost:1883"]
+    async fn talks_to_a_broker() {
+        let (client, mut eventloop) = AsyncClient::new(MqttOptions::new("piopener-test-peer", "localhost", 1883), 32);
+        // Left over from before the server connects, so the broker hands it out as retained
+        client.publish("piopener-test/default/command", QoS::AtLeastOnce, true, "close").await.unwrap();
+        client.subscribe("homeassistant/cover/piopener-test_default/config", QoS::AtLeastOnce).await.unwrap();
+        client.subscribe("piopener-test/default/state", QoS::AtLeastOnce).await.unwrap();
+        loop {
+            if let Event::Incoming(Packet::SubAck(_)) = eventloop.poll().await.unwrap() {
+                break;
+            }
+        }
+
+        let (door, mut command_rx) = DoorHandle::detached("default");
+        let config = MqttConfig {
+            host: "localhost".to_string(),
+            port: None,
+            client_id: Some("piopener-test".to_string()),
+            username: None,
+            password: None,
+            topic_prefix: None,
+            discovery_prefix: None,
+            discovery: None,
+        };
+        let handle = spawn(&config, &Doors::new(vec![door]));
+
+        let mut seen = Vec::new();
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+        while seen.len() < 2 {
+            let event = tokio::time::timeout_at(deadline, eventloop.poll()).await.unwrap().unwrap();
+            if let Event::Incoming(Packet::Publish(publish)) = event {
+                seen.push(publish.topic);
+            }
+        }
+        seen.sort();
+        assert_eq!(seen, ["homeassistant/cover/piopener-test_default/config", "piopener-test/default/state"]);
+
+        // The retained close is skipped and the live open runs
+        client.publish("piopener-test/default/command", QoS::AtLeastOnce, false, "open").await.unwrap();
+        let received = async {
+            loop {
+                tokio::select! {
+                    message = command_rx.recv() => break message,
+                    _ = eventloop.poll() => {}
+                }
+            }
+        };
+        let message = tokio::time::timeout(Duration::from_secs(10), received).await.unwrap();
+        assert!(matches!(message, Some(LoopMessage::Command(command)) if command.kind == GpioCommand::Open));
+
+        // Clear the retained command for the next run
+        client.publish("piopener-test/default/command", QoS::AtLeastOnce, true, "").await.unwrap();
+        let _ = tokio::time::timeout(Duration::from_secs(1), async {
+            loop {
+                eventloop.poll().await.unwrap();
+            }
+        })
+        .await;
+        handle.shutdown(Duration::from_secs(5)).await;
+    }
+}
@@ -0,0 +1,283 @@
+// Backends that alerts are sent through
+use std::{error::Error, time::Duration};
+use chrono::Utc;
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use reqwest::{header::CONTENT_TYPE, Client, Url};
+use serde::Serialize;
+use crate::{
+    alerts::AlertEvent,
+    config::{NotifierBackend, NotifierConfig, SmtpConfig, SmtpTls},
+    webhooks,
+};
+
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What people are told
+#[derive(Debug, Clone, Serialize)]
+pub struct Notification {
+    pub title: String,
+    pub message: String,
+    /// From 1 (least) to 5 (most), like ntfy priorities
+    #[serde(skip)]
+    pub urgency: u8,
+    /// `None` for test notifications
+    pub alert: Option<AlertEvent>,
+}
+
+#[derive(Debug)]
+pub struct Notifier {
+    pub name: String,
+    /// Only notified from this reminder on
+    pub from_reminder: u32,
+    backend: Backend,
+}
+
+#[derive(Debug)]
+enum Backend {
+    Webhook { url: Url, secret: Option<String> },
+    Ntfy { url: Url, token: Option<String> },
+    Smtp {
+        transport: AsyncSmtpTransport<Tokio1Executor>,
+        from: Mailbox,
+        to: Vec<Mailbox>,
+    },
+}
+
+impl Notifier {
+    pub fn new(config: &NotifierConfig) -> Result<Self, Box<dyn Error>> {
+        let parse_url = |url: &str| Url::parse(url).map_err(|err| format!("Invalid URL for notifier {}: {}", config.name, err));
+        let backend = match &config.backend {
+            NotifierBackend::Webhook { url, secret } => Backend::Webhook {
+                url: parse_url(url)?,
+                secret: secret.clone(),
+            },
+            NotifierBackend::Ntfy { url, token } => Backend::Ntfy {
+                url: parse_url(url)?,
+                token: token.clone(),
+            },
+            NotifierBackend::Smtp(smtp) => smtp_backend(smtp)?,
+        };
+        Ok(Self {
+            name: config.name.clone(),
+            from_reminder: config.from_reminder.unwrap_or(0),
+            backend,
+        })
+    }
+
+    pub async fn send(&self, client: &Client, notification: &Notification) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match &self.backend {
+            Backend::Webhook { url, secret } => {
+                let body = serde_json::to_vec(notification)?;
+                let timestamp = Utc::now().timestamp().to_string();
+                let mut request = client
+                    .post(url.clone())
+                    .header(CONTENT_TYPE, "application/json")
+                    .header("X-PiOpener-Event", "left_open")
+                    .header("X-PiOpener-Timestamp", &timestamp);
+                if let Some(secret) = secret {
+                    request = request.header("X-PiOpener-Signature", format!("sha256={}", webhooks::sign(secret, &timestamp, &body)));
+                }
+                request.body(body).send().await?.error_for_status()?;
+            }
+            Backend::Ntfy { url, token } => {
+                let mut request = client
+                    .post(url.clone())
+                    .header("Title", &notification.title)
+                    .header("Priority", notification.urgency.to_string())
+                    .header("Tags", if notification.urgency > 2 { "warning" } else { "white_check_mark" });
+                if let Some(token) = token {
+                    request = request.bearer_auth(token);
+                }
+                request.body(notification.message.clone()).send().await?.error_for_status()?;
+            }
+            Backend::Smtp { transport, from, to } => {
+                let mut message = Message::builder().from(from.clone()).subject(&notification.title);
+                for to in to {
+                    message = message.to(to.clone());
+                }
+                let message = message
+                    .header(ContentType::TEXT_PLAIN)
+                    .body(notification.message.clone())?;
+                transport.send(message).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn smtp_backend(config: &SmtpConfig) -> Result<Backend, Box<dyn Error>> {
+    let mut builder = match config.tls.unwrap_or(SmtpTls::Starttls) {
+        SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host)?,
+        SmtpTls::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)?,
+        // For a relay on the local network, or a local stand-in
+        SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.host),
+    }
+    .timeout(Some(TIMEOUT));
+    if let Some(port) = config.port {
+        builder = builder.port(port);
+    }
+    if let (Some(username), Some(password)) = (&config.username, &config.password) {
+        builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+    }
+    if config.to.is_empty() {
+        return Err("SMTP notifiers need at least one address in to".into());
+    }
+    Ok(Backend::Smtp {
+        transport: builder.build(),
+        from: config.from.parse()?,
+        to: config.to.iter().map(|to| to.parse()).collect::<Result<_, _>>()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Bytes, http::HeaderMap, routing::post, Router};
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+        sync::{mpsc, oneshot},
+    };
+    use crate::config::NotifierBackend;
+    use super::*;
+
+    fn notification() -> Notification {
+        Notification {
+            title: "PiOpener: left open".to_string(),
+            message: "Door default has not been closed for 10 min".to_string(),
+            urgency: 4,
+            alert: None,
+        }
+    }
+
+    fn notifier(backend: NotifierBackend) -> Notifier {
+        Notifier::new(&NotifierConfig {
+            name: "test".to_string(),
+            from_reminder: None,
+            backend,
+        })
+        .unwrap()
+    }
+
+    // An HTTP endpoint that passes on every request it gets
+    async fn http_stand_in() -> (String, mpsc::UnboundedReceiver<(HeaderMap, Bytes)>) {
+        let (requests_tx, requests_rx) = mpsc::unbounded_channel();
+        let app = Router::new().route(
+            "/notify",
+            post(move |headers: HeaderMap, body: Bytes| async move {
+                requests_tx.send((headers, body)).unwrap();
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/notify", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (url, requests_rx)
+    }
+
+    // Just enough of an SMTP server to take one message. Returns the port and everything the client sent.
+    async fn smtp_stand_in() -> (u16, oneshot::Receiver<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (transcript_tx, transcript_rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            let mut transcript = Vec::new();
+            let mut in_data = false;
+            writer.write_all(b"220 stand-in ESMTP\r\n").await.unwrap();
+            while let Some(line) = lines.next_line().await.unwrap() {
+                let reply: &[u8] = if in_data {
+                    in_data = line != ".";
+                    if in_data { b"" } else { b"250 Queued\r\n" }
+                } else if line.starts_with("DATA") {
+                    in_data = true;
+                    b"354 Go ahead\r\n"
+                } else if line.starts_with("QUIT") {
+                    b"221 Bye\r\n"
+                } else {
+                    b"250 OK\r\n"
+                };
+                writer.write_all(reply).await.unwrap();
+                let quit = line.starts_with("QUIT");
+                transcript.push(line);
+                if quit {
+                    break;
+                }
+            }
+            transcript_tx.send(transcript).unwrap();
+        });
+        (port, transcript_rx)
+    }
+
+    #[tokio::test]
+    async fn webhook_posts_signed_json() {
+        let (url, mut requests_rx) = http_stand_in().await;
+        let notifier = notifier(NotifierBackend::Webhook {
+            url,
+            secret: Some("secret".to_string()),
+        });
+        notifier.send(&Client::new(), &notification()).await.unwrap();
+
+        let (headers, body) = requests_rx.recv().await.unwrap();
+        assert_eq!(headers[CONTENT_TYPE], "application/json");
+        assert_eq!(headers["x-piopener-event"], "left_open");
+        let timestamp = headers["x-piopener-timestamp"].to_str().unwrap();
+        assert_eq!(
+            headers["x-piopener-signature"].to_str().unwrap(),
+            format!("sha256={}", webhooks::sign("secret", timestamp, &body)),
+        );
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "title": "PiOpener: left open",
+                "message": "Door default has not been closed for 10 min",
+                "alert": null,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn ntfy_posts_the_message_with_headers() {
+        let (url, mut requests_rx) = http_stand_in().await;
+        let notifier = notifier(NotifierBackend::Ntfy {
+            url,
+            token: Some("token".to_string()),
+        });
+        notifier.send(&Client::new(), &notification()).await.unwrap();
+
+        let (headers, body) = requests_rx.recv().await.unwrap();
+        assert_eq!(headers["title"], "PiOpener: left open");
+        assert_eq!(headers["priority"], "4");
+        assert_eq!(headers["tags"], "warning");
+        assert_eq!(headers["authorization"], "Bearer token");
+        assert_eq!(body, "Door default has not been closed for 10 min");
+    }
+
+    #[tokio::test]
+    async fn smtp_sends_a_plain_text_mail() {
+        let (port, transcript_rx) = smtp_stand_in().await;
+        let notifier = notifier(NotifierBackend::Smtp(SmtpConfig {
+            host: "127.0.0.1".to_string(),
+            port: Some(port),
+            tls: Some(SmtpTls::None),
+            username: None,
+            password: None,
+            from: "PiOpener <piopener@example.com>".to_string(),
+            to: vec!["someone@example.com".to_string(), "else@example.com".to_string()],
+        }));
+        notifier.send(&Client::new(), &notification()).await.unwrap();
+
+        let transcript = transcript_rx.await.unwrap();
+        assert!(transcript.contains(&"MAIL FROM:<piopener@example.com>".to_string()));
+        assert!(transcript.contains(&"RCPT TO:<someone@example.com>".to_string()));
+        assert!(transcript.contains(&"RCPT TO:<else@example.com>".to_string()));
+        assert!(transcript.contains(&"Subject: PiOpener: left open".to_string()));
+        assert!(transcript.contains(&"Content-Type: text/plain; charset=utf-8".to_string()));
+        assert!(transcript.contains(&"Door default has not been closed for 10 min".to_string()));
+    }
+}
@@ -0,0 +1,69 @@
+use utoipa::{
+    openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+    Modify, OpenApi,
+};
+
+/// OpenAPI document for the HTTP API, served at `/openapi.json`
+pub fn document() -> utoipa::openapi::OpenApi {
+    let mut openapi = ApiDoc::openapi();
+    // utoipa fills this in from Cargo.toml, which doesn't name one
+    openapi.info.license = None;
+    openapi
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "PiOpener", description = "Garage door opener API"),
+    paths(
+        crate::doors_handler,
+        crate::current_status_handler,
+        crate::watch_status_handler,
+        crate::open_door,
+        crate::close_door,
+        crate::toggle_door,
+        crate::stop_door,
+        crate::position_door,
+        crate::command_status_handler,
+        crate::cancel_warning,
+        crate::cancel_auto_close,
+        crate::calibration_handler,
+        crate::inputs_handler,
+        crate::ws::ws_handler,
+        crate::list_schedules,
+        crate::add_schedule,
+        crate::remove_schedule,
+        crate::skip_schedule,
+        crate::unskip_schedule,
+        crate::schedule_runs,
+        crate::history_handler,
+        crate::webhook_deliveries,
+        crate::test_notifier,
+        crate::metrics_handler,
+        crate::openapi_handler,
+    ),
+    // Only referenced from query parameters, which don't pull it in themselves
+    components(schemas(crate::history::EventType)),
+    tags(
+        (name = "door", description = "Every door route is also at `/api/v1/doors/{door}/...` for doors other than the default one"),
+    ),
+    modifiers(&BearerAuth),
+    security(("bearer" = [])),
+)]
+struct ApiDoc;
+
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .description(Some("The `api_key` from the server config"))
+                    .build(),
+            ),
+        );
+    }
+}
@@ -0,0 +1,350 @@
+use std::{collections::VecDeque, fmt, str::FromStr, sync::{Arc, Mutex}, time::Duration};
+use chrono::{DateTime, Local};
+use cron::Schedule;
+use serde::Serialize;
+use utoipa::ToSchema;
+use crate::{
+    commands::CommandSource,
+    config::{ScheduleAction, ScheduleCondition, ScheduleConfig},
+    controller::{CommandId, CommandProgress, DoorStatus, GpioCommand},
+    door::{DoorHandle, Doors},
+};
+
+// How many past runs to remember
+const HISTORY_LEN: usize = 64;
+
+pub type ScheduleId = u64;
+
+impl ScheduleAction {
+    pub fn command(self) -> GpioCommand {
+        match self {
+            ScheduleAction::Open => GpioCommand::Open,
+            ScheduleAction::Close => GpioCommand::Close,
+            ScheduleAction::Toggle => GpioCommand::Toggle,
+        }
+    }
+}
+
+impl ScheduleCondition {
+    fn holds(&self, status: DoorStatus) -> bool {
+        match self {
+            ScheduleCondition::Open => status == DoorStatus::Open,
+            ScheduleCondition::Closed => status == DoorStatus::Closed,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ScheduleError {
+    InvalidCron(cron::error::Error),
+    UnknownDoor(String),
+}
+
+impl fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScheduleError::InvalidCron(err) => write!(f, "Invalid cron expression: {}", err),
+            ScheduleError::UnknownDoor(id) => write!(f, "Unknown door: {}", id),
+        }
+    }
+}
+
+impl std::error::Error for ScheduleError {}
+
+#[derive(Debug, Clone)]
+pub struct ScheduledAction {
+    pub id: ScheduleId,
+    /// The door this schedule acts on
+    pub door: String,
+    pub config: ScheduleConfig,
+    schedule: Schedule,
+    /// When this schedule fires next
+    pub next: Option<DateTime<Local>>,
+    /// The run at `next` will be skipped
+    pub skip_next: bool,
+}
+
+impl ScheduledAction {
+    fn new(id: ScheduleId, door: String, config: ScheduleConfig) -> Result<Self, ScheduleError> {
+        let schedule = parse_cron(&config.cron).map_err(ScheduleError::InvalidCron)?;
+        let next = schedule.upcoming(Local).next();
+        Ok(Self {
+            id,
+            door,
+            config,
+            schedule,
+            next,
+            skip_next: false,
+        })
+    }
+
+    /// The next `count` times this schedule fires
+    pub fn upcoming(&self, count: usize) -> Vec<DateTime<Local>> {
+        match self.next {
+            Some(next) => std::iter::once(next).chain(self.schedule.after(&next)).take(count).collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+// Accept the usual five cron fields as well as the six or seven the cron crate wants
+fn parse_cron(expression: &str) -> Result<Schedule, cron::error::Error> {
+    let mut fields: Vec<String> = expression.split_whitespace().map(str::to_string).collect();
+    if fields.len() == 5 {
+        fields.insert(0, "0".to_string());
+    }
+    if let Some(days) = fields.get_mut(5) {
+        *days = day_names(days);
+    }
+    Schedule::from_str(&fields.join(" "))
+}
+
+const DAY_NAMES: [&str; 7] = ["SUN", "MON", "TUE", "WED", "THU", "FRI", "SAT"];
+
+// Usual cron numbers the weekdays from 0 or 7 for Sunday, the cron crate from 1 for Sunday.
+// Spelling numbered days out as names keeps "1-5" meaning Monday to Friday.
+fn day_names(field: &str) -> String {
+    let items: Vec<String> = field
+        .split(',')
+        .map(|item| {
+            let (range, step) = match item.split_once('/') {
+                Some((range, step)) => (range, step.parse::<usize>().ok().filter(|step| *step > 0)),
+                None => (item, Some(1)),
+            };
+            let bounds = match range.split_once('-') {
+                Some((first, last)) => first.parse().ok().zip(last.parse().ok()),
+                None if range == "*" => Some((0, 6)),
+                // "n/step" runs from n to Saturday
+                None => range.parse().ok().map(|day| (day, if item.contains('/') { 6 } else { day })),
+            };
+            match (bounds, step) {
+                _ if item == "*" || item == "?" => item.to_string(),
+                (Some((first, last)), Some(step)) if first <= last && last <= 7 => {
+                    let mut days: Vec<&str> = Vec::new();
+                    for day in (first..=last).step_by(step) {
+                        if !days.contains(&DAY_NAMES[day % 7]) {
+                            days.push(DAY_NAMES[day % 7]);
+                        }
+                    }
+                    days.join(",")
+                }
+                // Names already, or something the cron crate will reject
+                _ => item.to_string(),
+            }
+        })
+        .collect();
+    items.join(",")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+#[serde(tag = "outcome", content = "command", rename_all = "snake_case")]
+pub enum RunOutcome {
+    /// Skipped through the API
+    Skipped,
+    /// The door was not in the state the schedule asks for
+    ConditionNotMet,
+    GpioLoopStopped,
+    /// The command was sent, this is its latest progress
+    Command(CommandProgress),
+}
+
+#[derive(Debug, Clone)]
+pub struct ScheduleRun {
+    pub schedule_id: ScheduleId,
+    pub door: String,
+    pub name: String,
+    pub time: DateTime<Local>,
+    pub outcome: RunOutcome,
+}
+
+/// Keeps the schedules and their past runs. Cloning it shares the same schedules.
+#[derive(Debug, Clone)]
+pub struct Scheduler {
+    inner: Arc<Mutex<SchedulerInner>>,
+    doors: Doors,
+}
+
+#[derive(Debug, Default)]
+struct SchedulerInner {
+    next_id: ScheduleId,
+    schedules: Vec<ScheduledAction>,
+    runs: VecDeque<ScheduleRun>,
+}
+
+impl Scheduler {
+    pub fn new(configs: &[ScheduleConfig], doors: Doors) -> Result<Self, ScheduleError> {
+        let scheduler = Self {
+            inner: Arc::default(),
+            doors,
+        };
+        for config in configs {
+            scheduler.add(config.clone())?;
+        }
+        Ok(scheduler)
+    }
+
+    pub fn add(&self, config: ScheduleConfig) -> Result<ScheduledAction, ScheduleError> {
+        let door = match &config.door {
+            Some(id) => self.doors.get(id).ok_or_else(|| ScheduleError::UnknownDoor(id.clone()))?,
+            None => self.doors.default_door(),
+        };
+        let mut inner = self.inner.lock().unwrap();
+        let action = ScheduledAction::new(inner.next_id + 1, door.id.clone(), config)?;
+        inner.next_id = action.id;
+        inner.schedules.push(action.clone());
+        Ok(action)
+    }
+
+    pub fn remove(&self, id: ScheduleId) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let len = inner.schedules.len();
+        inner.schedules.retain(|action| action.id != id);
+        inner.schedules.len() != len
+    }
+
+    pub fn schedules(&self) -> Vec<ScheduledAction> {
+        self.inner.lock().unwrap().schedules.clone()
+    }
+
+    /// Skip the next run of a schedule, or undo that
+    pub fn set_skip_next(&self, id: ScheduleId, skip: bool) -> Option<ScheduledAction> {
+        let mut inner = self.inner.lock().unwrap();
+        let action = inner.schedules.iter_mut().find(|action| action.id == id)?;
+        action.skip_next = skip;
+        Some(action.clone())
+    }
+
+    /// Past runs, newest first
+    pub fn runs(&self) -> Vec<ScheduleRun> {
+        self.inner.lock().unwrap().runs.iter().rev().cloned().collect()
+    }
+
+    /// Start the task that runs schedules as they come due
+    pub fn spawn(&self) {
+        let scheduler = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                interval.tick().await;
+                for action in scheduler.take_due(Local::now()) {
+                    // Doors can't go away, so this always finds one
+                    if let Some(door) = scheduler.doors.get(&action.door) {
+                        scheduler.run(action, door);
+                    }
+                }
+            }
+        });
+    }
+
+    // Schedules that fired by `now`, as they were before moving them on to their next run
+    fn take_due(&self, now: DateTime<Local>) -> Vec<ScheduledAction> {
+        let mut inner = self.inner.lock().unwrap();
+        let mut due = Vec::new();
+        for action in &mut inner.schedules {
+            if action.next.is_some_and(|next| next <= now) {
+                due.push(action.clone());
+                // If we fell behind, only run once and continue from now
+                action.next = action.schedule.after(&now).next();
+                action.skip_next = false;
+            }
+        }
+        due
+    }
+
+    fn run(&self, action: ScheduledAction, door: &DoorHandle) {
+        let status = door.state.borrow().status;
+        let mut progress_rx = None;
+        let outcome = if action.skip_next {
+            RunOutcome::Skipped
+        } else if action.config.only_if.is_some_and(|condition| !condition.holds(status)) {
+            RunOutcome::ConditionNotMet
+        } else {
+            match door.commands.send(action.config.action.command(), CommandSource::Schedule) {
+                Ok(rx) => {
+                    let progress = *rx.borrow();
+                    progress_rx = Some(rx);
+                    RunOutcome::Command(progress)
+                }
+                Err(_) => RunOutcome::GpioLoopStopped,
+            }
+        };
+
+        {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.runs.len() >= HISTORY_LEN {
+                inner.runs.pop_front();
+            }
+            inner.runs.push_back(ScheduleRun {
+                schedule_id: action.id,
+                door: action.door,
+                name: action.config.name,
+                time: action.next.unwrap_or_else(Local::now),
+                outcome,
+            });
+        }
+
+        // Fill in how the command ended once it has
+        if let (Some(progress_rx), RunOutcome::Command(sent)) = (progress_rx, outcome) {
+            let scheduler = self.clone();
+            let commands = door.commands.clone();
+            tokio::spawn(async move {
+                let progress = commands.wait(progress_rx).await;
+                scheduler.update_run(sent.id, progress);
+            });
+        }
+    }
+
+    fn update_run(&self, id: CommandId, progress: CommandProgress) {
+        let mut inner = self.inner.lock().unwrap();
+        let run = inner
+            .runs
+            .iter_mut()
+            .find(|run| matches!(run.outcome, RunOutcome::Command(sent) if sent.id == id));
+        if let Some(run) = run {
+            run.outcome = RunOutcome::Command(progress);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Datelike, TimeZone, Weekday};
+    use super::*;
+
+    fn runs(expression: &str) -> Vec<DateTime<Local>> {
+        let start = Local.with_ymd_and_hms(2025, 6, 1, 0, 0, 0).unwrap();
+        parse_cron(expression).unwrap().after(&start).take(14).collect()
+    }
+
+    #[test]
+    fn numbers_weekdays_from_sunday_as_zero() {
+        assert_eq!(day_names("1-5"), "MON,TUE,WED,THU,FRI");
+        assert_eq!(day_names("0"), "SUN");
+        assert_eq!(day_names("7"), "SUN");
+        assert_eq!(day_names("0,6"), "SUN,SAT");
+        assert_eq!(day_names("5-7"), "FRI,SAT,SUN");
+        assert_eq!(day_names("0-7"), "SUN,MON,TUE,WED,THU,FRI,SAT");
+        assert_eq!(day_names("*/2"), "SUN,TUE,THU,SAT");
+        assert_eq!(day_names("1-5/2"), "MON,WED,FRI");
+        assert_eq!(day_names("*"), "*");
+        assert_eq!(day_names("Mon-Fri"), "Mon-Fri");
+    }
+
+    #[test]
+    fn weekday_schedules_skip_the_weekend() {
+        let weekdays = runs("30 7 * * 1-5");
+        assert_eq!(weekdays, runs("30 7 * * Mon-Fri"));
+        assert!(weekdays.iter().all(|time| time.weekday().number_from_monday() <= 5));
+        assert_eq!(weekdays[0].weekday(), Weekday::Mon);
+
+        // With a seconds field the weekday is the sixth field
+        assert_eq!(runs("0 30 7 * * 1-5"), weekdays);
+        assert_eq!(runs("0 0 * * 0"), runs("0 0 * * 7"));
+        assert!(runs("0 0 * * 0").iter().all(|time| time.weekday() == Weekday::Sun));
+    }
+
+    #[test]
+    fn rejects_days_past_seven() {
+        assert!(parse_cron("0 0 * * 8").is_err());
+    }
+}
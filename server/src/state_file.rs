@@ -0,0 +1,75 @@
+use std::{fs, io, path::Path};
+use serde::{Deserialize, Serialize};
+use crate::{calibration::TravelCalibration, controller::DoorSetpoint};
+
+/// What the controller remembers across restarts. It is checked against the
+/// limit switches before being trusted, see `DoorController::restore`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SavedState {
+    pub status: SavedStatus,
+    #[serde(flatten)]
+    pub setpoint: DoorSetpoint,
+    /// Only saved while the door is standing still
+    pub position: Option<f64>,
+    /// 1 if the door last moved up, -1 if down, 0 if we don't know
+    pub last_direction: f64,
+    pub calibration: TravelCalibration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SavedStatus {
+    Closed,
+    Open,
+    Ajar,
+    Moving,
+    /// Unknown or faulted
+    Unknown,
+}
+
+impl SavedState {
+    /// Load the state saved by a previous run, if there is one
+    pub fn load(path: &Path) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        match fs::read_to_string(path) {
+            Ok(contents) => Ok(Some(serde_json::from_str(&contents)?)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        write_json(path, self)
+    }
+}
+
+/// Write `value` to `path` as pretty JSON. The file is written under a temporary
+/// name first and renamed over `path`, so a power cut can't leave half a file behind.
+pub fn write_json(path: &Path, value: &impl Serialize) -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_string_pretty(value)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_the_file() {
+        let path = std::env::temp_dir().join(format!("piopener-state-{}.json", std::process::id()));
+        assert_eq!(SavedState::load(&path).unwrap(), None);
+
+        let saved = SavedState {
+            status: SavedStatus::Ajar,
+            setpoint: DoorSetpoint::Ajar,
+            position: Some(0.4),
+            last_direction: 1.0,
+            calibration: TravelCalibration::default(),
+        };
+        saved.save(&path).unwrap();
+        let loaded = SavedState::load(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap(), Some(saved));
+    }
+}
@@ -0,0 +1,426 @@
+// POSTs door events as JSON to the configured webhooks
+use std::{
+    collections::VecDeque,
+    error::Error,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use chrono::{DateTime, Local, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::{header::CONTENT_TYPE, Client, StatusCode, Url};
+use serde::Serialize;
+use sha2::Sha256;
+use tokio::sync::{broadcast::error::RecvError, mpsc};
+use utoipa::ToSchema;
+use crate::{
+    config::{WebhookConfig, WebhookEvent},
+    alerts::AlertEvent,
+    controller::{CommandPhase, CommandProgress, DoorStatus},
+    current_status,
+    door::{DoorHandle, Doors},
+    StatusResponse,
+};
+
+// How many deliveries to remember for `/webhooks/deliveries`
+const LOG_LEN: usize = 100;
+// Events waiting for a webhook that is down. Newer ones are dropped once it is full.
+const QUEUE_LEN: usize = 64;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// The delay before the first retry, doubling for every one after it
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(2);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+
+pub type DeliveryId = u64;
+
+/// An event sent, or meant to be sent, to one webhook
+#[derive(Debug, Clone)]
+pub struct Delivery {
+    pub id: DeliveryId,
+    pub url: String,
+    pub event: WebhookEvent,
+    pub door: String,
+    /// When the event happened
+    pub time: DateTime<Local>,
+    pub attempts: u32,
+    pub outcome: DeliveryOutcome,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum DeliveryOutcome {
+    /// Not sent yet, or waiting to be retried
+    Pending {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    Delivered {
+        status: u16,
+    },
+    /// Out of attempts, or the webhook answered with an error retrying won't fix
+    Failed {
+        error: String,
+    },
+    /// The queue was full because the webhook kept failing
+    Dropped,
+}
+
+// Body of every request
+#[derive(Serialize)]
+struct Payload<'a> {
+    event: WebhookEvent,
+    door: &'a str,
+    time: String,
+    // What `/status` returns for the door
+    status: StatusResponse,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command: Option<CommandProgress>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    alert: Option<AlertEvent>,
+}
+
+struct Job {
+    delivery: DeliveryId,
+    event: WebhookEvent,
+    body: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct Target {
+    config: WebhookConfig,
+    tx: mpsc::Sender<Job>,
+}
+
+impl Target {
+    fn wants(&self, event: WebhookEvent) -> bool {
+        self.config.events.is_empty() || self.config.events.contains(&event)
+    }
+}
+
+#[derive(Debug, Default)]
+struct DeliveryLog {
+    next_id: DeliveryId,
+    deliveries: VecDeque<Delivery>,
+}
+
+/// The configured webhooks and what was sent to them. Cloning it shares the same webhooks.
+#[derive(Debug, Clone)]
+pub struct Webhooks {
+    targets: Arc<Vec<Target>>,
+    log: Arc<Mutex<DeliveryLog>>,
+}
+
+impl Webhooks {
+    /// Start a sender for every webhook and watch the doors for events
+    pub fn spawn(configs: &[WebhookConfig], doors: &Doors) -> Result<Self, Box<dyn Error>> {
+        let client = Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .user_agent(concat!("PiOpener/", env!("CARGO_PKG_VERSION")))
+            .build()?;
+        let log = Arc::new(Mutex::new(DeliveryLog::default()));
+
+        let mut targets = Vec::new();
+        for config in configs {
+            let url = Url::parse(&config.url).map_err(|err| format!("Invalid webhook URL {}: {}", config.url, err))?;
+            let (tx, rx) = mpsc::channel(QUEUE_LEN);
+            tokio::spawn(deliver(client.clone(), url, config.clone(), rx, log.clone()));
+            targets.push(Target { config: config.clone(), tx });
+        }
+
+        let webhooks = Self {
+            targets: Arc::new(targets),
+            log,
+        };
+        if !webhooks.targets.is_empty() {
+            for door in doors.iter() {
+                tokio::spawn(watch_door(webhooks.clone(), door.clone()));
+            }
+        }
+        Ok(webhooks)
+    }
+
+    /// Recent deliveries, oldest first
+    pub fn deliveries(&self) -> Vec<Delivery> {
+        self.log.lock().unwrap().deliveries.iter().cloned().collect()
+    }
+
+    /// Send a left-open alert, or its reminder or clearing
+    pub fn alert(&self, door: &DoorHandle, alert: &AlertEvent) {
+        self.send(WebhookEvent::LeftOpen, door, None, Some(alert.clone()));
+    }
+
+    /// Queue an event for every webhook that wants it
+    fn send(&self, event: WebhookEvent, door: &DoorHandle, command: Option<CommandProgress>, alert: Option<AlertEvent>) {
+        let targets: Vec<_> = self.targets.iter().filter(|target| target.wants(event)).collect();
+        if targets.is_empty() {
+            return;
+        }
+        let time = Local::now();
+        let payload = Payload {
+            event,
+            door: &door.id,
+            time: time.to_rfc3339(),
+            status: current_status(door),
+            command,
+            alert,
+        };
+        let body = serde_json::to_vec(&payload).expect("Payloads always serialize");
+
+        for target in targets {
+            let delivery = self.log.lock().unwrap().add(Delivery {
+                id: 0,
+                url: target.config.url.clone(),
+                event,
+                door: door.id.clone(),
+                time,
+                attempts: 0,
+                outcome: DeliveryOutcome::Pending { error: None },
+            });
+            let job = Job {
+                delivery,
+                event,
+                body: body.clone(),
+            };
+            if target.tx.try_send(job).is_err() {
+                eprintln!("Webhook {} is too far behind, dropping a {:?} event", target.config.url, event);
+                self.log.lock().unwrap().update(delivery, 0, DeliveryOutcome::Dropped);
+            }
+        }
+    }
+}
+
+impl DeliveryLog {
+    fn add(&mut self, mut delivery: Delivery) -> DeliveryId {
+        self.next_id += 1;
+        delivery.id = self.next_id;
+        if self.deliveries.len() >= LOG_LEN {
+            self.deliveries.pop_front();
+        }
+        self.deliveries.push_back(delivery);
+        self.next_id
+    }
+
+    fn update(&mut self, id: DeliveryId, attempts: u32, outcome: DeliveryOutcome) {
+        if let Some(delivery) = self.deliveries.iter_mut().find(|delivery| delivery.id == id) {
+            delivery.attempts = attempts;
+            delivery.outcome = outcome;
+        }
+    }
+}
+
+// Turn state changes and command progress into events
+async fn watch_door(webhooks: Webhooks, door: DoorHandle) {
+    let mut state_rx = door.state.subscribe();
+    // Command progress comes from the tracker rather than the door state, which
+    // only holds the active command and can skip phases between updates
+    let mut command_rx = door.commands.tracker().events();
+    let mut last = *state_rx.borrow_and_update();
+    loop {
+        tokio::select! {
+            changed = state_rx.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+                let state = *state_rx.borrow_and_update();
+                if state.status != last.status || state.setpoint != last.setpoint {
+                    let event = match state.status {
+                        DoorStatus::Fault { .. } => WebhookEvent::Fault,
+                        _ => WebhookEvent::State,
+                    };
+                    webhooks.send(event, &door, None, None);
+                }
+                last = state;
+            }
+            progress = command_rx.recv() => match progress {
+                // A command is sent when it is received and again when it finishes
+                Ok(progress) if progress.phase == CommandPhase::Pending || progress.phase.is_finished() => {
+                    webhooks.send(WebhookEvent::Command, &door, Some(progress), None);
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(missed)) => {
+                    eprintln!("Webhooks fell behind on door {}, {} command updates were not sent", door.id, missed);
+                }
+                Err(RecvError::Closed) => break,
+            },
+        }
+    }
+}
+
+// Send the events for one webhook in order, retrying each with backoff
+async fn deliver(client: Client, url: Url, config: WebhookConfig, mut rx: mpsc::Receiver<Job>, log: Arc<Mutex<DeliveryLog>>) {
+    let max_attempts = config.max_attempts.unwrap_or(5).max(1);
+    while let Some(job) = rx.recv().await {
+        let mut delay = FIRST_RETRY_DELAY;
+        for attempt in 1..=max_attempts {
+            let (outcome, retry) = match post(&client, &url, &config, &job).await {
+                Ok(status) => (DeliveryOutcome::Delivered { status: status.as_u16() }, false),
+                Err((error, retry)) if retry && attempt < max_attempts => {
+                    (DeliveryOutcome::Pending { error: Some(error) }, retry)
+                }
+                Err((error, _)) => {
+                    eprintln!("Webhook {} failed: {}", config.url, error);
+                    (DeliveryOutcome::Failed { error }, false)
+                }
+            };
+            log.lock().unwrap().update(job.delivery, attempt, outcome);
+            if !retry {
+                break;
+            }
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        }
+    }
+}
+
+// One attempt. Errors say whether another attempt could go better.
+async fn post(client: &Client, url: &Url, config: &WebhookConfig, job: &Job) -> Result<StatusCode, (String, bool)> {
+    let timestamp = Utc::now().timestamp().to_string();
+    let mut request = client
+        .post(url.clone())
+        .header(CONTENT_TYPE, "application/json")
+        .header("X-PiOpener-Event", event_name(job.event))
+        .header("X-PiOpener-Delivery", job.delivery.to_string())
+        .header("X-PiOpener-Timestamp", &timestamp);
+    if let Some(secret) = &config.secret {
+        request = request.header("X-PiOpener-Signature", format!("sha256={}", sign(secret, &timestamp, &job.body)));
+    }
+
+    match request.body(job.body.clone()).send().await {
+        Ok(response) if response.status().is_success() => Ok(response.status()),
+        Ok(response) => {
+            let status = response.status();
+            // Other client errors mean the webhook doesn't want this request as it is
+            let retry = status.is_server_error()
+                || status == StatusCode::REQUEST_TIMEOUT
+                || status == StatusCode::TOO_MANY_REQUESTS;
+            Err((format!("HTTP {}", status), retry))
+        }
+        Err(err) => Err((err.to_string(), true)),
+    }
+}
+
+// Hex HMAC-SHA256 of "<timestamp>.<body>", so a captured request can't be replayed later with a new timestamp
+pub fn sign(secret: &str, timestamp: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// The name the config and the payloads use for an event
+fn event_name(event: WebhookEvent) -> String {
+    let value = serde_json::to_value(event).expect("Events always serialize");
+    value.as_str().unwrap_or_default().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use axum::{http::HeaderMap, routing::post, Router};
+    use super::*;
+
+    #[test]
+    fn signs_the_timestamp_and_body() {
+        assert_eq!(
+            sign("Jefe", "1700000000", b"what do ya want for nothing?"),
+            "1cdd0650c8be1cb0974b1788d458b1e781206cfef59b85faafc582d2e182c57e",
+        );
+    }
+
+    #[test]
+    fn names_events_like_the_config() {
+        assert_eq!(event_name(WebhookEvent::State), "state");
+        assert_eq!(event_name(WebhookEvent::LeftOpen), "left_open");
+    }
+
+    // A webhook that answers with `statuses` in turn and passes on the headers of every request
+    async fn stand_in(statuses: &'static [u16]) -> (Url, mpsc::UnboundedReceiver<HeaderMap>) {
+        let (headers_tx, headers_rx) = mpsc::unbounded_channel();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let app = Router::new().route(
+            "/hook",
+            post(move |headers: HeaderMap| async move {
+                headers_tx.send(headers).unwrap();
+                StatusCode::from_u16(statuses[requests.fetch_add(1, Ordering::SeqCst)]).unwrap()
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/hook", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (url, headers_rx)
+    }
+
+    // Start a sender for the webhook with one event queued
+    fn send_one(url: &Url, log: &Arc<Mutex<DeliveryLog>>) -> (DeliveryId, tokio::task::JoinHandle<()>) {
+        let config = WebhookConfig {
+            url: url.to_string(),
+            secret: Some("secret".to_string()),
+            events: Vec::new(),
+            max_attempts: Some(3),
+        };
+        let delivery = log.lock().unwrap().add(Delivery {
+            id: 0,
+            url: config.url.clone(),
+            event: WebhookEvent::State,
+            door: "default".to_string(),
+            time: Local::now(),
+            attempts: 0,
+            outcome: DeliveryOutcome::Pending { error: None },
+        });
+        let (tx, rx) = mpsc::channel(1);
+        tx.try_send(Job {
+            delivery,
+            event: WebhookEvent::State,
+            body: b"{}".to_vec(),
+        })
+        .unwrap();
+        // Dropping the sender lets the task end once the job is done
+        (delivery, tokio::spawn(deliver(Client::new(), url.clone(), config, rx, log.clone())))
+    }
+
+    fn outcome(log: &Arc<Mutex<DeliveryLog>>, id: DeliveryId) -> (u32, DeliveryOutcome) {
+        let log = log.lock().unwrap();
+        let delivery = log.deliveries.iter().find(|delivery| delivery.id == id).unwrap();
+        (delivery.attempts, delivery.outcome.clone())
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors() {
+        let (url, mut headers_rx) = stand_in(&[500, 200]).await;
+        let log = Arc::default();
+        let (id, task) = send_one(&url, &log);
+
+        let headers = headers_rx.recv().await.unwrap();
+        assert_eq!(headers["x-piopener-event"], "state");
+        let timestamp = headers["x-piopener-timestamp"].to_str().unwrap();
+        assert_eq!(
+            headers["x-piopener-signature"].to_str().unwrap(),
+            format!("sha256={}", sign("secret", timestamp, b"{}")),
+        );
+        // Pending again while it waits out the retry delay
+        while outcome(&log, id).0 == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(matches!(outcome(&log, id), (1, DeliveryOutcome::Pending { error: Some(error) }) if error.contains("500")));
+
+        task.await.unwrap();
+        assert!(matches!(outcome(&log, id), (2, DeliveryOutcome::Delivered { status: 200 })));
+        assert_eq!(headers_rx.recv().await.unwrap()["x-piopener-delivery"], id.to_string());
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_client_errors() {
+        let (url, mut headers_rx) = stand_in(&[404]).await;
+        let log = Arc::default();
+        let (id, task) = send_one(&url, &log);
+
+        task.await.unwrap();
+        assert!(matches!(outcome(&log, id), (1, DeliveryOutcome::Failed { error }) if error.contains("404")));
+        headers_rx.recv().await.unwrap();
+        assert!(headers_rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,155 @@
+use axum::{
+    extract::ws::{Message, WebSocket, WebSocketUpgrade},
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use crate::{
+    auto_close::AutoCloseHandle,
+    commands::CommandSource,
+    command_refused, current_status, submit_command,
+    controller::{CommandPhase, CommandProgress, GpioCommand},
+    door::DoorHandle,
+    error::{ApiError, ErrorCode},
+    Authenticated, SelectedDoor, StatusResponse,
+};
+
+/// A command from the client, e.g. `{"id": "1", "command": "open"}`
+#[derive(Debug, Deserialize)]
+struct ClientMessage {
+    /// Chosen by the client and echoed back in every reply to this command
+    id: String,
+    #[serde(flatten)]
+    command: GpioCommand,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+    /// Sent on connect and every time the status changes
+    Status(StatusResponse),
+    /// The command was handed to the GPIO loop
+    Ack {
+        id: String,
+        command: CommandProgress,
+    },
+    /// The command finished, see its phase for how
+    Result {
+        id: String,
+        command: CommandProgress,
+    },
+    /// `id` is missing if the message couldn't be parsed far enough to find it
+    Error {
+        id: Option<String>,
+        #[serde(flatten)]
+        error: ApiError,
+    },
+}
+
+/// Follow the status and send commands over a WebSocket
+///
+/// Every status change comes as `{"type": "status", ...}`. Commands like
+/// `{"id": "1", "command": "open"}` are answered with an `ack` once accepted
+/// and a `result` once finished, or an `error`.
+/// The connection is authenticated once, when it is upgraded.
+#[utoipa::path(
+    get,
+    tag = "door",
+    path = "/api/v1/ws",
+    responses(
+        (status = 101, description = "Switching to the WebSocket protocol"),
+        (status = 401, description = "Missing or invalid API key", body = ApiError),
+    ),
+)]
+pub async fn ws_handler(
+    _: Authenticated,
+    SelectedDoor(door): SelectedDoor,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    upgrade.on_upgrade(move |socket| handle_socket(socket, door))
+}
+
+async fn handle_socket(mut socket: WebSocket, door: DoorHandle) {
+    let mut state_rx = door.state.subscribe();
+    let mut auto_close_rx = door.auto_close.as_ref().map(AutoCloseHandle::subscribe);
+    // Replies for commands that finished in the background
+    let (reply_tx, mut reply_rx) = mpsc::unbounded_channel();
+
+    if send(&mut socket, ServerMessage::Status(current_status(&door))).await.is_err() {
+        return;
+    }
+
+    loop {
+        let message = tokio::select! {
+            changed = state_rx.changed() => match changed {
+                Ok(()) => ServerMessage::Status(current_status(&door)),
+                Err(_) => break,
+            },
+            Some(changed) = async { Some(auto_close_rx.as_mut()?.changed().await) } => match changed {
+                Ok(()) => ServerMessage::Status(current_status(&door)),
+                Err(_) => break,
+            },
+            Some(reply) = reply_rx.recv() => reply,
+            received = socket.recv() => match received {
+                Some(Ok(Message::Text(text))) => handle_command(&door, &text, &reply_tx),
+                // Pings are answered for us
+                Some(Ok(Message::Binary(_) | Message::Ping(_) | Message::Pong(_))) => continue,
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+            },
+        };
+
+        if send(&mut socket, message).await.is_err() {
+            break;
+        }
+    }
+}
+
+// Submit a command and follow it to the end. Returns the immediate reply.
+fn handle_command(door: &DoorHandle, text: &str, reply_tx: &mpsc::UnboundedSender<ServerMessage>) -> ServerMessage {
+    let ClientMessage { id, command } = match serde_json::from_str(text) {
+        Ok(message) => message,
+        Err(err) => {
+            // Still try to tell the client which message was wrong
+            let id = serde_json::from_str::<serde_json::Value>(text)
+                .ok()
+                .and_then(|value| value.get("id")?.as_str().map(str::to_string));
+            return ServerMessage::Error {
+                id,
+                error: ApiError::new(ErrorCode::InvalidRequest, err.to_string()),
+            };
+        }
+    };
+
+    let progress_rx = match submit_command(door, command, CommandSource::Websocket) {
+        Ok(progress_rx) => progress_rx,
+        Err(error) => return ServerMessage::Error { id: Some(id), error },
+    };
+    let progress = *progress_rx.borrow();
+
+    let door = door.clone();
+    let reply_tx = reply_tx.clone();
+    let result_id = id.clone();
+    tokio::spawn(async move {
+        let progress = door.commands.wait(progress_rx).await;
+        let reply = if progress.phase == CommandPhase::Refused {
+            ServerMessage::Error {
+                id: Some(result_id),
+                error: command_refused(*door.state.borrow()),
+            }
+        } else {
+            ServerMessage::Result {
+                id: result_id,
+                command: progress,
+            }
+        };
+        // The client may have gone away in the meantime
+        let _ = reply_tx.send(reply);
+    });
+
+    ServerMessage::Ack { id, command: progress }
+}
+
+async fn send(socket: &mut WebSocket, message: ServerMessage) -> Result<(), axum::Error> {
+    let text = serde_json::to_string(&message).expect("Server messages always serialize");
+    socket.send(Message::Text(text.into())).await
+}